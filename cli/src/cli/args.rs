@@ -1,5 +1,12 @@
 use anyhow::{bail, Result};
-use palettum::{color_difference, find_palette, palettized, smoothed, Filter, Mapping, Palette};
+#[cfg(feature = "video")]
+use palettum::media::VideoPassthrough;
+use palettum::{
+    color_difference, find_palette,
+    media::{IcoEncoding, MetadataMode, PngCompression, PngFilter, STANDARD_ICON_SIZES},
+    palettized, smoothed, Filter, GifOptimization, Mapping, Mask, MatteMode, OutputFormat, Palette,
+    PngOptions, Selection,
+};
 use std::path::PathBuf;
 
 use clap::{ArgAction, Args, Parser, Subcommand};
@@ -142,10 +149,172 @@ pub struct PalettifyArgs {
     #[arg(short, long, help_heading = "MISC OPTIONS")]
     pub output: Option<PathBuf>,
 
+    /// Format to write, inferred from the output file extension when not given
+    #[arg(long, value_enum, value_name = "FORMAT", help_heading = "MISC OPTIONS")]
+    pub format: Option<OutputFormat>,
+
+    /// Metadata (EXIF, ICC, XMP, text) to carry over to PNG, JPEG and WebP outputs
+    #[arg(
+        long,
+        value_enum,
+        value_name = "MODE",
+        default_value = "keep",
+        help_heading = "MISC OPTIONS"
+    )]
+    pub metadata: MetadataMode,
+
     /// Comma-separated list of output files (must match input count)
     #[arg(long, value_delimiter = ',', help_heading = "MISC OPTIONS")]
     pub output_files: Option<Vec<PathBuf>>,
 
+    /// Favor palette colors that keep the source hue or lightness
+    #[arg(
+        long,
+        value_enum,
+        value_name = "COMPONENT",
+        conflicts_with = "diff_weights",
+        help_heading = "MISC OPTIONS"
+    )]
+    pub preserve: Option<color_difference::Preserve>,
+
+    /// Lightness, chroma and hue factors of the color difference, e.g. '2,1,0.5' (higher
+    /// factors make that component matter less)
+    #[arg(
+        long,
+        value_name = "L,C,H",
+        value_parser = parse_weights,
+        help_heading = "MISC OPTIONS"
+    )]
+    pub diff_weights: Option<color_difference::Weights>,
+
+    /// Keep the lightness of the source, taking only hue and chroma from the palette
+    #[arg(long, default_value_t = false, help_heading = "MISC OPTIONS")]
+    pub keep_lightness: bool,
+
+    /// Color to pass through unchanged (repeatable)
+    #[arg(
+        long,
+        value_name = "COLOR",
+        value_parser = parse_color,
+        action = ArgAction::Append,
+        help_heading = "MISC OPTIONS"
+    )]
+    pub protect: Vec<[u8; 3]>,
+
+    /// Replace a source color with a fixed target color, e.g. '#ff0000=#00ff00' (repeatable)
+    #[arg(
+        long,
+        value_name = "SOURCE=TARGET",
+        value_parser = parse_remap,
+        action = ArgAction::Append,
+        help_heading = "MISC OPTIONS"
+    )]
+    pub remap: Vec<([u8; 3], [u8; 3])>,
+
+    /// Maximum color difference for --protect and --remap to match a pixel
+    #[arg(
+        long,
+        value_name = "DELTA_E",
+        default_value_t = 1.0,
+        help_heading = "MISC OPTIONS"
+    )]
+    pub protect_tolerance: f32,
+
+    /// Only recolor part of the image: a grayscale or transparent mask image, 'rect:X,Y,W,H' or
+    /// 'polygon:X,Y;X,Y;...'
+    #[arg(
+        long,
+        value_name = "MASK",
+        value_parser = parse_mask,
+        help_heading = "MISC OPTIONS"
+    )]
+    pub mask: Option<Mask>,
+
+    /// Width in pixels of the soft transition along the mask edges
+    #[arg(
+        long,
+        value_name = "PIXELS",
+        default_value_t = 0.0,
+        help_heading = "MISC OPTIONS"
+    )]
+    pub mask_feather: f32,
+
+    /// Recolor everything outside of the mask instead
+    #[arg(long, default_value_t = false, help_heading = "MISC OPTIONS")]
+    pub invert_mask: bool,
+
+    /// Only recolor hues within a range of degrees, e.g. '90..160' for greens (wraps past 360)
+    #[arg(
+        long,
+        value_name = "MIN..MAX",
+        value_parser = parse_range,
+        help_heading = "MISC OPTIONS"
+    )]
+    pub select_hue: Option<[f32; 2]>,
+
+    /// Only recolor chroma (colorfulness) within a range, e.g. '20..150'
+    #[arg(
+        long,
+        value_name = "MIN..MAX",
+        value_parser = parse_range,
+        help_heading = "MISC OPTIONS"
+    )]
+    pub select_chroma: Option<[f32; 2]>,
+
+    /// Only recolor lightness within a range from 0 to 100, e.g. '0..35' for shadows
+    #[arg(
+        long,
+        value_name = "MIN..MAX",
+        value_parser = parse_range,
+        help_heading = "MISC OPTIONS"
+    )]
+    pub select_lightness: Option<[f32; 2]>,
+
+    /// Width of the soft transition outside of the --select-* ranges
+    #[arg(
+        long,
+        value_name = "AMOUNT",
+        default_value_t = 0.0,
+        help_heading = "MISC OPTIONS"
+    )]
+    pub select_falloff: f32,
+
+    /// Background color to prepare transparent pixels against (e.g. '#ffffff' or '255,255,255')
+    #[arg(
+        long,
+        value_name = "COLOR",
+        value_parser = parse_color,
+        help_heading = "MISC OPTIONS"
+    )]
+    pub matte: Option<[u8; 3]>,
+
+    /// Whether to blend over the matte or undo a previous blend over it
+    #[arg(
+        long,
+        value_enum,
+        value_name = "MODE",
+        default_value = "composite",
+        help_heading = "MISC OPTIONS"
+    )]
+    pub matte_mode: MatteMode,
+
+    /// Make the output fully opaque against the matte (black if not given)
+    #[arg(long, default_value_t = false, help_heading = "MISC OPTIONS")]
+    pub opaque: bool,
+
+    /// Reuse the previous frame's colors for unchanged pixels of GIFs and videos
+    #[arg(long, default_value_t = false, help_heading = "MISC OPTIONS")]
+    pub temporal: bool,
+
+    /// Per-channel difference under which a pixel counts as unchanged (with --temporal)
+    #[arg(
+        long,
+        value_name = "CHANNEL",
+        default_value_t = 4,
+        help_heading = "MISC OPTIONS"
+    )]
+    pub temporal_threshold: u8,
+
     // PALETTIZED OPTIONS
     /// Dithering algorithm to apply (useful with limited palettes)
     #[arg(
@@ -167,6 +336,70 @@ pub struct PalettifyArgs {
     )]
     pub dither_strength: f32,
 
+    /// Alternate scan direction every row (error-diffusion algorithms only)
+    #[arg(long, default_value_t = false, help_heading = "PALETTIZED OPTIONS")]
+    pub serpentine: bool,
+
+    /// Color space error is diffused in (error-diffusion algorithms only)
+    #[arg(
+        long,
+        value_enum,
+        value_name = "SPACE",
+        default_value = "srgb",
+        help_heading = "PALETTIZED OPTIONS"
+    )]
+    pub dither_space: palettized::DitherSpace,
+
+    /// How out-of-range colors are clamped during error diffusion
+    #[arg(
+        long,
+        value_enum,
+        value_name = "STRATEGY",
+        default_value = "value",
+        help_heading = "PALETTIZED OPTIONS"
+    )]
+    pub dither_clamp: palettized::ErrorClamp,
+
+    /// Reduce dithering across edges to keep text and line art crisp
+    #[arg(
+        long,
+        value_enum,
+        value_name = "MODE",
+        default_value = "none",
+        help_heading = "PALETTIZED OPTIONS"
+    )]
+    pub dither_edges: palettized::EdgeAwareness,
+
+    /// Threshold matrix size for bayer and pattern dithering (2, 4, 8 or 16)
+    #[arg(
+        long,
+        value_name = "SIZE",
+        default_value_t = 8,
+        help_heading = "PALETTIZED OPTIONS"
+    )]
+    pub matrix_size: u32,
+
+    /// Generate a SIZExSIZE void-and-cluster blue-noise texture instead of the built-in 64x64 one
+    #[arg(long, value_name = "SIZE", help_heading = "PALETTIZED OPTIONS")]
+    pub blue_noise_size: Option<u32>,
+
+    /// Seed for the generated blue-noise texture
+    #[arg(
+        long,
+        value_name = "SEED",
+        default_value_t = 0,
+        help_heading = "PALETTIZED OPTIONS"
+    )]
+    pub blue_noise_seed: u32,
+
+    /// Use decorrelated blue-noise offsets for each color channel
+    #[arg(long, default_value_t = false, help_heading = "PALETTIZED OPTIONS")]
+    pub blue_noise_per_channel: bool,
+
+    /// Shift the blue-noise texture every frame of GIFs and videos
+    #[arg(long, default_value_t = false, help_heading = "PALETTIZED OPTIONS")]
+    pub blue_noise_animated: bool,
+
     /// Alpha threshold (0-255, 0 disables transparency)
     #[arg(
         short,
@@ -177,6 +410,25 @@ pub struct PalettifyArgs {
     )]
     pub alpha: u8,
 
+    /// Dither alpha between levels instead of cutting it at the alpha threshold
+    #[arg(
+        long,
+        value_enum,
+        value_name = "METHOD",
+        default_value = "none",
+        help_heading = "PALETTIZED OPTIONS"
+    )]
+    pub alpha_dither: palettized::AlphaDithering,
+
+    /// Comma-separated partial alpha levels to keep (e.g. 64,128,192), stored in the PNG tRNS chunk
+    #[arg(
+        long,
+        value_name = "LEVELS",
+        value_delimiter = ',',
+        help_heading = "PALETTIZED OPTIONS"
+    )]
+    pub alpha_levels: Vec<u8>,
+
     // SMOOTHED OPTIONS
     /// Interpolation formula
     #[arg(
@@ -198,6 +450,28 @@ pub struct PalettifyArgs {
     )]
     pub smooth_strength: f32,
 
+    /// Blend only the K palette colors nearest to each pixel
+    #[arg(long, value_name = "K", help_heading = "SMOOTHED OPTIONS")]
+    pub smooth_neighbors: Option<usize>,
+
+    /// Leave palette colors further than this color difference (ΔE) out of the blend
+    #[arg(long, value_name = "DELTA_E", help_heading = "SMOOTHED OPTIONS")]
+    pub smooth_cutoff: Option<f32>,
+
+    // GRADIENT MAP OPTIONS
+    /// Palette indices from dark to light, e.g. '3,0,2' (defaults to ordering by lightness)
+    #[arg(
+        long,
+        value_name = "INDICES",
+        value_delimiter = ',',
+        help_heading = "GRADIENT MAP OPTIONS"
+    )]
+    pub gradient_order: Vec<usize>,
+
+    /// Blend between adjacent palette colors instead of snapping to the closest one
+    #[arg(long, default_value_t = false, help_heading = "GRADIENT MAP OPTIONS")]
+    pub gradient_interpolate: bool,
+
     // SIZE OPTIONS
     /// Resize output to this width (preserves aspect ratio with height)
     #[arg(long, value_name = "PIXELS", help_heading = "SIZE OPTIONS")]
@@ -225,6 +499,104 @@ pub struct PalettifyArgs {
     )]
     pub filter: Filter,
 
+    // GIF OPTIONS
+    /// Count pixels within this per-channel difference of the previous frame as unchanged,
+    /// trading quality for smaller GIFs (0 keeps every pixel exact)
+    #[arg(
+        long,
+        value_name = "CHANNEL",
+        default_value_t = 0,
+        help_heading = "GIF OPTIONS"
+    )]
+    pub gif_lossy: u8,
+
+    /// Write GIF frames in their source layout instead of cropping them to what changed
+    #[arg(long, default_value_t = false, help_heading = "GIF OPTIONS")]
+    pub no_gif_delta: bool,
+
+    /// Keep identical consecutive GIF frames instead of merging their delays
+    #[arg(long, default_value_t = false, help_heading = "GIF OPTIONS")]
+    pub keep_duplicate_frames: bool,
+
+    // PNG OPTIONS
+    /// Compression level of written PNGs
+    #[arg(
+        long,
+        value_enum,
+        value_name = "LEVEL",
+        default_value = "fast",
+        help_heading = "PNG OPTIONS"
+    )]
+    pub png_compression: PngCompression,
+
+    /// Row filter of written PNGs
+    #[arg(
+        long,
+        value_enum,
+        value_name = "FILTER",
+        default_value = "sub",
+        help_heading = "PNG OPTIONS"
+    )]
+    pub png_filter: PngFilter,
+
+    /// Sort indexed PNG palettes by use, dropping unused colors and trimming transparency
+    #[arg(long, default_value_t = false, help_heading = "PNG OPTIONS")]
+    pub png_optimize: bool,
+
+    // ICON OPTIONS
+    /// Rebuild icons with an entry for each of these sizes, all scaled from the largest one
+    #[arg(
+        long,
+        value_name = "SIZES",
+        value_delimiter = ',',
+        help_heading = "ICON OPTIONS"
+    )]
+    pub icon_sizes: Option<Vec<u32>>,
+
+    /// Rebuild icons as a standard set of 16, 24, 32, 48, 64, 128 and 256 pixel entries
+    #[arg(long, default_value_t = false, help_heading = "ICON OPTIONS")]
+    pub icon_set: bool,
+
+    /// How icon entries are stored; BMP entries are indexed when palettized
+    #[arg(
+        long,
+        value_enum,
+        value_name = "ENCODING",
+        default_value = "auto",
+        help_heading = "ICON OPTIONS"
+    )]
+    pub icon_encoding: IcoEncoding,
+
+    /// Write icons as cursors with the hotspot at X,Y of the largest entry
+    #[arg(
+        long,
+        value_name = "X,Y",
+        value_parser = parse_hotspot,
+        help_heading = "ICON OPTIONS"
+    )]
+    pub cursor_hotspot: Option<(u16, u16)>,
+
+    // VIDEO OPTIONS
+    /// Drop the audio streams of videos
+    #[arg(long, default_value_t = false, help_heading = "VIDEO OPTIONS")]
+    pub no_audio: bool,
+
+    /// Drop the subtitle streams of videos
+    #[arg(long, default_value_t = false, help_heading = "VIDEO OPTIONS")]
+    pub no_subtitles: bool,
+
+    /// Drop the data streams of videos, such as timecodes
+    #[arg(long, default_value_t = false, help_heading = "VIDEO OPTIONS")]
+    pub no_data_streams: bool,
+
+    /// Drop the container and stream tags of videos, such as the title and creation time
+    #[arg(long, default_value_t = false, help_heading = "VIDEO OPTIONS")]
+    pub no_video_metadata: bool,
+
+    /// Drop the rotation players apply to videos, keeping the picture as stored
+    #[arg(long, default_value_t = false, help_heading = "VIDEO OPTIONS")]
+    pub no_rotation: bool,
+
     // PERFORMANCE OPTIONS
     /// Number of processing threads (0/1 to disable multi-threading)
     #[cfg(not(feature = "gpu"))]
@@ -258,6 +630,69 @@ pub struct PalettifyArgs {
     pub diff_formula: color_difference::Formula,
 }
 
+impl PalettifyArgs {
+    /// Color difference weights given by --diff-weights or --preserve.
+    pub fn weights(&self) -> color_difference::Weights {
+        self.diff_weights
+            .or_else(|| self.preserve.map(Into::into))
+            .unwrap_or_default()
+    }
+
+    /// The color selection given by the --select-* flags, if any.
+    pub fn selection(&self) -> Option<Selection> {
+        if self.select_hue.is_none()
+            && self.select_chroma.is_none()
+            && self.select_lightness.is_none()
+        {
+            return None;
+        }
+
+        Some(Selection {
+            hue: self.select_hue,
+            chroma: self.select_chroma,
+            lightness: self.select_lightness,
+            falloff: self.select_falloff,
+        })
+    }
+
+    /// How GIF frames are reduced, given by the GIF options.
+    pub fn gif_optimization(&self) -> GifOptimization {
+        GifOptimization {
+            delta_frames: !self.no_gif_delta,
+            lossy: self.gif_lossy,
+            merge_frames: !self.keep_duplicate_frames,
+        }
+    }
+
+    /// What videos carry over from their source, given by the video options.
+    #[cfg(feature = "video")]
+    pub fn video_passthrough(&self) -> VideoPassthrough {
+        VideoPassthrough {
+            audio: !self.no_audio,
+            subtitles: !self.no_subtitles,
+            data: !self.no_data_streams,
+            metadata: !self.no_video_metadata,
+            rotation: !self.no_rotation,
+        }
+    }
+
+    /// Sizes icons are rebuilt with, given by --icon-sizes or --icon-set.
+    pub fn icon_sizes(&self) -> Option<Vec<u32>> {
+        self.icon_sizes
+            .clone()
+            .or_else(|| self.icon_set.then(|| STANDARD_ICON_SIZES.to_vec()))
+    }
+
+    /// How PNGs are encoded, given by the PNG options.
+    pub fn png_options(&self) -> PngOptions {
+        PngOptions {
+            compression: self.png_compression,
+            filter: self.png_filter,
+            optimize: self.png_optimize,
+        }
+    }
+}
+
 #[derive(Args, Debug)]
 pub struct SaveArgs {
     /// Path to a JSON file containing at least a "colors" array with RGB values
@@ -313,6 +748,114 @@ fn parse_palette(s: &str) -> Result<Palette> {
     }
 }
 
+fn parse_color(s: &str) -> Result<[u8; 3]> {
+    const FORMAT_MSG: &str =
+        "The correct format is a hex color (e.g. '#ff8800') or 'r,g,b' (e.g. '255,136,0')";
+    let trimmed = s.trim();
+    if trimmed.contains(',') {
+        let channels = trimmed
+            .split(',')
+            .map(|c| c.trim().parse::<u8>())
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|_| anyhow::anyhow!(FORMAT_MSG))?;
+        return channels.try_into().map_err(|_| anyhow::anyhow!(FORMAT_MSG));
+    }
+
+    let hex = trimmed.strip_prefix('#').unwrap_or(trimmed);
+    if hex.len() != 6 || !hex.is_ascii() {
+        bail!(FORMAT_MSG);
+    }
+    let channel =
+        |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| anyhow::anyhow!(FORMAT_MSG));
+    Ok([channel(0)?, channel(2)?, channel(4)?])
+}
+
+fn parse_remap(s: &str) -> Result<([u8; 3], [u8; 3])> {
+    let Some((source, target)) = s.split_once('=') else {
+        bail!("The correct format is 'SOURCE=TARGET' (e.g. '#ff0000=#00ff00')");
+    };
+    Ok((parse_color(source)?, parse_color(target)?))
+}
+
+fn parse_weights(s: &str) -> Result<color_difference::Weights> {
+    const FORMAT_MSG: &str = "The correct format is 'L,C,H' (e.g. '2,1,0.5')";
+    let factors = s
+        .split(',')
+        .map(|k| k.trim().parse::<f32>())
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(|_| anyhow::anyhow!(FORMAT_MSG))?;
+    let [lightness, chroma, hue] = factors[..] else {
+        bail!(FORMAT_MSG);
+    };
+    Ok(color_difference::Weights {
+        lightness,
+        chroma,
+        hue,
+    })
+}
+
+fn parse_range(s: &str) -> Result<[f32; 2]> {
+    const FORMAT_MSG: &str = "The correct format is 'MIN..MAX' (e.g. '90..160')";
+    let (min, max) = s
+        .split_once("..")
+        .ok_or_else(|| anyhow::anyhow!(FORMAT_MSG))?;
+    match (min.trim().parse::<f32>(), max.trim().parse::<f32>()) {
+        (Ok(min), Ok(max)) => Ok([min, max]),
+        _ => bail!(FORMAT_MSG),
+    }
+}
+
+fn parse_mask(s: &str) -> Result<Mask> {
+    if let Some(rect) = s.strip_prefix("rect:") {
+        const FORMAT_MSG: &str = "The correct format is 'rect:X,Y,W,H' (e.g. 'rect:10,10,64,32')";
+        let values = rect
+            .split(',')
+            .map(|n| n.trim().parse::<u32>())
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|_| anyhow::anyhow!(FORMAT_MSG))?;
+        let [x, y, width, height] = values[..] else {
+            bail!(FORMAT_MSG);
+        };
+        return Ok(Mask::Rect {
+            x,
+            y,
+            width,
+            height,
+        });
+    }
+
+    if let Some(polygon) = s.strip_prefix("polygon:") {
+        const FORMAT_MSG: &str =
+            "The correct format is 'polygon:X,Y;X,Y;...' (e.g. 'polygon:0,0;64,0;32,48')";
+        let points = polygon
+            .split(';')
+            .map(|point| {
+                let (x, y) = point
+                    .split_once(',')
+                    .ok_or_else(|| anyhow::anyhow!(FORMAT_MSG))?;
+                match (x.trim().parse::<f32>(), y.trim().parse::<f32>()) {
+                    (Ok(x), Ok(y)) => Ok([x, y]),
+                    _ => bail!(FORMAT_MSG),
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+        return Ok(Mask::Polygon { points });
+    }
+
+    Ok(Mask::from_file(std::path::Path::new(s))?)
+}
+
+fn parse_hotspot(s: &str) -> Result<(u16, u16)> {
+    const FORMAT_MSG: &str = "The correct format is 'X,Y' (e.g. '4,4')";
+    let (x, y) = s
+        .split_once(',')
+        .ok_or_else(|| anyhow::anyhow!(FORMAT_MSG))?;
+    match (x.trim().parse::<u16>(), y.trim().parse::<u16>()) {
+        (Ok(x), Ok(y)) => Ok((x, y)),
+        _ => bail!(FORMAT_MSG),
+    }
+}
+
 fn parse_scale(s: &str) -> Result<f32> {
     const FORMAT_MSG: &str = "The correct format is 'nx' (e.g. '0.5x') or 'n%' (e.g. '50%')";
     let trimmed = s.trim();
@@ -6,7 +6,7 @@ use indicatif::{MultiProgress, ProgressBar};
 use log::{error, info};
 use palettum::{
     custom_palettes_dir, delete_custom_palette, media::load_media_from_path, palette_to_file,
-    Config, Palette, PaletteKind,
+    Config, Media, OutputFormat, Palette, PaletteKind,
 };
 use palettum::{get_all_palettes, palette_from_file_entry, save_custom_palette};
 use rayon::prelude::*;
@@ -20,7 +20,10 @@ use tabled::Table;
 use walkdir::WalkDir;
 
 use anyhow::{bail, Context, Result};
-const VALID_EXTS: [&str; 7] = ["gif", "png", "jpg", "jpeg", "webp", "ico", "mp4"];
+const VALID_EXTS: [&str; 18] = [
+    "gif", "png", "jpg", "jpeg", "webp", "ico", "cur", "mp4", "bmp", "tif", "tiff", "tga", "qoi",
+    "pnm", "pbm", "pgm", "ppm", "pam",
+];
 
 pub async fn run_cli(cli: Cli, multi: MultiProgress) -> Result<()> {
     let s = style::theme();
@@ -106,12 +109,41 @@ pub async fn run_cli(cli: Cli, multi: MultiProgress) -> Result<()> {
                     Config::builder()
                         .palette(args.palette.clone())
                         .mapping(args.mapping)
+                        .protected_colors(args.protect.clone())
+                        .color_remap(args.remap.clone())
+                        .protected_tolerance(args.protect_tolerance)
+                        .gradient_order(args.gradient_order.clone())
+                        .gradient_interpolate(args.gradient_interpolate)
                         .diff_formula(args.diff_formula)
+                        .diff_weights(args.weights())
+                        .keep_lightness(args.keep_lightness)
                         .transparency_threshold(args.alpha)
+                        .maybe_mask(args.mask.clone())
+                        .mask_feather(args.mask_feather)
+                        .mask_invert(args.invert_mask)
+                        .maybe_selection(args.selection())
+                        .maybe_matte(args.matte)
+                        .matte_mode(args.matte_mode)
+                        .force_opaque(args.opaque)
+                        .alpha_dither(args.alpha_dither)
+                        .alpha_levels(args.alpha_levels.clone())
                         .dither_algorithm(args.dither_algorithm)
                         .dither_strength(args.dither_strength)
+                        .dither_serpentine(args.serpentine)
+                        .dither_space(args.dither_space)
+                        .dither_clamp(args.dither_clamp)
+                        .dither_edges(args.dither_edges)
+                        .dither_matrix_size(args.matrix_size)
+                        .maybe_blue_noise_size(args.blue_noise_size)
+                        .blue_noise_seed(args.blue_noise_seed)
+                        .blue_noise_per_channel(args.blue_noise_per_channel)
+                        .blue_noise_animated(args.blue_noise_animated)
+                        .temporal_coherence(args.temporal)
+                        .temporal_threshold(args.temporal_threshold)
                         .smooth_formula(args.smooth_formula)
                         .smooth_strength(args.smooth_strength)
+                        .maybe_smooth_neighbors(args.smooth_neighbors)
+                        .maybe_smooth_cutoff(args.smooth_cutoff)
                         .num_threads(num_threads_for_config)
                         .quant_level(args.quantization)
                         .build(),
@@ -140,6 +172,33 @@ pub async fn run_cli(cli: Cli, multi: MultiProgress) -> Result<()> {
                 }
                 let mut media = load_media_from_path(&input)
                     .with_context(|| format!("Failed to load media from {input:?}"))?;
+                if let Some(format) = args.format.or_else(|| OutputFormat::from_path(&output)) {
+                    media = media
+                        .convert(format)
+                        .with_context(|| format!("Failed to convert {input:?} to {format}"))?;
+                }
+                match &mut media {
+                    Media::Gif(gif) => gif.optimization = args.gif_optimization(),
+                    Media::Animation(animation) => animation.png = args.png_options(),
+                    Media::Image(image) => {
+                        image.png = args.png_options();
+                        image.metadata.retain(args.metadata);
+                    }
+                    Media::Ico(ico) => {
+                        if let Some(sizes) = args.icon_sizes() {
+                            ico.regenerate(&sizes, args.filter).with_context(|| {
+                                format!("Failed to build icon set from {input:?}")
+                            })?;
+                        }
+                        if let Some((x, y)) = args.cursor_hotspot {
+                            ico.set_hotspot(x, y);
+                        }
+                        ico.encoding = args.icon_encoding;
+                    }
+                    #[cfg(feature = "video")]
+                    Media::Video(video) => video.passthrough = args.video_passthrough(),
+                    _ => {}
+                }
 
                 let mut output_with_ext = output.clone();
                 output_with_ext.set_extension(media.default_extension());
@@ -219,14 +278,52 @@ pub async fn run_cli(cli: Cli, multi: MultiProgress) -> Result<()> {
                     let job_pbs = Arc::clone(&job_pbs);
                     let palette = args.palette.clone();
                     let mapping = args.mapping;
+                    let protected_colors = args.protect.clone();
+                    let color_remap = args.remap.clone();
+                    let protected_tolerance = args.protect_tolerance;
+                    let gradient_order = args.gradient_order.clone();
+                    let gradient_interpolate = args.gradient_interpolate;
                     let pal_f = args.diff_formula;
+                    let diff_weights = args.weights();
+                    let keep_lightness = args.keep_lightness;
                     let tmp_f = args.smooth_formula;
                     let alpha = args.alpha;
+                    let mask = args.mask.clone();
+                    let mask_feather = args.mask_feather;
+                    let mask_invert = args.invert_mask;
+                    let selection = args.selection();
+                    let matte = args.matte;
+                    let matte_mode = args.matte_mode;
+                    let force_opaque = args.opaque;
+                    let alpha_dither = args.alpha_dither;
+                    let alpha_levels = args.alpha_levels.clone();
                     let smooth = args.smooth_strength;
+                    let smooth_neighbors = args.smooth_neighbors;
+                    let smooth_cutoff = args.smooth_cutoff;
+                    let gif_optimization = args.gif_optimization();
+                    let png_options = args.png_options();
+                    let metadata_mode = args.metadata;
+                    let icon_sizes = args.icon_sizes();
+                    let cursor_hotspot = args.cursor_hotspot;
+                    let icon_encoding = args.icon_encoding;
+                    #[cfg(feature = "video")]
+                    let video_passthrough = args.video_passthrough();
+                    let format = args.format;
                     let q = args.quantization;
                     let error_count = Arc::clone(&error_count);
                     let dither_algorithm = args.dither_algorithm;
                     let dither_strength = args.dither_strength;
+                    let dither_serpentine = args.serpentine;
+                    let dither_space = args.dither_space;
+                    let dither_clamp = args.dither_clamp;
+                    let dither_edges = args.dither_edges;
+                    let dither_matrix_size = args.matrix_size;
+                    let blue_noise_size = args.blue_noise_size;
+                    let blue_noise_seed = args.blue_noise_seed;
+                    let blue_noise_per_channel = args.blue_noise_per_channel;
+                    let blue_noise_animated = args.blue_noise_animated;
+                    let temporal_coherence = args.temporal;
+                    let temporal_threshold = args.temporal_threshold;
                     let width = args.width;
                     let height = args.height;
                     let scale = args.scale;
@@ -254,12 +351,41 @@ pub async fn run_cli(cli: Cli, multi: MultiProgress) -> Result<()> {
                             Config::builder()
                                 .palette(palette.clone())
                                 .mapping(mapping)
+                                .protected_colors(protected_colors.clone())
+                                .color_remap(color_remap.clone())
+                                .protected_tolerance(protected_tolerance)
+                                .gradient_order(gradient_order.clone())
+                                .gradient_interpolate(gradient_interpolate)
                                 .diff_formula(pal_f)
+                                .diff_weights(diff_weights)
+                                .keep_lightness(keep_lightness)
                                 .transparency_threshold(alpha)
+                                .maybe_mask(mask.clone())
+                                .mask_feather(mask_feather)
+                                .mask_invert(mask_invert)
+                                .maybe_selection(selection)
+                                .maybe_matte(matte)
+                                .matte_mode(matte_mode)
+                                .force_opaque(force_opaque)
+                                .alpha_dither(alpha_dither)
+                                .alpha_levels(alpha_levels.clone())
                                 .dither_algorithm(dither_algorithm)
                                 .dither_strength(dither_strength)
+                                .dither_serpentine(dither_serpentine)
+                                .dither_space(dither_space)
+                                .dither_clamp(dither_clamp)
+                                .dither_edges(dither_edges)
+                                .dither_matrix_size(dither_matrix_size)
+                                .maybe_blue_noise_size(blue_noise_size)
+                                .blue_noise_seed(blue_noise_seed)
+                                .blue_noise_per_channel(blue_noise_per_channel)
+                                .blue_noise_animated(blue_noise_animated)
+                                .temporal_coherence(temporal_coherence)
+                                .temporal_threshold(temporal_threshold)
                                 .smooth_formula(tmp_f)
                                 .smooth_strength(smooth)
+                                .maybe_smooth_neighbors(smooth_neighbors)
+                                .maybe_smooth_cutoff(smooth_cutoff)
                                 .num_threads(pixel_threads)
                                 .quant_level(q)
                                 .build(),
@@ -268,6 +394,35 @@ pub async fn run_cli(cli: Cli, multi: MultiProgress) -> Result<()> {
                         let result: Result<()> = async {
                             let mut media = load_media_from_path(&input)
                                 .with_context(|| format!("Failed to load media from {input:?}"))?;
+                            if let Some(format) =
+                                format.or_else(|| OutputFormat::from_path(&output))
+                            {
+                                media = media.convert(format).with_context(|| {
+                                    format!("Failed to convert {input:?} to {format}")
+                                })?;
+                            }
+                            match &mut media {
+                                Media::Gif(gif) => gif.optimization = gif_optimization,
+                                Media::Animation(animation) => animation.png = png_options,
+                                Media::Image(image) => {
+                                    image.png = png_options;
+                                    image.metadata.retain(metadata_mode);
+                                }
+                                Media::Ico(ico) => {
+                                    if let Some(sizes) = &icon_sizes {
+                                        ico.regenerate(sizes, filter).with_context(|| {
+                                            format!("Failed to build icon set from {input:?}")
+                                        })?;
+                                    }
+                                    if let Some((x, y)) = cursor_hotspot {
+                                        ico.set_hotspot(x, y);
+                                    }
+                                    ico.encoding = icon_encoding;
+                                }
+                                #[cfg(feature = "video")]
+                                Media::Video(video) => video.passthrough = video_passthrough,
+                                _ => {}
+                            }
                             media
                                 .resize(width, height, scale, filter)
                                 .with_context(|| format!("Failed to resize {input:?}"))?;
@@ -412,6 +567,7 @@ fn determine_output(output: Option<&Path>, input: &Path, mapping: palettum::Mapp
     let suffix = match mapping {
         palettum::Mapping::Palettized => "_palettized",
         palettum::Mapping::Smoothed => "_smoothed",
+        palettum::Mapping::GradientMap => "_gradient_map",
     };
 
     let parent = input.parent().unwrap_or_else(|| Path::new("."));
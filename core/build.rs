@@ -0,0 +1,10 @@
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rustc-check-cfg=cfg(ffmpeg_6_1)");
+
+    // ffmpeg-sys-next reports the FFmpeg it was built against. 6.1 moved stream side data, such
+    // as the display matrix, into the codec parameters.
+    if std::env::var("DEP_FFMPEG_FFMPEG_6_1").as_deref() == Ok("true") {
+        println!("cargo:rustc-cfg=ffmpeg_6_1");
+    }
+}
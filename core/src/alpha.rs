@@ -0,0 +1,134 @@
+use crate::{
+    config::Config,
+    palettized::{self, AlphaDithering},
+    Mapping,
+};
+
+/// Whether palettized output quantizes alpha to levels instead of hard-thresholding it.
+fn enabled(config: &Config) -> bool {
+    config.mapping == Mapping::Palettized
+        && (config.alpha_dither != AlphaDithering::None || !config.alpha_levels.is_empty())
+}
+
+/// Sorted alpha levels palettized output may use; fully transparent and opaque are always
+/// available.
+fn levels(config: &Config) -> Vec<u8> {
+    let mut levels = config.alpha_levels.clone();
+    levels.extend([0, 255]);
+    levels.sort_unstable();
+    levels.dedup();
+    levels
+}
+
+/// Takes the alpha channel out of the way of color mapping.
+///
+/// Returns the source alpha channel when alpha is quantized separately, after making every pixel
+/// that isn't fully transparent opaque so the color mapping neither clears it at
+/// `transparency_threshold` nor drops its color.
+pub(crate) fn detach(image_data: &mut [u8], config: &Config) -> Option<Vec<u8>> {
+    if !enabled(config) {
+        return None;
+    }
+
+    let alpha = image_data.chunks_exact(4).map(|px| px[3]).collect();
+    for px in image_data.chunks_exact_mut(4) {
+        if px[3] != 0 {
+            px[3] = 255;
+        }
+    }
+    Some(alpha)
+}
+
+/// Writes the quantized (and optionally dithered) source alpha back into the mapped pixels.
+/// Pixels that end up fully transparent are cleared entirely.
+pub(crate) fn attach(image_data: &mut [u8], alpha: &[u8], width: u32, config: &Config) {
+    let levels = levels(config);
+    let width = width as usize;
+
+    let quantized: Vec<u8> = match config.alpha_dither {
+        AlphaDithering::None => alpha.iter().map(|&a| nearest(&levels, a as f32)).collect(),
+        AlphaDithering::Ordered => ordered(alpha, width, &levels, config),
+        AlphaDithering::Diffusion => diffused(alpha, width, &levels),
+    };
+
+    for (px, &a) in image_data.chunks_exact_mut(4).zip(&quantized) {
+        if a == 0 {
+            px.fill(0);
+        } else {
+            px[3] = a;
+        }
+    }
+}
+
+fn nearest(levels: &[u8], value: f32) -> u8 {
+    *levels
+        .iter()
+        .min_by(|&&a, &&b| {
+            (a as f32 - value)
+                .abs()
+                .partial_cmp(&(b as f32 - value).abs())
+                .unwrap_or(std::cmp::Ordering::Equal)
+        })
+        .unwrap()
+}
+
+/// Picks between the two levels surrounding each value using a Bayer threshold.
+fn ordered(alpha: &[u8], width: usize, levels: &[u8], config: &Config) -> Vec<u8> {
+    let size = config.dither_matrix_size as usize;
+    let ranks = palettized::bayer_matrix(size);
+    let count = (size * size) as f32;
+
+    alpha
+        .iter()
+        .enumerate()
+        .map(|(i, &a)| {
+            let upper = levels.partition_point(|&level| level < a);
+            if levels[upper] == a {
+                return a;
+            }
+            let (low, high) = (levels[upper - 1], levels[upper]);
+
+            let (x, y) = (i % width, i / width);
+            let threshold = (ranks[(y % size) * size + x % size] as f32 + 0.5) / count;
+            if (a - low) as f32 / (high - low) as f32 > threshold {
+                high
+            } else {
+                low
+            }
+        })
+        .collect()
+}
+
+/// Floyd-Steinberg error diffusion over the alpha channel.
+fn diffused(alpha: &[u8], width: usize, levels: &[u8]) -> Vec<u8> {
+    let mut values: Vec<f32> = alpha.iter().map(|&a| a as f32).collect();
+    let mut quantized = vec![0u8; alpha.len()];
+
+    for i in 0..values.len() {
+        // Fully transparent pixels lost their color during mapping, so keep them out of it
+        if alpha[i] == 0 {
+            continue;
+        }
+
+        let (x, y) = (i % width, i / width);
+        let value = values[i].clamp(0.0, 255.0);
+        let level = nearest(levels, value);
+        quantized[i] = level;
+
+        let error = values[i] - level as f32;
+        let mut spread = |dx: isize, dy: usize, weight: f32| {
+            let nx = x as isize + dx;
+            if nx >= 0 && (nx as usize) < width {
+                if let Some(v) = values.get_mut((y + dy) * width + nx as usize) {
+                    *v += error * weight;
+                }
+            }
+        };
+        spread(1, 0, 7.0 / 16.0);
+        spread(-1, 1, 3.0 / 16.0);
+        spread(0, 1, 5.0 / 16.0);
+        spread(1, 1, 1.0 / 16.0);
+    }
+
+    quantized
+}
@@ -0,0 +1,274 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use crate::{config::Config, palettized::BLUE_NOISE_64X64};
+
+/// Standard deviation of the Gaussian energy filter, as recommended by Ulichney.
+const SIGMA: f32 = 1.5;
+
+/// The filter is truncated at this many pixels from its center.
+const RADIUS: usize = 6;
+
+/// Fraction of pixels set in the initial binary pattern.
+const INITIAL_DENSITY: usize = 10;
+
+// Additive recurrence of the R2 sequence, used to shift the texture per channel and per frame.
+// Toroidal shifts of a blue-noise texture are still blue noise, and R2 keeps consecutive shifts
+// far apart so the offsets stay decorrelated.
+const R2_X: f64 = 0.754_877_666_246_692_8;
+const R2_Y: f64 = 0.569_840_290_998_053_2;
+
+/// A square, tileable threshold texture. Values are centered on zero and span `-127.5..127.5`.
+pub(crate) struct Texture {
+    pub size: usize,
+    pub values: Arc<[f32]>,
+    /// Per-channel `(x, y)` shift applied before sampling.
+    offsets: [(usize, usize); 3],
+}
+
+impl Texture {
+    /// Texture selected by the configuration: the built-in 64x64 table, or a generated one when
+    /// `blue_noise_size` is set.
+    pub fn for_config(config: &Config) -> Self {
+        let (size, values) = match config.blue_noise_size {
+            Some(size) => (
+                size as usize,
+                generated(size as usize, config.blue_noise_seed),
+            ),
+            None => (64, builtin()),
+        };
+
+        // Temporal coherence keeps the pattern anchored so freshly mapped regions line up with
+        // the pixels reused from earlier frames.
+        let frame = if config.blue_noise_animated && !config.temporal_coherence {
+            config.frame_index as usize
+        } else {
+            0
+        };
+        let offsets = std::array::from_fn(|channel| {
+            let step = if config.blue_noise_per_channel {
+                frame * 3 + channel
+            } else {
+                frame * 3
+            };
+            (
+                ((step as f64 * R2_X).fract() * size as f64) as usize,
+                ((step as f64 * R2_Y).fract() * size as f64) as usize,
+            )
+        });
+
+        Texture {
+            size,
+            values,
+            offsets,
+        }
+    }
+
+    pub fn sample(&self, x: usize, y: usize) -> [f32; 3] {
+        self.offsets
+            .map(|(dx, dy)| self.values[((y + dy) % self.size) * self.size + (x + dx) % self.size])
+    }
+}
+
+fn builtin() -> Arc<[f32]> {
+    static BUILTIN: OnceLock<Arc<[f32]>> = OnceLock::new();
+    BUILTIN
+        .get_or_init(|| BLUE_NOISE_64X64.iter().map(|&v| v as f32 - 127.5).collect())
+        .clone()
+}
+
+/// Void-and-cluster texture of the given size and seed. Textures are generated once and cached
+/// for the lifetime of the process.
+fn generated(size: usize, seed: u32) -> Arc<[f32]> {
+    type Cache = Mutex<HashMap<(usize, u32), Arc<[f32]>>>;
+    static CACHE: OnceLock<Cache> = OnceLock::new();
+
+    let cache = CACHE.get_or_init(|| Mutex::new(HashMap::new()));
+    if let Some(values) = cache.lock().unwrap().get(&(size, seed)) {
+        return values.clone();
+    }
+
+    // Generate outside the lock; a racing thread at worst repeats the (deterministic) work.
+    let levels = (size * size) as f32;
+    let values: Arc<[f32]> = void_and_cluster(size, seed)
+        .into_iter()
+        .map(|rank| (rank as f32 + 0.5) / levels * 255.0 - 127.5)
+        .collect();
+
+    cache
+        .lock()
+        .unwrap()
+        .entry((size, seed))
+        .or_insert(values)
+        .clone()
+}
+
+/// Ranks `0..size * size` of every pixel of a `size`x`size` blue-noise dither array, computed
+/// with Ulichney's void-and-cluster method.
+pub(crate) fn void_and_cluster(size: usize, seed: u32) -> Vec<u32> {
+    let area = size * size;
+    let mut pattern = Pattern::new(size);
+
+    // Random initial pattern
+    let mut rng = SplitMix64(seed as u64);
+    let initial = (area / INITIAL_DENSITY).max(1);
+    while pattern.count < initial {
+        let index = (rng.next() % area as u64) as usize;
+        if !pattern.set[index] {
+            pattern.toggle(index);
+        }
+    }
+
+    // Relax it into an evenly distributed prototype by moving the tightest cluster into the
+    // largest void until that stops changing anything.
+    for _ in 0..area {
+        let cluster = pattern.tightest_cluster();
+        pattern.toggle(cluster);
+
+        let void = pattern.largest_void();
+        pattern.toggle(void);
+
+        if void == cluster {
+            break;
+        }
+    }
+
+    let mut ranks = vec![0u32; area];
+
+    // Phase 1: rank the prototype's pixels by removing its tightest clusters
+    let mut prototype = pattern.clone();
+    for rank in (0..initial).rev() {
+        let cluster = prototype.tightest_cluster();
+        prototype.toggle(cluster);
+        ranks[cluster] = rank as u32;
+    }
+
+    // Phases 2 and 3: rank the remaining pixels by filling the largest voids. With a linear
+    // filter, the tightest cluster of minority zeros is exactly the largest void of ones, so a
+    // single loop covers both halves.
+    for rank in initial..area {
+        let void = pattern.largest_void();
+        pattern.toggle(void);
+        ranks[void] = rank as u32;
+    }
+
+    ranks
+}
+
+/// A binary pattern on a torus along with its Gaussian-filtered density.
+///
+/// Toggling a pixel only changes the energy of the rows under the filter, so the densest set pixel
+/// and the emptiest unset pixel of every row are cached and only those rows are rescanned.
+#[derive(Clone)]
+struct Pattern {
+    size: usize,
+    radius: usize,
+    kernel: Vec<f32>,
+    energy: Vec<f32>,
+    set: Vec<bool>,
+    count: usize,
+    row_cluster: Vec<Option<(usize, f32)>>,
+    row_void: Vec<Option<(usize, f32)>>,
+}
+
+impl Pattern {
+    fn new(size: usize) -> Self {
+        let radius = RADIUS.min((size - 1) / 2);
+        let span = 2 * radius + 1;
+        let kernel = (0..span * span)
+            .map(|i| {
+                let dx = (i % span) as f32 - radius as f32;
+                let dy = (i / span) as f32 - radius as f32;
+                (-(dx * dx + dy * dy) / (2.0 * SIGMA * SIGMA)).exp()
+            })
+            .collect();
+
+        let mut pattern = Pattern {
+            size,
+            radius,
+            kernel,
+            energy: vec![0.0; size * size],
+            set: vec![false; size * size],
+            count: 0,
+            row_cluster: vec![None; size],
+            row_void: vec![None; size],
+        };
+        for y in 0..size {
+            pattern.rescan_row(y);
+        }
+        pattern
+    }
+
+    /// Flips pixel `index`, adding or removing the filter centered on it.
+    fn toggle(&mut self, index: usize) {
+        let sign = if self.set[index] { -1.0 } else { 1.0 };
+        self.set[index] = !self.set[index];
+        if self.set[index] {
+            self.count += 1;
+        } else {
+            self.count -= 1;
+        }
+
+        let (cx, cy) = (index % self.size, index / self.size);
+        let span = 2 * self.radius + 1;
+        for ky in 0..span {
+            let y = (cy + self.size + ky - self.radius) % self.size;
+            for kx in 0..span {
+                let x = (cx + self.size + kx - self.radius) % self.size;
+                self.energy[y * self.size + x] += sign * self.kernel[ky * span + kx];
+            }
+            self.rescan_row(y);
+        }
+    }
+
+    fn rescan_row(&mut self, y: usize) {
+        let mut cluster: Option<(usize, f32)> = None;
+        let mut void: Option<(usize, f32)> = None;
+        for index in y * self.size..(y + 1) * self.size {
+            let value = self.energy[index];
+            if self.set[index] {
+                if cluster.is_none_or(|(_, best)| value > best) {
+                    cluster = Some((index, value));
+                }
+            } else if void.is_none_or(|(_, best)| value < best) {
+                void = Some((index, value));
+            }
+        }
+        self.row_cluster[y] = cluster;
+        self.row_void[y] = void;
+    }
+
+    /// The set pixel with the highest energy.
+    fn tightest_cluster(&self) -> usize {
+        Self::extreme(&self.row_cluster, |candidate, best| candidate > best)
+    }
+
+    /// The unset pixel with the lowest energy.
+    fn largest_void(&self) -> usize {
+        Self::extreme(&self.row_void, |candidate, best| candidate < best)
+    }
+
+    fn extreme(rows: &[Option<(usize, f32)>], better: impl Fn(f32, f32) -> bool) -> usize {
+        let mut best: Option<(usize, f32)> = None;
+        for &(index, value) in rows.iter().flatten() {
+            if best.is_none_or(|(_, b)| better(value, b)) {
+                best = Some((index, value));
+            }
+        }
+        best.map(|(index, _)| index).unwrap_or(0)
+    }
+}
+
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+}
@@ -1,4 +1,5 @@
 use image::{Rgb, Rgba};
+use std::sync::OnceLock;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Lab {
@@ -104,29 +105,42 @@ impl ConvertToLab for Rgba<u8> {
         let g_lin = (g_u8 as f32 / 255.0).powf(2.2);
         let b_lin = (b_u8 as f32 / 255.0).powf(2.2);
 
-        // Linear RGB to XYZ (D65 illuminant)
-        let x = (r_lin * 0.4124564 + g_lin * 0.3575761 + b_lin * 0.1804375) * 100.0;
-        let y = (r_lin * 0.2126729 + g_lin * 0.7151522 + b_lin * 0.0721750) * 100.0;
-        let z = (r_lin * 0.0193339 + g_lin * 0.119_192 + b_lin * 0.9503041) * 100.0;
+        linear_to_lab([r_lin, g_lin, b_lin])
+    }
+}
 
-        // XYZ to Lab
-        let xr = x / WHITE_X;
-        let yr = y / WHITE_Y;
-        let zr = z / WHITE_Z;
+/// sRGB channel value to linear intensity (`0.0..=1.0`), with the same gamma as [`ConvertToLab`].
+pub(crate) fn srgb_to_linear(v: u8) -> f32 {
+    static TABLE: OnceLock<[f32; 256]> = OnceLock::new();
+    TABLE.get_or_init(|| std::array::from_fn(|i| (i as f32 / 255.0).powf(2.2)))[v as usize]
+}
 
-        let fx = pivot_xyz(xr);
-        let fy = pivot_xyz(yr);
-        let fz = pivot_xyz(zr);
+/// Linear RGB (`0.0..=1.0` per channel) to Lab.
+pub(crate) fn linear_to_lab(rgb: [f32; 3]) -> Lab {
+    let [r_lin, g_lin, b_lin] = rgb;
 
-        let l_star = (116.0 * fy - 16.0).max(0.0);
-        let a_star = 500.0 * (fx - fy);
-        let b_star = 200.0 * (fy - fz);
+    // Linear RGB to XYZ (D65 illuminant)
+    let x = (r_lin * 0.4124564 + g_lin * 0.3575761 + b_lin * 0.1804375) * 100.0;
+    let y = (r_lin * 0.2126729 + g_lin * 0.7151522 + b_lin * 0.0721750) * 100.0;
+    let z = (r_lin * 0.0193339 + g_lin * 0.119_192 + b_lin * 0.9503041) * 100.0;
 
-        Lab {
-            l: l_star,
-            a: a_star,
-            b: b_star,
-        }
+    // XYZ to Lab
+    let xr = x / WHITE_X;
+    let yr = y / WHITE_Y;
+    let zr = z / WHITE_Z;
+
+    let fx = pivot_xyz(xr);
+    let fy = pivot_xyz(yr);
+    let fz = pivot_xyz(zr);
+
+    let l_star = (116.0 * fy - 16.0).max(0.0);
+    let a_star = 500.0 * (fx - fy);
+    let b_star = 200.0 * (fy - fz);
+
+    Lab {
+        l: l_star,
+        a: a_star,
+        b: b_star,
     }
 }
 
@@ -19,22 +19,84 @@ pub enum Formula {
     CIEDE2000,
 }
 
-pub(crate) fn delta_e(color1: &Lab, color2: &Lab, formula: Formula) -> f32 {
+/// Parametric factors (kL, kC, kH) dividing the lightness, chroma and hue terms of a color
+/// difference. Raising a factor makes differences in that component matter less.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "wasm", derive(Tsify, Serialize, Deserialize))]
+pub struct Weights {
+    pub lightness: f32,
+    pub chroma: f32,
+    pub hue: f32,
+}
+
+impl Weights {
+    pub const NEUTRAL: Self = Weights {
+        lightness: 1.0,
+        chroma: 1.0,
+        hue: 1.0,
+    };
+
+    /// Hue shifts cost more than lightness changes, so a dark red stays red rather than turning
+    /// into a brown of matching lightness.
+    pub const PRESERVE_HUE: Self = Weights {
+        lightness: 2.0,
+        chroma: 1.0,
+        hue: 0.5,
+    };
+
+    /// Lightness changes cost more than hue and chroma shifts.
+    pub const PRESERVE_LIGHTNESS: Self = Weights {
+        lightness: 0.5,
+        chroma: 1.0,
+        hue: 1.0,
+    };
+}
+
+impl Default for Weights {
+    fn default() -> Self {
+        Self::NEUTRAL
+    }
+}
+
+/// Named [`Weights`] presets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum, strum_macros::Display))]
+pub enum Preserve {
+    Hue,
+    Lightness,
+}
+
+impl From<Preserve> for Weights {
+    fn from(preserve: Preserve) -> Self {
+        match preserve {
+            Preserve::Hue => Weights::PRESERVE_HUE,
+            Preserve::Lightness => Weights::PRESERVE_LIGHTNESS,
+        }
+    }
+}
+
+pub(crate) fn delta_e(color1: &Lab, color2: &Lab, formula: Formula, weights: &Weights) -> f32 {
     match formula {
-        Formula::CIEDE2000 => ciede2000(color1, color2),
-        Formula::CIE94 => cie94(color1, color2),
-        Formula::CIE76 => cie76(color1, color2),
+        Formula::CIEDE2000 => ciede2000(color1, color2, weights),
+        Formula::CIE94 => cie94(color1, color2, weights),
+        Formula::CIE76 if *weights == Weights::NEUTRAL => cie76(color1, color2),
+        Formula::CIE76 => cie76_weighted(color1, color2, weights),
     }
 }
 
-pub(crate) fn delta_e_batch(reference: &Lab, colors: &[Lab], formula: Formula) -> Vec<f32> {
+pub(crate) fn delta_e_batch(
+    reference: &Lab,
+    colors: &[Lab],
+    formula: Formula,
+    weights: &Weights,
+) -> Vec<f32> {
     colors
         .iter()
-        .map(|palette_color| delta_e(reference, palette_color, formula))
+        .map(|palette_color| delta_e(reference, palette_color, formula, weights))
         .collect()
 }
 
-fn ciede2000(reference: &Lab, color: &Lab) -> f32 {
+fn ciede2000(reference: &Lab, color: &Lab, weights: &Weights) -> f32 {
     const PI: f32 = std::f32::consts::PI;
     const POW25_7: f32 = 6103515625.0;
 
@@ -160,9 +222,9 @@ fn ciede2000(reference: &Lab, color: &Lab) -> f32 {
 
     let r_t = rt_sqrt * sin_result * -2.0;
 
-    let lightness = delta_l_prime / s_l;
-    let chroma = delta_c_prime / s_c;
-    let hue = delta_h_prime_big / s_h;
+    let lightness = delta_l_prime / (weights.lightness * s_l);
+    let chroma = delta_c_prime / (weights.chroma * s_c);
+    let hue = delta_h_prime_big / (weights.hue * s_h);
 
     let lightness_sq = lightness * lightness;
     let chroma_sq = chroma * chroma;
@@ -175,12 +237,11 @@ fn ciede2000(reference: &Lab, color: &Lab) -> f32 {
     sum.sqrt()
 }
 
-fn cie94(color1: &Lab, color2: &Lab) -> f32 {
+fn cie94(color1: &Lab, color2: &Lab, weights: &Weights) -> f32 {
     // These constants are actually adjustable variables in the main formula
     // https://en.wikipedia.org/wiki/Color_difference#CIE94
     let k1: f32 = 0.045;
     let k2: f32 = 0.015;
-    let kl: f32 = 1.0;
 
     let delta_l: f32 = color1.l - color2.l;
 
@@ -203,9 +264,9 @@ fn cie94(color1: &Lab, color2: &Lab) -> f32 {
     let s_c: f32 = 1.0 + k1 * c1;
     let s_h: f32 = 1.0 + k2 * c1;
 
-    let term_l: f32 = delta_l / (kl * s_l);
-    let term_c: f32 = delta_c / s_c;
-    let term_h: f32 = delta_h / s_h;
+    let term_l: f32 = delta_l / (weights.lightness * s_l);
+    let term_c: f32 = delta_c / (weights.chroma * s_c);
+    let term_h: f32 = delta_h / (weights.hue * s_h);
 
     (term_l * term_l + term_c * term_c + term_h * term_h).sqrt()
 }
@@ -216,3 +277,17 @@ fn cie76(color1: &Lab, color2: &Lab) -> f32 {
     let db = color1.b - color2.b;
     (dl * dl + da * da + db * db).sqrt()
 }
+
+/// CIE76 split into lightness, chroma and hue terms so each can be weighted.
+fn cie76_weighted(color1: &Lab, color2: &Lab, weights: &Weights) -> f32 {
+    let dl = color1.l - color2.l;
+    let da = color1.a - color2.a;
+    let db = color1.b - color2.b;
+    let dc = color1.a.hypot(color1.b) - color2.a.hypot(color2.b);
+    let dh_sq = (da * da + db * db - dc * dc).max(0.0);
+
+    let term_l = dl / weights.lightness;
+    let term_c = dc / weights.chroma;
+    let term_h_sq = dh_sq / (weights.hue * weights.hue);
+    (term_l * term_l + term_c * term_c + term_h_sq).sqrt()
+}
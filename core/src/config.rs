@@ -9,7 +9,7 @@ use tsify::Tsify;
 use crate::{
     color_difference,
     error::{Error, Result},
-    palettized, smoothed, Filter, Mapping, Palette,
+    palettized, smoothed, Filter, Mapping, Mask, MatteMode, Palette, Selection,
 };
 
 // TODO: Use states to define whether or not a configuration has been validated to avoid redundant
@@ -27,16 +27,92 @@ pub struct Config {
     #[builder(default)]
     pub mapping: Mapping,
 
+    /// Colors that pass through unchanged instead of being mapped.
+    #[builder(default)]
+    pub protected_colors: Vec<[u8; 3]>,
+
+    /// Explicit source to target colors, taking precedence over palette matching and protected
+    /// colors.
+    #[builder(default)]
+    pub color_remap: Vec<([u8; 3], [u8; 3])>,
+
+    /// Maximum color difference (ΔE, per `diff_formula`) for a pixel to match a protected or
+    /// remapped color.
+    // The derived `Default` would leave it at 0, protecting exact matches only
+    #[builder(default = Config::DEFAULT_PROTECTED_TOLERANCE)]
+    #[cfg_attr(
+        feature = "wasm",
+        serde(default = "Config::default_protected_tolerance")
+    )]
+    pub protected_tolerance: f32,
+
+    /// Palette indices in the order a gradient map runs from dark to light. Empty orders the
+    /// palette by lightness.
+    #[builder(default)]
+    pub gradient_order: Vec<usize>,
+
+    /// Blend between adjacent gradient map stops instead of snapping to the closest one.
+    #[builder(default)]
+    pub gradient_interpolate: bool,
+
     #[cfg_attr(feature = "wasm", tsify(type = "DiffFormula"))]
     #[builder(default)]
     pub diff_formula: color_difference::Formula,
 
+    /// Parametric factors of `diff_formula`, trading lightness against chroma and hue.
+    #[cfg_attr(feature = "wasm", tsify(type = "Weights"))]
+    #[builder(default)]
+    pub diff_weights: color_difference::Weights,
+
+    /// Keep the source lightness of every pixel, taking only hue and chroma from the mapping.
+    #[builder(default)]
+    pub keep_lightness: bool,
+
     #[builder(default = 0)]
     pub quant_level: u8,
 
     #[builder(default = 128)]
     pub transparency_threshold: u8,
 
+    /// Restricts mapping to part of the image, blending between original and mapped pixels by its
+    /// weights.
+    #[cfg_attr(feature = "wasm", tsify(type = "Mask | null"))]
+    pub mask: Option<Mask>,
+
+    /// Width in pixels of the soft transition along the mask edges.
+    #[builder(default)]
+    pub mask_feather: f32,
+
+    /// Map the pixels outside of the mask instead.
+    #[builder(default)]
+    pub mask_invert: bool,
+
+    /// Restricts mapping to colors within hue, chroma and lightness ranges.
+    #[cfg_attr(feature = "wasm", tsify(type = "Selection | null"))]
+    pub selection: Option<Selection>,
+
+    /// Background color pixels are prepared against before mapping.
+    #[cfg_attr(feature = "wasm", tsify(type = "[number, number, number] | null"))]
+    pub matte: Option<[u8; 3]>,
+
+    #[cfg_attr(feature = "wasm", tsify(type = "MatteMode"))]
+    #[builder(default)]
+    pub matte_mode: MatteMode,
+
+    /// Make every pixel opaque against the matte (black if unset).
+    #[builder(default)]
+    pub force_opaque: bool,
+
+    /// Quantize alpha in palettized mode instead of cutting it at `transparency_threshold`.
+    #[cfg_attr(feature = "wasm", tsify(type = "AlphaDithering"))]
+    #[builder(default)]
+    pub alpha_dither: palettized::AlphaDithering,
+
+    /// Partial alpha levels palettized output may use, in addition to fully transparent and
+    /// opaque. Setting any also replaces the `transparency_threshold` cut.
+    #[builder(default)]
+    pub alpha_levels: Vec<u8>,
+
     #[builder(default = num_cpus::get())]
     #[cfg_attr(feature = "wasm", serde(skip))]
     pub num_threads: usize,
@@ -48,6 +124,13 @@ pub struct Config {
     #[builder(default = 0.5)]
     pub smooth_strength: f32,
 
+    /// Blend only the k palette colors nearest to each pixel instead of the whole palette.
+    pub smooth_neighbors: Option<usize>,
+
+    /// Leave palette colors further than this color difference (ΔE) out of the blend. The
+    /// nearest color is always kept.
+    pub smooth_cutoff: Option<f32>,
+
     #[cfg_attr(feature = "wasm", tsify(type = "Dithering"))]
     #[builder(default)]
     pub dither_algorithm: palettized::Dithering,
@@ -55,6 +138,62 @@ pub struct Config {
     #[builder(default = 0.5)]
     pub dither_strength: f32,
 
+    #[builder(default)]
+    pub dither_serpentine: bool,
+
+    #[cfg_attr(feature = "wasm", tsify(type = "DitherSpace"))]
+    #[builder(default)]
+    pub dither_space: palettized::DitherSpace,
+
+    #[cfg_attr(feature = "wasm", tsify(type = "ErrorClamp"))]
+    #[builder(default)]
+    pub dither_clamp: palettized::ErrorClamp,
+
+    // The derived `Default` would leave it at 0, which no matrix dithering accepts
+    #[builder(default = Config::DEFAULT_DITHER_MATRIX_SIZE)]
+    #[cfg_attr(
+        feature = "wasm",
+        serde(default = "Config::default_dither_matrix_size")
+    )]
+    pub dither_matrix_size: u32,
+
+    #[cfg_attr(feature = "wasm", tsify(type = "EdgeAwareness"))]
+    #[builder(default)]
+    pub dither_edges: palettized::EdgeAwareness,
+
+    /// Side of a generated void-and-cluster blue-noise texture (a power of two). `None` uses the
+    /// built-in 64x64 table.
+    pub blue_noise_size: Option<u32>,
+
+    #[builder(default)]
+    pub blue_noise_seed: u32,
+
+    /// Shift the blue-noise texture independently for each color channel.
+    #[builder(default)]
+    pub blue_noise_per_channel: bool,
+
+    /// Shift the blue-noise texture on every frame of an animation.
+    #[builder(default)]
+    pub blue_noise_animated: bool,
+
+    /// Reuse the previous frame's output for pixels of GIF and video frames whose source color
+    /// stayed within `temporal_threshold` on every channel.
+    #[builder(default)]
+    pub temporal_coherence: bool,
+
+    // The derived `Default` would leave it at 0, reusing only bit-identical pixels
+    #[builder(default = Config::DEFAULT_TEMPORAL_THRESHOLD)]
+    #[cfg_attr(
+        feature = "wasm",
+        serde(default = "Config::default_temporal_threshold")
+    )]
+    pub temporal_threshold: u8,
+
+    /// Index of the animation frame being processed, set internally by the media types.
+    #[builder(skip)]
+    #[cfg_attr(feature = "wasm", serde(skip))]
+    pub(crate) frame_index: u32,
+
     #[cfg_attr(all(feature = "serde", not(feature = "wasm")), serde(skip))]
     pub resize_width: Option<u32>,
 
@@ -73,16 +212,45 @@ impl fmt::Display for Config {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "Config {{ palette: ..., mapping: {:?}, color_diff_formula: {:?}, quant_level: {}, transparency_threshold: {}, num_threads: {}, smoothed_formula: {:?}, smoothing_strength: {}, dithering_algorithm: {:?}, dithering_strength: {:?} }}",
+            "Config {{ palette: ..., mapping: {:?}, protected_colors: {:?}, color_remap: {:?}, protected_tolerance: {}, gradient_order: {:?}, gradient_interpolate: {}, color_diff_formula: {:?}, diff_weights: {:?}, keep_lightness: {}, quant_level: {}, transparency_threshold: {}, mask: {:?}, mask_feather: {}, mask_invert: {}, selection: {:?}, matte: {:?}, matte_mode: {:?}, force_opaque: {}, alpha_dither: {:?}, alpha_levels: {:?}, num_threads: {}, smoothed_formula: {:?}, smoothing_strength: {}, smoothing_neighbors: {:?}, smoothing_cutoff: {:?}, dithering_algorithm: {:?}, dithering_strength: {:?}, dithering_serpentine: {}, dithering_space: {:?}, dithering_clamp: {:?}, dithering_matrix_size: {}, dithering_edges: {:?}, blue_noise_size: {:?}, blue_noise_seed: {}, blue_noise_per_channel: {}, blue_noise_animated: {}, temporal_coherence: {}, temporal_threshold: {} }}",
             self.mapping,
+            self.protected_colors,
+            self.color_remap,
+            self.protected_tolerance,
+            self.gradient_order,
+            self.gradient_interpolate,
             self.diff_formula,
+            self.diff_weights,
+            self.keep_lightness,
             self.quant_level,
             self.transparency_threshold,
+            self.mask.as_ref().map(Mask::to_string),
+            self.mask_feather,
+            self.mask_invert,
+            self.selection,
+            self.matte,
+            self.matte_mode,
+            self.force_opaque,
+            self.alpha_dither,
+            self.alpha_levels,
             self.num_threads,
             self.smooth_formula,
             self.smooth_strength,
+            self.smooth_neighbors,
+            self.smooth_cutoff,
             self.dither_algorithm,
             self.dither_strength,
+            self.dither_serpentine,
+            self.dither_space,
+            self.dither_clamp,
+            self.dither_matrix_size,
+            self.dither_edges,
+            self.blue_noise_size,
+            self.blue_noise_seed,
+            self.blue_noise_per_channel,
+            self.blue_noise_animated,
+            self.temporal_coherence,
+            self.temporal_threshold,
         )
     }
 }
@@ -90,6 +258,28 @@ impl fmt::Display for Config {
 impl Config {
     const MAX_QUANT_LEVEL: u8 = 5;
     const MAX_PALETTE_SIZE: usize = 255; // Actual max is 256, but 1 is reserved for transparency
+    const DEFAULT_PROTECTED_TOLERANCE: f32 = 1.0;
+    const DEFAULT_DITHER_MATRIX_SIZE: u32 = 8;
+    const MIN_DITHER_MATRIX_SIZE: u32 = 2;
+    const MAX_DITHER_MATRIX_SIZE: u32 = 16;
+    const MIN_BLUE_NOISE_SIZE: u32 = 4;
+    const MAX_BLUE_NOISE_SIZE: u32 = 256;
+    const DEFAULT_TEMPORAL_THRESHOLD: u8 = 4;
+
+    #[cfg(feature = "wasm")]
+    fn default_protected_tolerance() -> f32 {
+        Self::DEFAULT_PROTECTED_TOLERANCE
+    }
+
+    #[cfg(feature = "wasm")]
+    fn default_dither_matrix_size() -> u32 {
+        Self::DEFAULT_DITHER_MATRIX_SIZE
+    }
+
+    #[cfg(feature = "wasm")]
+    fn default_temporal_threshold() -> u8 {
+        Self::DEFAULT_TEMPORAL_THRESHOLD
+    }
 
     pub fn validate(&self) -> Result<()> {
         if self.palette.colors.is_empty() || self.palette.colors.len() > 256 {
@@ -106,14 +296,87 @@ impl Config {
             });
         }
 
+        if let Some(&index) = self
+            .gradient_order
+            .iter()
+            .find(|&&index| index >= self.palette.colors.len())
+        {
+            return Err(Error::InvalidGradientOrder {
+                index,
+                size: self.palette.colors.len(),
+            });
+        }
+
+        let weights = self.diff_weights;
+        if [weights.lightness, weights.chroma, weights.hue]
+            .iter()
+            .any(|k| !k.is_finite() || *k <= 0.0)
+        {
+            return Err(Error::InvalidDiffWeights(weights));
+        }
+
+        if self.protected_tolerance < 0.0 {
+            return Err(Error::InvalidProtectedTolerance(self.protected_tolerance));
+        }
+
+        if let Some(mask) = &self.mask {
+            mask.validate()?;
+        }
+
+        if self.mask_feather < 0.0 {
+            return Err(Error::InvalidMaskFeather(self.mask_feather));
+        }
+
+        if let Some(selection) = &self.selection {
+            selection.validate()?;
+        }
+
         if self.smooth_strength < 0.0 || self.smooth_strength > 1.0 {
             return Err(Error::InvalidSmoothStrength(self.smooth_strength));
         }
 
+        if self.smooth_neighbors == Some(0) {
+            return Err(Error::InvalidSmoothNeighbors);
+        }
+
+        if let Some(cutoff) = self.smooth_cutoff {
+            if cutoff.is_nan() || cutoff < 0.0 {
+                return Err(Error::InvalidSmoothCutoff(cutoff));
+            }
+        }
+
         if self.dither_strength < 0.0 || self.dither_strength > 1.0 {
             return Err(Error::InvalidDitherStrength(self.dither_strength));
         }
 
+        let uses_matrix = matches!(
+            self.dither_algorithm,
+            palettized::Dithering::Bayer | palettized::Dithering::Pattern
+        ) || self.alpha_dither == palettized::AlphaDithering::Ordered;
+        if uses_matrix
+            && (!self.dither_matrix_size.is_power_of_two()
+                || !(Self::MIN_DITHER_MATRIX_SIZE..=Self::MAX_DITHER_MATRIX_SIZE)
+                    .contains(&self.dither_matrix_size))
+        {
+            return Err(Error::InvalidDitherMatrixSize {
+                value: self.dither_matrix_size,
+                min: Self::MIN_DITHER_MATRIX_SIZE,
+                max: Self::MAX_DITHER_MATRIX_SIZE,
+            });
+        }
+
+        if let Some(size) = self.blue_noise_size {
+            if !size.is_power_of_two()
+                || !(Self::MIN_BLUE_NOISE_SIZE..=Self::MAX_BLUE_NOISE_SIZE).contains(&size)
+            {
+                return Err(Error::InvalidBlueNoiseSize {
+                    value: size,
+                    min: Self::MIN_BLUE_NOISE_SIZE,
+                    max: Self::MAX_BLUE_NOISE_SIZE,
+                });
+            }
+        }
+
         #[cfg(not(target_arch = "wasm32"))]
         if self.num_threads > num_cpus::get() {
             return Err(Error::InvalidThreadCount(num_cpus::get()));
@@ -126,4 +389,12 @@ impl Config {
         config.validate()?;
         Ok(config)
     }
+
+    /// A copy of this configuration for processing frame `index` of an animation.
+    pub(crate) fn for_frame(&self, index: usize) -> Self {
+        Self {
+            frame_index: index as u32,
+            ..self.clone()
+        }
+    }
 }
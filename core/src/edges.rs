@@ -0,0 +1,94 @@
+use rayon::prelude::*;
+
+use crate::{
+    color::{self, Lab},
+    config::Config,
+    palettized::EdgeAwareness,
+};
+
+// Sobel magnitudes (in Lab lightness units) mapped to no edge and to a full edge. A clean step
+// of 40 L between two flat areas yields a magnitude of 160.
+const EDGE_LOW: f32 = 20.0;
+const EDGE_HIGH: f32 = 160.0;
+
+/// Per-pixel edge strength in `0.0..=1.0` used to attenuate dithering, or `None` when
+/// `dither_edges` is off.
+///
+/// The Sobel gradient of Lab lightness is computed once per image. In
+/// [`EdgeAwareness::Structure`] mode the strength is further scaled by the coherence of the local
+/// structure tensor, so oriented features like text strokes and line art are protected while
+/// isotropic texture such as foliage or noise keeps its dithering.
+pub(crate) fn edge_weights(
+    image_data: &[u8],
+    width: u32,
+    height: u32,
+    config: &Config,
+) -> Option<Vec<f32>> {
+    if config.dither_edges == EdgeAwareness::None {
+        return None;
+    }
+
+    let (width, height) = (width as usize, height as usize);
+    let lightness: Vec<f32> = image_data
+        .par_chunks_exact(4)
+        .map(|px| {
+            let linear = [px[0], px[1], px[2]].map(color::srgb_to_linear);
+            let Lab { l, .. } = color::linear_to_lab(linear);
+            l
+        })
+        .collect();
+
+    let at = |x: isize, y: isize| {
+        let x = x.clamp(0, width as isize - 1) as usize;
+        let y = y.clamp(0, height as isize - 1) as usize;
+        lightness[y * width + x]
+    };
+
+    let gradients: Vec<(f32, f32)> = (0..width * height)
+        .into_par_iter()
+        .map(|i| {
+            let (x, y) = ((i % width) as isize, (i / width) as isize);
+            let gx = (at(x + 1, y - 1) + 2.0 * at(x + 1, y) + at(x + 1, y + 1))
+                - (at(x - 1, y - 1) + 2.0 * at(x - 1, y) + at(x - 1, y + 1));
+            let gy = (at(x - 1, y + 1) + 2.0 * at(x, y + 1) + at(x + 1, y + 1))
+                - (at(x - 1, y - 1) + 2.0 * at(x, y - 1) + at(x + 1, y - 1));
+            (gx, gy)
+        })
+        .collect();
+
+    let strength =
+        |(gx, gy): (f32, f32)| ((gx.hypot(gy) - EDGE_LOW) / (EDGE_HIGH - EDGE_LOW)).clamp(0.0, 1.0);
+
+    let weights = match config.dither_edges {
+        EdgeAwareness::Structure => (0..width * height)
+            .into_par_iter()
+            .map(|i| {
+                let (x, y) = (i % width, i / width);
+
+                // Structure tensor summed over the 3x3 neighborhood
+                let (mut jxx, mut jxy, mut jyy) = (0.0f32, 0.0f32, 0.0f32);
+                for ny in y.saturating_sub(1)..(y + 2).min(height) {
+                    for nx in x.saturating_sub(1)..(x + 2).min(width) {
+                        let (gx, gy) = gradients[ny * width + nx];
+                        jxx += gx * gx;
+                        jxy += gx * gy;
+                        jyy += gy * gy;
+                    }
+                }
+
+                // (λ1 - λ2)² / (λ1 + λ2)²: 1 for a single dominant orientation, 0 for isotropy
+                let trace = jxx + jyy;
+                let coherence = if trace > f32::EPSILON {
+                    ((jxx - jyy).powi(2) + 4.0 * jxy * jxy) / (trace * trace)
+                } else {
+                    0.0
+                };
+
+                strength(gradients[i]) * coherence
+            })
+            .collect(),
+        _ => gradients.into_par_iter().map(strength).collect(),
+    };
+
+    Some(weights)
+}
@@ -15,6 +15,17 @@ pub enum Error {
     #[error("PNG encoding or I/O error: {0}")]
     PngEncodingError(#[from] png::EncodingError),
 
+    #[error("GIF encoding or I/O error: {0}")]
+    GifEncodingError(#[from] gif::EncodingError),
+
+    #[cfg(feature = "tiff")]
+    #[error("TIFF error: {0}")]
+    TiffError(#[from] tiff::TiffError),
+
+    #[cfg(feature = "tiff")]
+    #[error("Unsupported TIFF color type: {0:?}")]
+    UnsupportedTiffColor(tiff::ColorType),
+
     #[cfg(feature = "video")]
     #[error("FFmpeg error: {0}")]
     FFmpegError(#[from] ffmpeg_next::Error),
@@ -48,18 +59,55 @@ pub enum Error {
     #[error("Invalid quant_level: must be between 0 (to disable) and {max}, got {value}")]
     InvalidQuantLevel { value: u8, max: u8 },
 
+    #[error("Invalid gradient_order: palette index {index} out of bounds (size {size})")]
+    InvalidGradientOrder { index: usize, size: usize },
+
+    #[error("Invalid diff_weights: every factor must be positive, got {0:?}")]
+    InvalidDiffWeights(crate::color_difference::Weights),
+
+    #[error("Invalid protected_tolerance: must not be negative, got {0}")]
+    InvalidProtectedTolerance(f32),
+
+    #[error("Invalid mask: {0}")]
+    InvalidMask(&'static str),
+
+    #[error("Invalid mask_feather: must not be negative, got {0}")]
+    InvalidMaskFeather(f32),
+
+    #[error("Invalid selection: {0}")]
+    InvalidSelection(&'static str),
+
     #[error("Invalid smooth_strength: must be between 0.0 and 1.0, got {0}")]
     InvalidSmoothStrength(f32),
 
+    #[error("Invalid smooth_neighbors: must blend at least 1 color")]
+    InvalidSmoothNeighbors,
+
+    #[error("Invalid smooth_cutoff: must not be negative, got {0}")]
+    InvalidSmoothCutoff(f32),
+
     #[error("Invalid dither_strength: must be between 0.0 and 1.0, got {0}")]
     InvalidDitherStrength(f32),
 
+    #[error(
+        "Invalid dither_matrix_size: must be a power of two between {min} and {max}, got {value}"
+    )]
+    InvalidDitherMatrixSize { value: u32, min: u32, max: u32 },
+
+    #[error(
+        "Invalid blue_noise_size: must be a power of two between {min} and {max}, got {value}"
+    )]
+    InvalidBlueNoiseSize { value: u32, min: u32, max: u32 },
+
     #[error("Invalid resize dimensions: width and height must be positive")]
     InvalidResizeDimensions,
 
     #[error("Invalid resize scale: Scale factor must be positive")]
     InvalidResizeScale,
 
+    #[error("Invalid icon size: must be between 1 and {max} pixels, got {size}")]
+    InvalidIconSize { size: u32, max: u32 },
+
     #[error(
         "Invalid thread count: Specifying more threads than available CPU cores ({0}) is redundant"
     )]
@@ -307,9 +307,6 @@ impl Processor {
                         compute_pass.set_pipeline(&self.context.palettized_pipeline);
                         compute_pass.set_bind_group(1, &self.context.blue_noise_bind_group, &[]);
                         match config.dither_algorithm {
-                            Dithering::Fs => {
-                                compute_pass.dispatch_workgroups(1, 1, 1);
-                            }
                             Dithering::Bn | Dithering::None => {
                                 let dispatch_x =
                                     gpu_chunk_config.image_width.div_ceil(WORKGROUP_SIZE_X);
@@ -317,8 +314,16 @@ impl Processor {
                                     gpu_chunk_config.image_height.div_ceil(WORKGROUP_SIZE_Y);
                                 compute_pass.dispatch_workgroups(dispatch_x, dispatch_y, 1);
                             }
+                            _ => {
+                                compute_pass.dispatch_workgroups(1, 1, 1);
+                            }
                         }
                     }
+                    Mapping::GradientMap => {
+                        return Err(Error::Gpu(
+                            "Gradient maps are not supported by the compute pipeline".to_string(),
+                        ));
+                    }
                     Mapping::Smoothed => {
                         compute_pass.set_pipeline(&self.context.smoothed_pipeline);
                         let dispatch_x = gpu_chunk_config.image_width.div_ceil(WORKGROUP_SIZE_X);
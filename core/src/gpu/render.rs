@@ -1107,7 +1107,7 @@ impl Renderer {
         let mapping = config.mapping;
 
         let mapping_frag_bg_to_use: Option<wgpu::BindGroup> = match mapping {
-            Mapping::Smoothed | Mapping::Palettized => {
+            Mapping::Smoothed | Mapping::Palettized | Mapping::GradientMap => {
                 let gpu_config_data =
                     GpuConfig::from_config(&config, work_tex.size().width, work_tex.size().height);
 
@@ -1165,7 +1165,7 @@ impl Renderer {
             }
         };
 
-        let blue_noise_bg_to_use: Option<wgpu::BindGroup> = if mapping == Mapping::Palettized {
+        let blue_noise_bg_to_use: Option<wgpu::BindGroup> = if mapping != Mapping::Smoothed {
             Some(ctx.device.create_bind_group(&wgpu::BindGroupDescriptor {
                 label: Some("blue_noise_bg"),
                 layout: &ctx.blue_noise_bgl,
@@ -1222,7 +1222,7 @@ impl Renderer {
             rpass.set_pipeline(pipe);
 
             match mapping {
-                Mapping::Smoothed | Mapping::Palettized => {
+                Mapping::Smoothed | Mapping::Palettized | Mapping::GradientMap => {
                     rpass.set_bind_group(0, mapping_frag_bg_to_use.as_ref().unwrap(), &[]);
                 }
             }
@@ -1606,9 +1606,21 @@ impl Renderer {
             source: wgpu::ShaderSource::Wgsl(Cow::Owned(smoothed_fs_code)),
         });
 
+        shaders.insert(
+            PathBuf::from("gradient_map_fs.wgsl"),
+            include_str!("shaders/gradient_map_fs.wgsl").to_string(),
+        );
+
+        let gradient_map_fs_code = preprocess(&shaders, "gradient_map_fs.wgsl").unwrap();
+
+        let gradient_map_fs = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("present_gradient_map_fs"),
+            source: wgpu::ShaderSource::Wgsl(Cow::Owned(gradient_map_fs_code)),
+        });
+
         let make = |fs: &wgpu::ShaderModule, mapping: Mapping| {
             let mut layout = vec![mapping_frag_bgl, uni_bgl];
-            if mapping == Mapping::Palettized {
+            if mapping != Mapping::Smoothed {
                 layout.push(blue_noise_bgl);
             }
 
@@ -1650,6 +1662,10 @@ impl Renderer {
             make(&palettized_fs, Mapping::Palettized),
         );
         present_pipelines.insert(Mapping::Smoothed, make(&smoothed_fs, Mapping::Smoothed));
+        present_pipelines.insert(
+            Mapping::GradientMap,
+            make(&gradient_map_fs, Mapping::GradientMap),
+        );
 
         (
             blit_pipeline,
@@ -16,6 +16,7 @@ struct Config {
     dither_strength: f32,
     image_width: u32,
     image_height: u32,
+    gradient_interpolate: u32,
 };
 
 const WHITE_X: f32 = 95.047;
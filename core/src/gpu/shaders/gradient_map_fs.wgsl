@@ -0,0 +1,50 @@
+#include "common.wgsl"
+
+@group(0) @binding(0) var samp: sampler;
+@group(0) @binding(1) var tex: texture_2d<f32>;
+@group(0) @binding(2) var<uniform> config: Config;
+
+@group(2) @binding(0) var blue_noise_tex: texture_2d<f32>;
+@group(2) @binding(1) var blue_noise_sampler: sampler;
+
+// The palette holds the gradient stops, ordered from dark to light
+@fragment
+fn fs_main(
+    @builtin(position) frag_coord: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+) -> @location(0) vec4<f32> {
+    let pixel = textureSample(tex, samp, uv);
+    let blue_noise_size = vec2<f32>(64.0, 64.0);
+    let noise_uv = (frag_coord.xy % blue_noise_size) / blue_noise_size;
+    let noise = textureSample(blue_noise_tex, blue_noise_sampler, noise_uv).r - 0.5;
+
+    if pixel.a * 255.0 < f32(config.transparency_threshold) {
+        return vec4<f32>(0.0, 0.0, 0.0, 0.0);
+    }
+
+    let last = f32(config.palette_size - 1u);
+    var position = clamp(linear_rgb_to_lab(pixel.rgb).l / 100.0, 0.0, 1.0) * last;
+
+    if config.gradient_interpolate != 0u {
+        let lower = floor(position);
+        let t = position - lower;
+        let a = rgba_to_lab(color_at(u32(lower)));
+        let b = rgba_to_lab(color_at(u32(min(lower + 1.0, last))));
+        let blended = Lab(mix(a.l, b.l, t), mix(a.a, b.a, t), mix(a.b, b.b, t), 0.0);
+        return vec4<f32>(lab_to_linear_rgb(blended), 1.0);
+    }
+
+    // Dithering: error diffusion is not possible in a fragment shader, 2u is Blue-Noise
+    if config.dither_algorithm == 2u {
+        position = position + noise * 2.0 * config.dither_strength;
+    }
+
+    let stop = color_at(u32(clamp(round(position), 0.0, last)));
+    let final_rgb_linear = vec3<f32>(
+        srgb_to_linear(f32((stop >> 0u) & 0xFFu) / 255.0),
+        srgb_to_linear(f32((stop >> 8u) & 0xFFu) / 255.0),
+        srgb_to_linear(f32((stop >> 16u) & 0xFFu) / 255.0),
+    );
+
+    return vec4<f32>(final_rgb_linear, 1.0);
+}
@@ -1,4 +1,4 @@
-use crate::{palettized::Dithering, Config, Result};
+use crate::{color::ConvertToLab, gradient_map, palettized::Dithering, Config, Mapping, Result};
 use std::collections::HashMap;
 use std::path::PathBuf;
 use std::sync::Arc;
@@ -134,15 +134,27 @@ pub struct GpuConfig {
     pub dither_strength: f32,
     pub image_width: u32,
     pub image_height: u32,
-    pub _padding1: u32,
+    pub gradient_interpolate: u32,
     pub _padding2: u32,
     pub _padding3: u32,
 }
 
 impl GpuConfig {
     pub fn from_config(config: &Config, processing_width: u32, processing_height: u32) -> Self {
+        // Gradient maps read the palette as their stops, from dark to light
+        let colors = match config.mapping {
+            Mapping::GradientMap => {
+                let lab_colors: Vec<_> = config.palette.colors.iter().map(|c| c.to_lab()).collect();
+                gradient_map::order(config, &lab_colors)
+                    .into_iter()
+                    .map(|i| config.palette.colors[i])
+                    .collect()
+            }
+            _ => config.palette.colors.clone(),
+        };
+
         let mut palette = [0u32; MAX_PALETTE_SIZE];
-        for (i, rgb_color) in config.palette.colors.iter().enumerate() {
+        for (i, rgb_color) in colors.iter().enumerate() {
             if i >= MAX_PALETTE_SIZE {
                 break;
             }
@@ -165,18 +177,22 @@ impl GpuConfig {
                 crate::smoothed::Formula::Gaussian => 1,
                 crate::smoothed::Formula::Rq => 2,
             },
-            palette_size: config.palette.colors.len() as u32,
+            palette_size: colors.len() as u32,
             palette,
             smooth_strength: config.smooth_strength,
             dither_algorithm: match config.dither_algorithm {
                 Dithering::None => 0,
                 Dithering::Fs => 1,
                 Dithering::Bn => 2,
+                // Only the live preview sees these, the compute path hands them to the CPU, so
+                // approximate ordered algorithms with blue noise and the rest with Floyd-Steinberg.
+                Dithering::Bayer | Dithering::Pattern => 2,
+                _ => 1,
             },
             dither_strength: config.dither_strength,
             image_width: processing_width,
             image_height: processing_height,
-            _padding1: 0,
+            gradient_interpolate: config.gradient_interpolate as u32,
             _padding2: 0,
             _padding3: 0,
         }
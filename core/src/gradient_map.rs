@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+
+use image::{Rgb, Rgba};
+use rayon::prelude::*;
+
+use crate::{
+    blue_noise,
+    color::{ConvertToLab, Lab},
+    config::Config,
+    error::Result,
+    palettized::{self, Dithering},
+};
+
+/// Palette colors laid out from dark to light, which source lightness is spread over.
+pub(crate) struct Gradient {
+    stops: Vec<(Lab, [u8; 3])>,
+    interpolate: bool,
+}
+
+/// Palette indices from the first stop to the last: `gradient_order`, or the palette ordered by
+/// lightness when no order is given.
+pub(crate) fn order(config: &Config, lab_colors: &[Lab]) -> Vec<usize> {
+    if !config.gradient_order.is_empty() {
+        return config.gradient_order.clone();
+    }
+
+    let mut order: Vec<usize> = (0..lab_colors.len()).collect();
+    order.sort_by(|&a, &b| lab_colors[a].l.total_cmp(&lab_colors[b].l));
+    order
+}
+
+impl Gradient {
+    pub fn new(config: &Config, lab_colors: &[Lab]) -> Self {
+        Gradient {
+            stops: order(config, lab_colors)
+                .into_iter()
+                .map(|i| (lab_colors[i], config.palette.colors[i].0))
+                .collect(),
+            interpolate: config.gradient_interpolate,
+        }
+    }
+
+    /// Position of a lightness along the gradient, from 0 (first stop) to the last stop's index.
+    fn position(&self, lightness: f32) -> f32 {
+        (lightness / 100.0).clamp(0.0, 1.0) * self.last() as f32
+    }
+
+    fn last(&self) -> usize {
+        self.stops.len() - 1
+    }
+
+    fn stop(&self, position: f32) -> [u8; 3] {
+        self.stops[(position.round().max(0.0) as usize).min(self.last())].1
+    }
+
+    /// Color at a position, blended in Lab between the surrounding stops when interpolating.
+    fn color(&self, position: f32) -> [u8; 3] {
+        if !self.interpolate {
+            return self.stop(position);
+        }
+
+        let lower = (position.floor() as usize).min(self.last());
+        let upper = (lower + 1).min(self.last());
+        let t = position - lower as f32;
+        let (a, b) = (self.stops[lower].0, self.stops[upper].0);
+        Lab {
+            l: a.l + (b.l - a.l) * t,
+            a: a.a + (b.a - a.a) * t,
+            b: a.b + (b.b - a.b) * t,
+        }
+        .to_rgb()
+        .0
+    }
+}
+
+/// Color of a single pixel on the gradient, without dithering. The gradient is built on every
+/// call, so whole images go through [`map`] instead.
+pub(crate) fn closest_rgb(reference: &Lab, colors: &[Lab], config: &Config) -> Rgb<u8> {
+    let gradient = Gradient::new(config, colors);
+    Rgb(gradient.color(gradient.position(reference.l)))
+}
+
+/// Maps every pixel's lightness onto the gradient. Dithering spreads pixels between the two
+/// stops around their position; it has no effect when interpolating, which leaves nothing to
+/// dither.
+pub(crate) fn map(
+    image_data: &mut [u8],
+    width: u32,
+    config: &Config,
+    lab_colors: &[Lab],
+    edges: Option<&[f32]>,
+    locked: Option<&[Option<[u8; 3]>]>,
+) -> Result<()> {
+    let gradient = Gradient::new(config, lab_colors);
+    let width = width as usize;
+
+    // Position of every visible pixel, `None` for pixels cleared by the transparency threshold
+    let positions: Vec<Option<f32>> = image_data
+        .par_chunks_exact(4)
+        .map_init(HashMap::<[u8; 3], f32>::new, |cache, px| {
+            if px[3] < config.transparency_threshold {
+                return None;
+            }
+            let rgb = [px[0], px[1], px[2]];
+            Some(*cache.entry(rgb).or_insert_with(|| {
+                gradient.position(Rgba([rgb[0], rgb[1], rgb[2], 255]).to_lab().l)
+            }))
+        })
+        .collect();
+
+    if let Some(kernel) = config.dither_algorithm.kernel() {
+        if !gradient.interpolate {
+            let outputs = diffuse(&gradient, &positions, width, kernel, config, edges, locked);
+            write(image_data, &outputs);
+            return Ok(());
+        }
+    }
+
+    // Ordered offsets span -1..1 stops, so the default strength of 0.5 covers exactly the gap
+    // between two stops
+    let offset: Option<Box<dyn Fn(usize, usize) -> f32 + Sync>> = match config.dither_algorithm {
+        _ if gradient.interpolate => None,
+        Dithering::Bn => {
+            let texture = blue_noise::Texture::for_config(config);
+            Some(Box::new(move |x, y| texture.sample(x, y)[0] / 127.5))
+        }
+        Dithering::Bayer | Dithering::Pattern => {
+            let size = config.dither_matrix_size as usize;
+            let ranks = palettized::bayer_matrix(size);
+            let levels = (size * size) as f32;
+            Some(Box::new(move |x, y| {
+                ((ranks[(y % size) * size + x % size] as f32 + 0.5) / levels - 0.5) * 2.0
+            }))
+        }
+        _ => None,
+    };
+
+    let outputs: Vec<Option<[u8; 3]>> = positions
+        .par_iter()
+        .enumerate()
+        .map(|(i, position)| {
+            let position = (*position)?;
+            Some(match &offset {
+                Some(offset) => {
+                    let strength =
+                        config.dither_strength * edges.map_or(1.0, |edges| 1.0 - edges[i]);
+                    gradient.stop(position + offset(i % width, i / width) * strength)
+                }
+                None => gradient.color(position),
+            })
+        })
+        .collect();
+    write(image_data, &outputs);
+
+    Ok(())
+}
+
+/// Error diffusion over the scalar gradient position, in the configured scan direction.
+fn diffuse(
+    gradient: &Gradient,
+    positions: &[Option<f32>],
+    width: usize,
+    kernel: &palettized::Kernel,
+    config: &Config,
+    edges: Option<&[f32]>,
+    locked: Option<&[Option<[u8; 3]>]>,
+) -> Vec<Option<[u8; 3]>> {
+    let height = positions.len() / width.max(1);
+    let mut errors = vec![0.0f32; positions.len()];
+    let mut outputs = vec![None; positions.len()];
+
+    for y in 0..height {
+        let reversed = config.dither_serpentine && y % 2 == 1;
+        for step in 0..width {
+            let x = if reversed { width - 1 - step } else { step };
+            let i = y * width + x;
+            let Some(position) = positions[i] else {
+                continue;
+            };
+
+            let keep = palettized::error_share(edges, locked, i);
+            let value = position + errors[i] * keep;
+            let stop = value.round().clamp(0.0, gradient.last() as f32);
+            outputs[i] = Some(gradient.stop(stop));
+
+            let error = (value - stop) * config.dither_strength * keep;
+            for (dx, dy, weight) in kernel.weights() {
+                let dx = if reversed { -dx } else { dx };
+                let (nx, ny) = (x as i64 + dx as i64, y + dy);
+                if nx >= 0 && (nx as usize) < width && ny < height {
+                    errors[ny * width + nx as usize] += error * weight;
+                }
+            }
+        }
+    }
+
+    outputs
+}
+
+fn write(image_data: &mut [u8], outputs: &[Option<[u8; 3]>]) {
+    for (px, output) in image_data.chunks_exact_mut(4).zip(outputs) {
+        match output {
+            Some(rgb) => px.copy_from_slice(&[rgb[0], rgb[1], rgb[2], 255]),
+            None => px.fill(0),
+        }
+    }
+}
@@ -1,20 +1,32 @@
+mod alpha;
+mod blue_noise;
 mod color;
 pub mod color_difference;
 mod config;
+mod edges;
 pub mod error;
+mod gradient_map;
+mod lightness;
+mod locks;
+mod mask;
 mod math;
+mod matte;
 pub mod media;
 mod palette;
 pub mod palettized;
 mod processing;
+mod selection;
 pub mod smoothed;
+mod temporal;
 pub use config::Config;
 pub use error::{Error, Result};
-pub use media::{Gif, Ico, Image, Media};
+pub use mask::Mask;
+pub use media::{Gif, GifOptimization, Ico, Image, Media, OutputFormat, PngOptions};
+pub use selection::Selection;
 #[cfg(feature = "gpu")]
 pub mod gpu;
 
-pub use processing::process_pixels;
+pub use processing::{process_pixels, process_pixels_cpu};
 
 pub use palette::{
     create_id, custom_palettes_dir, delete_custom_palette, find_palette, get_all_palettes,
@@ -38,6 +50,8 @@ pub enum Mapping {
     Palettized,
     #[default]
     Smoothed,
+    /// Spread source lightness over the palette ordered from dark to light
+    GradientMap,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Hash)]
@@ -51,6 +65,18 @@ pub enum Filter {
     Lanczos3,
 }
 
+/// How pixels relate to `Config::matte`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Hash)]
+#[cfg_attr(feature = "wasm", derive(Tsify, Serialize, Deserialize))]
+#[cfg_attr(feature = "cli", derive(ValueEnum, Display))]
+pub enum MatteMode {
+    /// Blend partially transparent pixels over the matte color
+    #[default]
+    Composite,
+    /// Recover straight colors from pixels that were already blended over the matte color
+    Unpremultiply,
+}
+
 impl From<Filter> for ::image::imageops::FilterType {
     fn from(f: Filter) -> Self {
         match f {
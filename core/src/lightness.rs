@@ -0,0 +1,49 @@
+use std::collections::HashMap;
+
+use image::Rgb;
+use rayon::prelude::*;
+
+use crate::{
+    color::{ConvertToLab, Lab},
+    config::Config,
+};
+
+/// Lightness of every source pixel, when the output keeps it.
+pub(crate) fn capture(image_data: &[u8], config: &Config) -> Option<Vec<f32>> {
+    if !config.keep_lightness {
+        return None;
+    }
+
+    Some(
+        image_data
+            .par_chunks_exact(4)
+            .map_init(HashMap::<[u8; 3], f32>::new, |cache, px| {
+                let rgb = [px[0], px[1], px[2]];
+                *cache.entry(rgb).or_insert_with(|| Rgb(rgb).to_lab().l)
+            })
+            .collect(),
+    )
+}
+
+/// Swaps the lightness of every visible mapped pixel for its source lightness, keeping the mapped
+/// hue and chroma.
+pub(crate) fn restore(image_data: &mut [u8], source: &[f32]) {
+    image_data
+        .par_chunks_exact_mut(4)
+        .zip(source.par_iter())
+        .for_each_init(
+            HashMap::<([u8; 3], u32), [u8; 3]>::new,
+            |cache, (px, &l)| {
+                if px[3] == 0 {
+                    return;
+                }
+
+                let rgb = [px[0], px[1], px[2]];
+                let out = cache.entry((rgb, l.to_bits())).or_insert_with(|| {
+                    let Lab { a, b, .. } = Rgb(rgb).to_lab();
+                    Lab { l, a, b }.to_rgb().0
+                });
+                px[..3].copy_from_slice(out);
+            },
+        );
+}
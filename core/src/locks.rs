@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+
+use image::Rgb;
+use rayon::prelude::*;
+
+use crate::{
+    color::{ConvertToLab, Lab},
+    color_difference,
+    config::Config,
+};
+
+/// Output color forced for each pixel, or `None` for pixels mapped normally.
+pub(crate) type LockedPixels = Vec<Option<[u8; 3]>>;
+
+/// Colors that bypass palette matching: `color_remap` sources are replaced by their targets and
+/// `protected_colors` pass through unchanged, both matched within `protected_tolerance` (ΔE).
+struct Locks {
+    remap: Vec<(Lab, [u8; 3])>,
+    protected: Vec<Lab>,
+}
+
+impl Locks {
+    fn new(config: &Config) -> Option<Self> {
+        if config.color_remap.is_empty() && config.protected_colors.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            remap: config
+                .color_remap
+                .iter()
+                .map(|&(source, target)| (Rgb(source).to_lab(), target))
+                .collect(),
+            protected: config
+                .protected_colors
+                .iter()
+                .map(|&color| Rgb(color).to_lab())
+                .collect(),
+        })
+    }
+
+    /// The remap table takes precedence over protected colors; within each, the closest entry
+    /// wins.
+    fn target(&self, rgb: [u8; 3], config: &Config) -> Option<[u8; 3]> {
+        let lab = Rgb(rgb).to_lab();
+        let within = |other: &Lab| {
+            let distance = color_difference::delta_e(
+                &lab,
+                other,
+                config.diff_formula,
+                &color_difference::Weights::NEUTRAL,
+            );
+            (distance <= config.protected_tolerance).then_some(distance)
+        };
+
+        let remapped = self
+            .remap
+            .iter()
+            .filter_map(|(source, target)| within(source).map(|d| (d, *target)))
+            .min_by(|(a, _), (b, _)| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal))
+            .map(|(_, target)| target);
+
+        remapped.or_else(|| {
+            self.protected
+                .iter()
+                .any(|p| within(p).is_some())
+                .then_some(rgb)
+        })
+    }
+}
+
+/// Resolves the locked pixels of an image before it is mapped, or `None` if no colors are locked.
+pub(crate) fn resolve(image_data: &[u8], config: &Config) -> Option<LockedPixels> {
+    let locks = Locks::new(config)?;
+
+    Some(
+        image_data
+            .par_chunks_exact(4)
+            .map_init(HashMap::<[u8; 3], Option<[u8; 3]>>::new, |cache, px| {
+                let rgb = [px[0], px[1], px[2]];
+                *cache
+                    .entry(rgb)
+                    .or_insert_with(|| locks.target(rgb, config))
+            })
+            .collect(),
+    )
+}
+
+/// Puts the locked colors back over whatever the mapping produced. Pixels the mapping made fully
+/// transparent stay transparent.
+pub(crate) fn restore(image_data: &mut [u8], locked: &[Option<[u8; 3]>]) {
+    image_data
+        .par_chunks_exact_mut(4)
+        .zip(locked.par_iter())
+        .for_each(|(px, target)| {
+            if let Some(target) = target {
+                if px[3] != 0 {
+                    px[..3].copy_from_slice(target);
+                }
+            }
+        });
+}
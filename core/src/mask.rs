@@ -0,0 +1,232 @@
+use std::{fmt, path::Path};
+
+use image::{imageops, DynamicImage, GrayImage};
+#[cfg(feature = "wasm")]
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "wasm")]
+use tsify::Tsify;
+
+use crate::{
+    config::Config,
+    error::{Error, Result},
+};
+
+/// Part of an image that mapping is restricted to. Pixels outside of it keep their original color.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "wasm", derive(Tsify, Serialize, Deserialize))]
+#[cfg_attr(feature = "wasm", serde(rename_all = "camelCase", tag = "kind"))]
+pub enum Mask {
+    /// Per-pixel weights, from 0 (keep the original) to 255 (fully mapped), stretched over each
+    /// frame
+    Image {
+        width: u32,
+        height: u32,
+        weights: Vec<u8>,
+    },
+    /// Rectangle in pixels of the processed frame
+    Rect {
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    },
+    /// Polygon in pixels of the processed frame, filled with the even-odd rule
+    Polygon { points: Vec<[f32; 2]> },
+}
+
+impl fmt::Display for Mask {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mask::Image { width, height, .. } => write!(f, "Image({width}x{height})"),
+            Mask::Rect {
+                x,
+                y,
+                width,
+                height,
+            } => write!(f, "Rect({x}, {y}, {width}x{height})"),
+            Mask::Polygon { points } => write!(f, "Polygon({} points)", points.len()),
+        }
+    }
+}
+
+impl Mask {
+    pub fn from_file(path: &Path) -> Result<Self> {
+        Ok(Self::from_image(&image::open(path)?))
+    }
+
+    pub fn from_memory(bytes: &[u8]) -> Result<Self> {
+        Ok(Self::from_image(&image::load_from_memory(bytes)?))
+    }
+
+    /// Uses the alpha channel of images that have any transparency, and the luminance otherwise.
+    pub fn from_image(image: &DynamicImage) -> Self {
+        let rgba = image.to_rgba8();
+        let weights = if rgba.pixels().any(|px| px[3] != 255) {
+            rgba.pixels().map(|px| px[3]).collect()
+        } else {
+            image.to_luma8().into_raw()
+        };
+
+        Mask::Image {
+            width: image.width(),
+            height: image.height(),
+            weights,
+        }
+    }
+
+    pub(crate) fn validate(&self) -> Result<()> {
+        match self {
+            Mask::Image {
+                width,
+                height,
+                weights,
+            } => {
+                if *width == 0 || *height == 0 || weights.len() != (*width * *height) as usize {
+                    return Err(Error::InvalidMask(
+                        "weights must cover a non-empty width x height image",
+                    ));
+                }
+            }
+            Mask::Rect { width, height, .. } => {
+                if *width == 0 || *height == 0 {
+                    return Err(Error::InvalidMask("rectangle must not be empty"));
+                }
+            }
+            Mask::Polygon { points } => {
+                if points.len() < 3 {
+                    return Err(Error::InvalidMask("polygon needs at least 3 points"));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Coverage of every pixel of a `width` x `height` frame, between 0 and 1.
+    fn coverage(&self, width: u32, height: u32) -> Vec<f32> {
+        let (w, h) = (width as usize, height as usize);
+
+        match self {
+            Mask::Image {
+                width: mask_width,
+                height: mask_height,
+                weights,
+            } => {
+                let mut mask = GrayImage::from_raw(*mask_width, *mask_height, weights.clone())
+                    .expect("mask dimensions are validated");
+                if (*mask_width, *mask_height) != (width, height) {
+                    mask = imageops::resize(&mask, width, height, imageops::FilterType::Triangle);
+                }
+                mask.into_raw().iter().map(|&v| v as f32 / 255.0).collect()
+            }
+            Mask::Rect {
+                x,
+                y,
+                width: rect_width,
+                height: rect_height,
+            } => {
+                let (x0, y0) = (*x as usize, *y as usize);
+                let (x1, y1) = (x0 + *rect_width as usize, y0 + *rect_height as usize);
+                (0..w * h)
+                    .map(|i| {
+                        let (px, py) = (i % w, i / w);
+                        ((x0..x1).contains(&px) && (y0..y1).contains(&py)) as u8 as f32
+                    })
+                    .collect()
+            }
+            Mask::Polygon { points } => {
+                let mut coverage = vec![0.0; w * h];
+                let mut crossings = Vec::new();
+
+                for (y, row) in coverage.chunks_exact_mut(w).enumerate() {
+                    // Sample at pixel centers
+                    let sy = y as f32 + 0.5;
+                    crossings.clear();
+                    for (i, a) in points.iter().enumerate() {
+                        let b = points[(i + 1) % points.len()];
+                        if (a[1] <= sy) != (b[1] <= sy) {
+                            crossings.push(a[0] + (sy - a[1]) / (b[1] - a[1]) * (b[0] - a[0]));
+                        }
+                    }
+                    crossings.sort_by(|a, b| a.total_cmp(b));
+
+                    for span in crossings.chunks_exact(2) {
+                        let start = (span[0] - 0.5).ceil().clamp(0.0, w as f32) as usize;
+                        let end = (span[1] - 0.5).ceil().clamp(0.0, w as f32) as usize;
+                        row[start..end.max(start)].fill(1.0);
+                    }
+                }
+                coverage
+            }
+        }
+    }
+}
+
+/// Blending weight of every pixel, or `None` when the whole image is mapped.
+pub(crate) fn weights(config: &Config, width: u32, height: u32) -> Option<Vec<f32>> {
+    let mask = config.mask.as_ref()?;
+    if width == 0 || height == 0 {
+        return None;
+    }
+    let mut weights = mask.coverage(width, height);
+
+    if config.mask_invert {
+        weights.iter_mut().for_each(|w| *w = 1.0 - *w);
+    }
+
+    // Three box blurs approximate a gaussian reaching about `mask_feather` pixels
+    if config.mask_feather > 0.0 {
+        let radius = (config.mask_feather / 3.0).round().max(1.0) as usize;
+        for _ in 0..3 {
+            box_blur(&mut weights, width as usize, height as usize, radius);
+        }
+    }
+
+    Some(weights)
+}
+
+fn box_blur(values: &mut [f32], width: usize, height: usize, radius: usize) {
+    for row in values.chunks_exact_mut(width) {
+        let blurred = blur_line(row, radius);
+        row.copy_from_slice(&blurred);
+    }
+    for x in 0..width {
+        let column: Vec<f32> = (0..height).map(|y| values[y * width + x]).collect();
+        for (y, v) in blur_line(&column, radius).into_iter().enumerate() {
+            values[y * width + x] = v;
+        }
+    }
+}
+
+/// Running-sum box blur that repeats the edge values.
+fn blur_line(line: &[f32], radius: usize) -> Vec<f32> {
+    let last = line.len() as isize - 1;
+    let at = |i: isize| line[i.clamp(0, last) as usize];
+    let r = radius as isize;
+    let count = (2 * r + 1) as f32;
+
+    let mut sum: f32 = (-r..=r).map(at).sum();
+    (0..line.len() as isize)
+        .map(|i| {
+            let value = sum / count;
+            sum += at(i + r + 1) - at(i - r);
+            value
+        })
+        .collect()
+}
+
+/// Blends the mapped pixels back over the `original` ones by their weight.
+pub(crate) fn blend(image_data: &mut [u8], original: &[u8], weights: &[f32]) {
+    for ((px, src), &weight) in image_data
+        .chunks_exact_mut(4)
+        .zip(original.chunks_exact(4))
+        .zip(weights)
+    {
+        if weight <= 0.0 {
+            px.copy_from_slice(src);
+        } else if weight < 1.0 {
+            for c in 0..4 {
+                px[c] = (src[c] as f32 + (px[c] as f32 - src[c] as f32) * weight).round() as u8;
+            }
+        }
+    }
+}
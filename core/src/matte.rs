@@ -0,0 +1,41 @@
+use rayon::prelude::*;
+
+use crate::{config::Config, MatteMode};
+
+/// Prepares pixels against `config.matte` before they are mapped. Does nothing unless a matte is
+/// set or opaque output is forced (which falls back to a black matte).
+pub(crate) fn apply(image_data: &mut [u8], config: &Config) {
+    let matte = match (config.matte, config.force_opaque) {
+        (Some(matte), _) => matte,
+        (None, true) => [0, 0, 0],
+        (None, false) => return,
+    };
+
+    image_data.par_chunks_exact_mut(4).for_each(|px| {
+        let alpha = px[3] as u32;
+
+        match config.matte_mode {
+            MatteMode::Composite => {
+                for c in 0..3 {
+                    px[c] = ((px[c] as u32 * alpha + matte[c] as u32 * (255 - alpha) + 127) / 255)
+                        as u8;
+                }
+            }
+            // Already blended over the matte, which is exactly what opaque output shows
+            MatteMode::Unpremultiply if config.force_opaque => {}
+            MatteMode::Unpremultiply => {
+                if alpha > 0 {
+                    for c in 0..3 {
+                        let background = matte[c] as f32 * (255 - alpha) as f32 / 255.0;
+                        let straight = (px[c] as f32 - background) * 255.0 / alpha as f32;
+                        px[c] = straight.round().clamp(0.0, 255.0) as u8;
+                    }
+                }
+            }
+        }
+
+        if config.force_opaque {
+            px[3] = 255;
+        }
+    });
+}
@@ -0,0 +1,305 @@
+use crate::{
+    config::Config,
+    error::{Error, Result},
+    processing,
+    temporal::FrameHistory,
+    Filter, Image, PngOptions,
+};
+
+use image::{
+    codecs::{png::PngDecoder, webp::WebPDecoder},
+    AnimationDecoder, ExtendedColorType, Frame, ImageDecoder,
+};
+
+use std::fs::File;
+use std::io::{BufWriter, Cursor, Write};
+use std::path::{Path, PathBuf};
+
+/// Container of an animation other than GIF.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnimationFormat {
+    /// Animated PNG
+    Png,
+    WebP,
+}
+
+impl AnimationFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            AnimationFormat::Png => "png",
+            AnimationFormat::WebP => "webp",
+        }
+    }
+}
+
+/// An animated PNG or WebP, held as composited full-canvas frames.
+#[derive(Clone)]
+pub struct Animation {
+    pub frames: Vec<Frame>,
+    pub width: u32,
+    pub height: u32,
+    pub format: AnimationFormat,
+    /// Times the animation plays, 0 for forever
+    pub loop_count: u32,
+    /// How APNG frames are compressed. Frames are always RGBA, so `optimize` has no effect.
+    pub png: PngOptions,
+}
+
+/// Format of `bytes` if they hold an animated PNG or WebP, `None` for still images and anything
+/// else.
+pub fn animated_format(bytes: &[u8]) -> Option<AnimationFormat> {
+    if png_chunk(bytes, b"acTL").is_some() {
+        return Some(AnimationFormat::Png);
+    }
+
+    // Extended WebP with the animation flag set
+    let vp8x = riff_chunks(bytes).find(|(id, _)| id == b"VP8X")?.1;
+    (vp8x.first()? & 0x02 != 0).then_some(AnimationFormat::WebP)
+}
+
+/// Data of the first PNG chunk of type `id` ahead of the image data.
+pub(super) fn png_chunk<'a>(bytes: &'a [u8], id: &[u8; 4]) -> Option<&'a [u8]> {
+    if !bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return None;
+    }
+
+    let mut offset = 8;
+    while offset + 8 <= bytes.len() {
+        let length = u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap()) as usize;
+        let kind = &bytes[offset + 4..offset + 8];
+        let data = bytes.get(offset + 8..offset + 8 + length)?;
+        if kind == id {
+            return Some(data);
+        }
+        if kind == b"IDAT" {
+            return None;
+        }
+        // Length, type, data and CRC
+        offset += 12 + length;
+    }
+    None
+}
+
+/// Top level chunks of a RIFF WebP file as (FourCC, data).
+pub(super) fn riff_chunks(bytes: &[u8]) -> impl Iterator<Item = ([u8; 4], &[u8])> {
+    let body = if bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        &bytes[12..]
+    } else {
+        &[]
+    };
+
+    let mut offset = 0;
+    std::iter::from_fn(move || {
+        let header = body.get(offset..offset + 8)?;
+        let id: [u8; 4] = header[..4].try_into().unwrap();
+        let length = u32::from_le_bytes(header[4..].try_into().unwrap()) as usize;
+        let data = body.get(offset + 8..offset + 8 + length)?;
+        // Chunks are padded to an even size
+        offset += 8 + length + (length & 1);
+        Some((id, data))
+    })
+}
+
+pub(super) fn write_riff_chunk(out: &mut Vec<u8>, id: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(id);
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        out.push(0);
+    }
+}
+
+pub(super) fn u24_le(value: u32) -> [u8; 3] {
+    let [a, b, c, _] = value.to_le_bytes();
+    [a, b, c]
+}
+
+impl Animation {
+    pub fn from_memory(bytes: &[u8]) -> Result<Self> {
+        let format = animated_format(bytes).ok_or(Error::UnsupportedFormat)?;
+
+        let (width, height, frames, loop_count) = match format {
+            AnimationFormat::Png => {
+                let decoder = PngDecoder::new(Cursor::new(bytes))?;
+                let (width, height) = decoder.dimensions();
+                let frames = decoder.apng()?.into_frames().collect_frames()?;
+                // acTL holds the frame count followed by the number of plays
+                let loop_count = png_chunk(bytes, b"acTL")
+                    .and_then(|data| data.get(4..8))
+                    .map_or(0, |plays| u32::from_be_bytes(plays.try_into().unwrap()));
+                (width, height, frames, loop_count)
+            }
+            AnimationFormat::WebP => {
+                let decoder = WebPDecoder::new(Cursor::new(bytes))?;
+                let (width, height) = decoder.dimensions();
+                let frames = decoder.into_frames().collect_frames()?;
+                // ANIM holds the background color followed by the loop count
+                let loop_count = riff_chunks(bytes)
+                    .find(|(id, _)| id == b"ANIM")
+                    .and_then(|(_, data)| data.get(4..6))
+                    .map_or(0, |count| u16::from_le_bytes([count[0], count[1]]) as u32);
+                (width, height, frames, loop_count)
+            }
+        };
+
+        Ok(Self {
+            frames,
+            width,
+            height,
+            format,
+            loop_count,
+            png: PngOptions::default(),
+        })
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_memory(&std::fs::read(path)?)
+    }
+
+    pub fn write_to_file<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let extension = self.format.extension();
+
+        if matches!(path.extension(), Some(ext) if ext != extension) {
+            log::debug!(
+                "Output path {} has a non-{extension} extension; replacing with .{extension}",
+                path.display()
+            );
+        }
+
+        let mut path_with_ext = PathBuf::from(path);
+        path_with_ext.set_extension(extension);
+
+        let mut writer = BufWriter::new(File::create(&path_with_ext)?);
+        writer.write_all(&self.write_to_memory()?)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn write_to_memory(&self) -> Result<Vec<u8>> {
+        match self.format {
+            AnimationFormat::Png => self.write_apng(),
+            AnimationFormat::WebP => self.write_webp(),
+        }
+    }
+
+    fn delay_ms(frame: &Frame) -> u32 {
+        let (numer, denom) = frame.delay().numer_denom_ms();
+        numer / denom.max(1)
+    }
+
+    fn write_apng(&self) -> Result<Vec<u8>> {
+        let mut buffer = Vec::new();
+        {
+            let mut encoder = png::Encoder::new(&mut buffer, self.width, self.height);
+            encoder.set_color(png::ColorType::Rgba);
+            encoder.set_depth(png::BitDepth::Eight);
+            self.png.apply(&mut encoder);
+            encoder.set_animated(self.frames.len() as u32, self.loop_count)?;
+            // Frames are whole canvases, so each one replaces the previous outright
+            encoder.set_blend_op(png::BlendOp::Source)?;
+            encoder.set_dispose_op(png::DisposeOp::None)?;
+
+            let mut writer = encoder.write_header()?;
+            for frame in &self.frames {
+                let delay = Self::delay_ms(frame).min(u16::MAX as u32) as u16;
+                writer.set_frame_delay(delay, 1000)?;
+                writer.write_image_data(frame.buffer().as_raw())?;
+            }
+            writer.finish()?;
+        }
+        Ok(buffer)
+    }
+
+    /// Extended WebP with one lossless ANMF chunk per frame.
+    fn write_webp(&self) -> Result<Vec<u8>> {
+        let mut chunks = Vec::new();
+
+        // Animation and alpha flags, then the canvas size minus one
+        let mut vp8x = vec![0x02 | 0x10, 0, 0, 0];
+        vp8x.extend_from_slice(&u24_le(self.width.max(1) - 1));
+        vp8x.extend_from_slice(&u24_le(self.height.max(1) - 1));
+        write_riff_chunk(&mut chunks, b"VP8X", &vp8x);
+
+        // Transparent background, then the loop count
+        let mut anim = vec![0; 4];
+        anim.extend_from_slice(&(self.loop_count.min(u16::MAX as u32) as u16).to_le_bytes());
+        write_riff_chunk(&mut chunks, b"ANIM", &anim);
+
+        for frame in &self.frames {
+            let buffer = frame.buffer();
+            let mut still = Vec::new();
+            image::codecs::webp::WebPEncoder::new_lossless(&mut still).encode(
+                buffer.as_raw(),
+                buffer.width(),
+                buffer.height(),
+                ExtendedColorType::Rgba8,
+            )?;
+            let (_, bitstream) = riff_chunks(&still)
+                .find(|(id, _)| id == b"VP8L")
+                .ok_or_else(|| Error::Internal("WebP encoder wrote no VP8L chunk".into()))?;
+
+            // Position (halved), size minus one, duration, then "don't blend" so transparent
+            // pixels replace the previous frame
+            let mut anmf = Vec::with_capacity(16 + bitstream.len() + 9);
+            anmf.extend_from_slice(&u24_le(0));
+            anmf.extend_from_slice(&u24_le(0));
+            anmf.extend_from_slice(&u24_le(buffer.width() - 1));
+            anmf.extend_from_slice(&u24_le(buffer.height() - 1));
+            anmf.extend_from_slice(&u24_le(Self::delay_ms(frame).min(0xFF_FFFF)));
+            anmf.push(0x02);
+            write_riff_chunk(&mut anmf, b"VP8L", bitstream);
+            write_riff_chunk(&mut chunks, b"ANMF", &anmf);
+        }
+
+        let mut out = Vec::with_capacity(12 + chunks.len());
+        out.extend_from_slice(b"RIFF");
+        out.extend_from_slice(&(4 + chunks.len() as u32).to_le_bytes());
+        out.extend_from_slice(b"WEBP");
+        out.extend_from_slice(&chunks);
+        Ok(out)
+    }
+
+    pub fn resize(
+        &mut self,
+        target_width: Option<u32>,
+        target_height: Option<u32>,
+        scale: Option<f32>,
+        filter: Filter,
+    ) -> Result<()> {
+        for frame in &mut self.frames {
+            let mut image = Image::from_rgba(frame.buffer().clone());
+            image.resize(target_width, target_height, scale, filter)?;
+            self.width = image.width;
+            self.height = image.height;
+            *frame = Frame::from_parts(image.buffer, 0, 0, frame.delay());
+        }
+
+        Ok(())
+    }
+
+    pub async fn palettify(&mut self, config: &Config) -> Result<()> {
+        config.validate()?;
+
+        log::debug!(
+            "Processing animation pixels ({}x{})",
+            self.width,
+            self.height
+        );
+        let mut history = FrameHistory::default();
+        for (index, frame) in self.frames.iter_mut().enumerate() {
+            let (w, h) = (frame.buffer().width(), frame.buffer().height());
+            let frame_config = config.for_frame(index);
+            let source = config
+                .temporal_coherence
+                .then(|| frame.buffer().as_raw().clone());
+            processing::process_pixels(frame.buffer_mut().as_mut(), w, h, &frame_config).await?;
+            if let Some(source) = source {
+                history.stabilize(&source, frame.buffer_mut().as_mut(), config);
+            }
+        }
+        log::debug!("Pixel processing complete.");
+
+        Ok(())
+    }
+}
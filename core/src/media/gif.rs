@@ -1,14 +1,14 @@
 use crate::{
     config::Config,
     error::{Error, Result},
-    processing, Filter, Image,
+    processing,
+    temporal::FrameHistory,
+    Filter, Image, Mapping,
 };
 
-use image::{
-    codecs::gif::{GifEncoder, Repeat},
-    AnimationDecoder, Frame, ImageDecoder,
-};
+use image::{codecs::gif::Repeat, AnimationDecoder, Frame, ImageDecoder, Rgb};
 
+use std::collections::{HashMap, HashSet};
 use std::path::Path;
 use std::{fs::File, io::SeekFrom};
 use std::{
@@ -21,45 +21,243 @@ pub struct Gif {
     pub frames: Vec<Frame>,
     pub width: u32,
     pub height: u32,
+    /// Repeats after the first play, as in the NETSCAPE2.0 block. `None` and `Finite(0)` play
+    /// once and write no block.
     pub repeat: Option<Repeat>,
     pub speed: u16,
+    /// Colors of the global color table once palettified, with the transparent placeholder last.
+    /// `None` writes every frame with its own quantized local table.
+    pub palette: Option<Vec<Rgb<u8>>>,
+    pub optimization: GifOptimization,
+    /// Source layout of every frame, empty when the frames don't come from a GIF file
+    pub layouts: Vec<FrameLayout>,
+    /// Extension blocks stored after the last frame
+    pub extensions: Vec<ExtensionBlock>,
+}
+
+/// Where and how a frame was stored in the source file. `frames` only hold the composited
+/// canvas, which is cropped back to this layout when writing without delta frames.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameLayout {
+    pub left: u32,
+    pub top: u32,
+    pub width: u32,
+    pub height: u32,
+    pub dispose: gif::DisposalMethod,
+    /// Extension blocks stored right before the frame, e.g. comments or application data
+    pub extensions: Vec<ExtensionBlock>,
+}
+
+/// An extension block carried through unchanged.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtensionBlock {
+    pub label: u8,
+    /// Data sub-blocks, each at most 255 bytes
+    pub blocks: Vec<Vec<u8>>,
+}
+
+impl ExtensionBlock {
+    fn write<W: std::io::Write>(&self, encoder: &mut gif::Encoder<W>) -> Result<()> {
+        let blocks: Vec<&[u8]> = self.blocks.iter().map(Vec::as_slice).collect();
+        encoder.write_raw_extension(gif::AnyExtension(self.label), &blocks)?;
+        Ok(())
+    }
+}
+
+/// What the block structure of a GIF file tells beyond its pixels.
+struct Metadata {
+    repeat: Option<Repeat>,
+    speed: u16,
+    layouts: Vec<FrameLayout>,
+    extensions: Vec<ExtensionBlock>,
+}
+
+/// How frames are reduced before they are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GifOptimization {
+    /// Crop every frame to what changed since the previous one, leaving unchanged pixels
+    /// transparent so the previous frame shows through. Otherwise frames keep their source layout.
+    pub delta_frames: bool,
+    /// Largest per-channel difference still counted as unchanged. 0 keeps every pixel exact,
+    /// higher values trade quality for size.
+    pub lossy: u8,
+    /// Merge identical consecutive frames into one, summing their delays. Without delta frames
+    /// only frames with the same layout are merged.
+    pub merge_frames: bool,
+}
+
+impl Default for GifOptimization {
+    fn default() -> Self {
+        Self {
+            delta_frames: true,
+            lossy: 0,
+            merge_frames: true,
+        }
+    }
+}
+
+/// Part of the canvas, in pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Rect {
+    left: usize,
+    top: usize,
+    width: usize,
+    height: usize,
+}
+
+impl Rect {
+    /// Smallest rectangle holding every pixel of a `width` x `height` canvas whose index matches
+    /// `include`.
+    fn bounding(width: usize, height: usize, include: impl Fn(usize) -> bool) -> Option<Rect> {
+        let (mut left, mut top, mut right, mut bottom) = (usize::MAX, usize::MAX, 0, 0);
+        for y in 0..height {
+            for x in 0..width {
+                if include(y * width + x) {
+                    left = left.min(x);
+                    top = top.min(y);
+                    right = right.max(x + 1);
+                    bottom = y + 1;
+                }
+            }
+        }
+
+        (left != usize::MAX).then(|| Rect {
+            left,
+            top,
+            width: right - left,
+            height: bottom - top,
+        })
+    }
+
+    fn union(self, other: Rect) -> Rect {
+        let (left, top) = (self.left.min(other.left), self.top.min(other.top));
+        let right = (self.left + self.width).max(other.left + other.width);
+        let bottom = (self.top + self.height).max(other.top + other.height);
+        Rect {
+            left,
+            top,
+            width: right - left,
+            height: bottom - top,
+        }
+    }
+
+    fn clear(self, pixels: &mut [u8], width: usize) {
+        for y in self.top..self.top + self.height {
+            let start = (y * width + self.left) * 4;
+            pixels[start..start + self.width * 4].fill(0);
+        }
+    }
+}
+
+/// A frame as written: RGBA pixels drawn over a rectangle of what the previous frames left.
+struct Patch {
+    rect: Rect,
+    pixels: Vec<u8>,
+    delay: u16,
+    dispose: gif::DisposalMethod,
+    /// Source frames shown by the patch, whose extension blocks are written before it
+    sources: Vec<usize>,
+}
+
+impl Patch {
+    fn new(rect: Rect, pixels: Vec<u8>, delay: u16, source: usize) -> Self {
+        Patch {
+            rect,
+            pixels,
+            delay,
+            dispose: gif::DisposalMethod::Keep,
+            sources: vec![source],
+        }
+    }
+
+    /// Extends the patch to `rect` with transparent pixels.
+    fn grow(&mut self, rect: Rect) {
+        let old = self.rect;
+        let mut pixels = vec![0; rect.width * rect.height * 4];
+        for (y, row) in self.pixels.chunks_exact(old.width * 4).enumerate() {
+            let start = ((old.top - rect.top + y) * rect.width + old.left - rect.left) * 4;
+            pixels[start..start + row.len()].copy_from_slice(row);
+        }
+        self.rect = rect;
+        self.pixels = pixels;
+    }
+}
+
+/// Whether two RGBA pixels look the same, within `tolerance` per channel when both are visible.
+fn same(a: &[u8], b: &[u8], tolerance: u8) -> bool {
+    match (a[3] == 0, b[3] == 0) {
+        (true, true) => true,
+        (false, false) => (0..3).all(|c| a[c].abs_diff(b[c]) <= tolerance),
+        _ => false,
+    }
 }
 
 impl Gif {
     pub fn from_memory(gif_bytes: &[u8]) -> Result<Self> {
         let mut cursor = Cursor::new(gif_bytes);
-        let (repeat, speed) = Self::extract_metadata(&mut cursor)?;
+        let metadata = Self::extract_metadata(&mut cursor)?;
         cursor.seek(SeekFrom::Start(0))?;
 
         let decoder = image::codecs::gif::GifDecoder::new(cursor)?;
         let (width, height) = decoder.dimensions();
         let frames = decoder.into_frames().collect_frames()?;
 
-        Ok(Self {
-            frames,
-            width,
-            height,
-            repeat,
-            speed,
-        })
+        Ok(Self::from_parts(frames, width, height, metadata))
     }
 
     pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
         let mut file = File::open(&path)?;
-        let (repeat, speed) = Self::extract_metadata(&mut file)?;
+        let metadata = Self::extract_metadata(&mut file)?;
         file.seek(SeekFrom::Start(0))?;
 
         let decoder = image::codecs::gif::GifDecoder::new(BufReader::new(file))?;
         let (width, height) = decoder.dimensions();
         let frames = decoder.into_frames().collect_frames()?;
 
-        Ok(Self {
+        Ok(Self::from_parts(frames, width, height, metadata))
+    }
+
+    /// GIF of composited full-canvas frames, played `loop_count` times (0 for forever).
+    pub fn from_frames(frames: Vec<Frame>, width: u32, height: u32, loop_count: u32) -> Self {
+        let repeat = match loop_count {
+            0 => Repeat::Infinite,
+            plays => Repeat::Finite((plays - 1).min(u16::MAX as u32) as u16),
+        };
+        Self::from_parts(
             frames,
             width,
             height,
-            repeat,
-            speed,
-        })
+            Metadata {
+                repeat: Some(repeat),
+                speed: 10,
+                layouts: Vec::new(),
+                extensions: Vec::new(),
+            },
+        )
+    }
+
+    fn from_parts(frames: Vec<Frame>, width: u32, height: u32, metadata: Metadata) -> Self {
+        let mut layouts = metadata.layouts;
+        if !layouts.is_empty() && layouts.len() != frames.len() {
+            log::debug!(
+                "Found {} frame layouts for {} frames; writing full frames instead.",
+                layouts.len(),
+                frames.len()
+            );
+            layouts.clear();
+        }
+
+        Self {
+            frames,
+            width,
+            height,
+            repeat: metadata.repeat,
+            speed: metadata.speed,
+            palette: None,
+            optimization: GifOptimization::default(),
+            layouts,
+            extensions: metadata.extensions,
+        }
     }
 
     pub fn write_to_file<P: AsRef<Path>>(&self, path: P) -> Result<()> {
@@ -90,19 +288,328 @@ impl Gif {
     }
 
     fn write_to_writer<W: std::io::Write>(&self, writer: W) -> Result<()> {
-        let mut encoder = GifEncoder::new_with_speed(writer, self.speed.into());
+        let table = self.palette.as_ref().and_then(|palette| {
+            let table = self.color_table(palette);
+            if table.is_none() {
+                log::warn!(
+                    "Too many colors for a global color table; quantizing each frame instead."
+                );
+            }
+            table
+        });
 
-        // Use the repeat setting from the original GIF, defaulting to Infinite if not specified
-        if let Some(repeat) = self.repeat {
-            encoder.set_repeat(repeat)?;
+        let patches = if self.optimization.delta_frames {
+            self.delta_patches()
         } else {
-            encoder.set_repeat(Repeat::Infinite)?;
+            self.source_patches()
+        };
+        log::debug!(
+            "Writing {} GIF frames for {} source frames.",
+            patches.len(),
+            self.frames.len()
+        );
+
+        let global_palette: Vec<u8> = table.iter().flatten().flatten().copied().collect();
+        let mut encoder = gif::Encoder::new(
+            writer,
+            self.width as u16,
+            self.height as u16,
+            &global_palette,
+        )?;
+        // Use the repeat setting from the original GIF. A loop count of 0 means forever, so a
+        // single play leaves the block out.
+        match self.repeat {
+            Some(Repeat::Finite(0)) | None => {}
+            Some(Repeat::Finite(count)) => encoder.set_repeat(gif::Repeat::Finite(count))?,
+            Some(Repeat::Infinite) => encoder.set_repeat(gif::Repeat::Infinite)?,
+        }
+
+        match table {
+            Some(table) => self.write_indexed(&mut encoder, patches, &table)?,
+            None => {
+                for mut patch in patches {
+                    self.write_extensions(&mut encoder, &patch.sources)?;
+                    let mut frame = gif::Frame::from_rgba_speed(
+                        patch.rect.width as u16,
+                        patch.rect.height as u16,
+                        &mut patch.pixels,
+                        self.speed.into(),
+                    );
+                    frame.delay = patch.delay;
+                    frame.dispose = patch.dispose;
+                    frame.left = patch.rect.left as u16;
+                    frame.top = patch.rect.top as u16;
+                    encoder.write_frame(&frame)?;
+                }
+            }
+        }
+
+        for extension in &self.extensions {
+            extension.write(&mut encoder)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the extension blocks stored before the given source frames.
+    fn write_extensions<W: std::io::Write>(
+        &self,
+        encoder: &mut gif::Encoder<W>,
+        sources: &[usize],
+    ) -> Result<()> {
+        for layout in sources.iter().filter_map(|&i| self.layouts.get(i)) {
+            for extension in &layout.extensions {
+                extension.write(encoder)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Global color table entries covering every visible frame color: the palette first, then any
+    /// colors that bypassed it (locked colors, kept lightness). The transparent placeholder comes
+    /// last. `None` if they don't fit in 256 entries.
+    fn color_table(&self, palette: &[Rgb<u8>]) -> Option<Vec<[u8; 3]>> {
+        let (transparent_color, colors) = palette.split_last()?;
+
+        let mut entries: Vec<[u8; 3]> = colors.iter().map(|color| color.0).collect();
+        let mut known: HashSet<[u8; 3]> = entries.iter().copied().collect();
+        for frame in &self.frames {
+            for pixel in frame.buffer().pixels() {
+                let rgb = [pixel[0], pixel[1], pixel[2]];
+                if pixel[3] != 0 && known.insert(rgb) {
+                    entries.push(rgb);
+                }
+            }
+        }
+
+        if entries.len() >= 256 {
+            return None;
+        }
+        entries.push(transparent_color.0);
+        Some(entries)
+    }
+
+    /// Writes every patch as indices into the global color table, so the output colors are
+    /// exactly the table's.
+    fn write_indexed<W: std::io::Write>(
+        &self,
+        encoder: &mut gif::Encoder<W>,
+        patches: Vec<Patch>,
+        table: &[[u8; 3]],
+    ) -> Result<()> {
+        log::debug!(
+            "Writing indexed GIF with a {} color global table.",
+            table.len()
+        );
+
+        let transparent_index = (table.len() - 1) as u8;
+        let color_to_index: HashMap<[u8; 3], u8> = table[..table.len() - 1]
+            .iter()
+            .enumerate()
+            .map(|(i, color)| (*color, i as u8))
+            .collect();
+
+        for patch in patches {
+            self.write_extensions(encoder, &patch.sources)?;
+            let indices: Vec<u8> = patch
+                .pixels
+                .chunks_exact(4)
+                .map(|pixel| {
+                    if pixel[3] == 0 {
+                        transparent_index
+                    } else {
+                        color_to_index[&[pixel[0], pixel[1], pixel[2]]]
+                    }
+                })
+                .collect();
+
+            encoder.write_frame(&gif::Frame {
+                delay: patch.delay,
+                dispose: patch.dispose,
+                transparent: Some(transparent_index),
+                left: patch.rect.left as u16,
+                top: patch.rect.top as u16,
+                width: patch.rect.width as u16,
+                height: patch.rect.height as u16,
+                buffer: indices.into(),
+                ..gif::Frame::default()
+            })?;
         }
 
-        encoder.encode_frames(self.frames.clone())?;
         Ok(())
     }
 
+    /// Frame `index` laid out over the whole canvas as RGBA.
+    fn canvas(&self, index: usize) -> Vec<u8> {
+        let frame = &self.frames[index];
+        let buffer = frame.buffer();
+        if (frame.left(), frame.top(), buffer.width(), buffer.height())
+            == (0, 0, self.width, self.height)
+        {
+            return buffer.as_raw().clone();
+        }
+
+        let mut canvas = vec![0; (self.width * self.height * 4) as usize];
+        for (x, y, pixel) in buffer.enumerate_pixels() {
+            let (cx, cy) = (x + frame.left(), y + frame.top());
+            if cx < self.width && cy < self.height {
+                let i = ((cy * self.width + cx) * 4) as usize;
+                canvas[i..i + 4].copy_from_slice(&pixel.0);
+            }
+        }
+        canvas
+    }
+
+    /// Frames in their source layout, cropped from the composited frames. Without a source
+    /// layout every frame covers the whole canvas. With `merge_frames`, a frame showing the same
+    /// picture in the same rectangle as the previous one only extends its delay.
+    fn source_patches(&self) -> Vec<Patch> {
+        let (width, height) = (self.width as usize, self.height as usize);
+        let mut patches: Vec<Patch> = Vec::with_capacity(self.frames.len());
+        let mut previous_canvas = None;
+
+        for index in 0..self.frames.len() {
+            let canvas = self.canvas(index);
+            let delay = self.get_frame_delay(index);
+            let patch = match self.layouts.get(index) {
+                None => {
+                    let full = Rect {
+                        left: 0,
+                        top: 0,
+                        width,
+                        height,
+                    };
+                    // Cleared after showing so transparent pixels stay transparent
+                    let mut patch = Patch::new(full, canvas.clone(), delay, index);
+                    patch.dispose = gif::DisposalMethod::Background;
+                    patch
+                }
+                Some(layout) => {
+                    // Clamped to the canvas, which some files draw past
+                    let left = (layout.left as usize).min(width.saturating_sub(1));
+                    let top = (layout.top as usize).min(height.saturating_sub(1));
+                    let rect = Rect {
+                        left,
+                        top,
+                        width: (layout.width as usize).clamp(1, width - left),
+                        height: (layout.height as usize).clamp(1, height - top),
+                    };
+                    let pixels = (rect.top..rect.top + rect.height)
+                        .flat_map(|y| {
+                            let start = (y * width + rect.left) * 4;
+                            &canvas[start..start + rect.width * 4]
+                        })
+                        .copied()
+                        .collect();
+
+                    let mut patch = Patch::new(rect, pixels, delay, index);
+                    patch.dispose = layout.dispose;
+                    patch
+                }
+            };
+
+            // Whatever the earlier disposal left, drawing the duplicate shows the same picture,
+            // so only restoring to what was there before it would tell them apart
+            if let Some(last) = patches.last_mut() {
+                let duplicate = self.optimization.merge_frames
+                    && previous_canvas.as_ref() == Some(&canvas)
+                    && last.rect == patch.rect
+                    && (patch.dispose != gif::DisposalMethod::Previous
+                        || last.dispose == gif::DisposalMethod::Previous);
+                if duplicate {
+                    last.delay = last.delay.saturating_add(delay);
+                    last.dispose = patch.dispose;
+                    last.sources.push(index);
+                    continue;
+                }
+            }
+
+            previous_canvas = Some(canvas);
+            patches.push(patch);
+        }
+
+        patches
+    }
+
+    /// Frames cropped to what changed since the previous one, reduced according to
+    /// `optimization`.
+    fn delta_patches(&self) -> Vec<Patch> {
+        let (width, height) = (self.width as usize, self.height as usize);
+        let optimization = self.optimization;
+
+        let mut patches: Vec<Patch> = Vec::with_capacity(self.frames.len());
+        // What a viewer shows before the next patch is drawn
+        let mut shown = vec![0u8; width * height * 4];
+
+        for index in 0..self.frames.len() {
+            let target = self.canvas(index);
+            let delay = self.get_frame_delay(index);
+
+            // Pixels turning transparent can only be cleared by disposing the previous patch
+            // to the background, over a rectangle grown to cover them
+            let cleared = Rect::bounding(width, height, |i| {
+                shown[i * 4 + 3] != 0 && target[i * 4 + 3] == 0
+            });
+            if let (Some(cleared), Some(last)) = (cleared, patches.last_mut()) {
+                last.grow(cleared.union(last.rect));
+                last.dispose = gif::DisposalMethod::Background;
+                last.rect.clear(&mut shown, width);
+            }
+
+            let changed = |i: usize| {
+                !same(
+                    &shown[i * 4..i * 4 + 4],
+                    &target[i * 4..i * 4 + 4],
+                    optimization.lossy,
+                )
+            };
+            let Some(rect) = Rect::bounding(width, height, changed) else {
+                if optimization.merge_frames && cleared.is_none() && !patches.is_empty() {
+                    let last = patches.last_mut().unwrap();
+                    last.delay = last.delay.saturating_add(delay);
+                    last.sources.push(index);
+                } else {
+                    // Nothing to draw, but the frame still takes its time
+                    patches.push(Patch::new(
+                        Rect {
+                            left: 0,
+                            top: 0,
+                            width: 1,
+                            height: 1,
+                        },
+                        vec![0; 4],
+                        delay,
+                        index,
+                    ));
+                }
+                continue;
+            };
+
+            let mut pixels = Vec::with_capacity(rect.width * rect.height * 4);
+            for y in rect.top..rect.top + rect.height {
+                for x in rect.left..rect.left + rect.width {
+                    let i = y * width + x;
+                    if changed(i) {
+                        pixels.extend_from_slice(&target[i * 4..i * 4 + 4]);
+                    } else {
+                        // Unchanged pixels let the previous frame show through
+                        pixels.extend_from_slice(&[0; 4]);
+                    }
+                }
+            }
+            for (y, row) in pixels.chunks_exact(rect.width * 4).enumerate() {
+                for (x, pixel) in row.chunks_exact(4).enumerate() {
+                    if pixel[3] != 0 {
+                        let i = ((rect.top + y) * width + rect.left + x) * 4;
+                        shown[i..i + 4].copy_from_slice(pixel);
+                    }
+                }
+            }
+            patches.push(Patch::new(rect, pixels, delay, index));
+        }
+
+        patches
+    }
+
     pub fn resize(
         &mut self,
         target_width: Option<u32>,
@@ -141,14 +648,23 @@ impl Gif {
 
         // Otherwise, resize all frames
         for frame in &mut self.frames {
-            let mut image = Image {
-                buffer: frame.buffer().clone(),
-                width: frame.buffer().width(),
-                height: frame.buffer().height(),
-                palette: None,
-            };
+            let mut image = Image::from_rgba(frame.buffer().clone());
             image.resize(target_width, target_height, scale, filter)?;
-            *frame = Frame::from_parts(image.buffer, frame.left(), frame.top(), frame.delay());
+            *frame = Frame::from_parts(image.buffer, 0, 0, frame.delay());
+        }
+
+        // Grow source rectangles outwards to whole pixels so they still cover every change
+        let scale_x = final_width as f64 / self.width as f64;
+        let scale_y = final_height as f64 / self.height as f64;
+        for layout in &mut self.layouts {
+            let left = (layout.left as f64 * scale_x).floor() as u32;
+            let top = (layout.top as f64 * scale_y).floor() as u32;
+            let right = ((layout.left + layout.width) as f64 * scale_x).ceil() as u32;
+            let bottom = ((layout.top + layout.height) as f64 * scale_y).ceil() as u32;
+            layout.left = left;
+            layout.top = top;
+            layout.width = (right - left).max(1);
+            layout.height = (bottom - top).max(1);
         }
 
         self.width = final_width;
@@ -157,7 +673,7 @@ impl Gif {
         Ok(())
     }
 
-    fn extract_metadata<R: Read + Seek>(reader: &mut R) -> Result<(Option<Repeat>, u16)> {
+    fn extract_metadata<R: Read + Seek>(reader: &mut R) -> Result<Metadata> {
         // GIF header is 6 bytes ("GIF87a" or "GIF89a")
         let mut header = [0u8; 6];
         reader.read_exact(&mut header)?;
@@ -166,115 +682,110 @@ impl Gif {
             return Err(Error::InvalidGifFile);
         }
 
-        // Skip logical screen descriptor (width, height, etc.) - 7 bytes
-        reader.seek(SeekFrom::Current(7))?;
+        let mut metadata = Metadata {
+            // Without a NETSCAPE2.0 block, a GIF plays once
+            repeat: Some(Repeat::Finite(0)),
+            speed: 10, // Default speed
+            layouts: Vec::new(),
+            extensions: Vec::new(),
+        };
+        // The decoder reports broken files, so keep whatever could be read before the damage
+        if let Err(err) = Self::read_blocks(reader, &mut metadata) {
+            log::debug!("Stopped reading GIF metadata: {err}");
+        }
+
+        // Reset the reader position
+        reader.seek(SeekFrom::Start(0))?;
 
-        let mut repeat = None;
-        let mut speed = 10; // Default speed
+        Ok(metadata)
+    }
 
-        // Read blocks to find application extension for NETSCAPE2.0 (loop info)
+    /// Walks the blocks after the header, collecting loop info, frame layouts and the extension
+    /// blocks around frames.
+    fn read_blocks<R: Read + Seek>(reader: &mut R, metadata: &mut Metadata) -> Result<()> {
+        // Logical screen descriptor - 7 bytes, followed by the global color table if there is one
+        let mut screen = [0u8; 7];
+        reader.read_exact(&mut screen)?;
+        skip_color_table(reader, screen[4])?;
+
+        let mut dispose = gif::DisposalMethod::Any;
+        let mut extensions = Vec::new();
         let mut block_type = [0u8; 1];
         while reader.read_exact(&mut block_type).is_ok() {
             match block_type[0] {
                 0x21 => {
                     // Extension Introducer
-                    let mut ext_label = [0u8; 1];
-                    reader.read_exact(&mut ext_label)?;
-
-                    match ext_label[0] {
-                        0xFF => {
-                            // Application Extension
-                            let mut block_size = [0u8; 1];
-                            reader.read_exact(&mut block_size)?;
-
-                            if block_size[0] == 11 {
-                                // NETSCAPE2.0 block size
-                                let mut app_id = [0u8; 11];
-                                reader.read_exact(&mut app_id)?;
-
-                                if &app_id[0..11] == b"NETSCAPE2.0" {
-                                    let mut sub_block_size = [0u8; 1];
-                                    reader.read_exact(&mut sub_block_size)?;
-
-                                    if sub_block_size[0] == 3 {
-                                        let mut data = [0u8; 3];
-                                        reader.read_exact(&mut data)?;
-
-                                        if data[0] == 1 {
-                                            let loop_count = u16::from_le_bytes([data[1], data[2]]);
-                                            repeat = if loop_count == 0 {
-                                                Some(Repeat::Infinite)
-                                            } else {
-                                                Some(Repeat::Finite(loop_count))
-                                            };
-                                        }
-                                    }
-                                }
-                            } else {
-                                // Skip this block
-                                reader.seek(SeekFrom::Current(block_size[0] as i64))?;
+                    let mut label = [0u8; 1];
+                    reader.read_exact(&mut label)?;
+                    let blocks = read_sub_blocks(reader)?;
+
+                    match (label[0], blocks.first()) {
+                        (0xF9, Some(data)) if data.len() >= 4 => {
+                            // Graphic Control Extension, applying to the next frame
+                            dispose = gif::DisposalMethod::from_u8((data[0] >> 2) & 0b111)
+                                .unwrap_or(gif::DisposalMethod::Any);
+
+                            // Extract delay time in 1/100 seconds
+                            let delay = u16::from_le_bytes([data[1], data[2]]);
+                            if delay > 0 && metadata.speed == 10 {
+                                // Only set speed if not already set and valid
+                                // Mapping delay to speed (10 is default speed)
+                                // Lower delays need higher speed values
+                                metadata.speed = if delay < 5 {
+                                    30
+                                } else if delay < 10 {
+                                    20
+                                } else {
+                                    10
+                                };
                             }
                         }
-                        0xF9 => {
-                            // Graphic Control Extension - might contain speed info
-                            let mut block_size = [0u8; 1];
-                            reader.read_exact(&mut block_size)?;
-
-                            if block_size[0] == 4 {
-                                let mut data = [0u8; 4];
-                                reader.read_exact(&mut data)?;
-
-                                // Extract delay time in 1/100 seconds
-                                let delay = u16::from_le_bytes([data[1], data[2]]);
-                                if delay > 0 && speed == 10 {
-                                    // Only set speed if not already set and valid
-                                    // Mapping delay to speed (10 is default speed)
-                                    // Lower delays need higher speed values
-                                    speed = if delay < 5 {
-                                        30
-                                    } else if delay < 10 {
-                                        20
-                                    } else {
-                                        10
-                                    };
-                                }
-                            } else {
-                                // Skip this block
-                                reader.seek(SeekFrom::Current(block_size[0] as i64))?;
+                        (0xFF, Some(app_id)) if app_id.as_slice() == b"NETSCAPE2.0" => {
+                            // Loop info, written again from `repeat`
+                            if let Some(data) = blocks.get(1).filter(|d| d.len() >= 3 && d[0] == 1)
+                            {
+                                let loop_count = u16::from_le_bytes([data[1], data[2]]);
+                                metadata.repeat = if loop_count == 0 {
+                                    Some(Repeat::Infinite)
+                                } else {
+                                    Some(Repeat::Finite(loop_count))
+                                };
                             }
                         }
-                        _ => {
-                            // Skip unknown extension
-                            let mut block_size = [0u8; 1];
-                            reader.read_exact(&mut block_size)?;
-                            reader.seek(SeekFrom::Current(block_size[0] as i64))?;
-                        }
-                    }
-
-                    // Skip sub-blocks
-                    let mut sub_block_size = [0u8; 1];
-                    reader.read_exact(&mut sub_block_size)?;
-                    while sub_block_size[0] != 0 {
-                        reader.seek(SeekFrom::Current(sub_block_size[0] as i64))?;
-                        reader.read_exact(&mut sub_block_size)?;
+                        _ => extensions.push(ExtensionBlock {
+                            label: label[0],
+                            blocks,
+                        }),
                     }
                 }
                 0x2C => {
                     // Image Descriptor
-                    // Found an image descriptor, we've read enough metadata
-                    break;
+                    let mut descriptor = [0u8; 9];
+                    reader.read_exact(&mut descriptor)?;
+                    let field = |i: usize| u16::from_le_bytes([descriptor[i], descriptor[i + 1]]);
+                    metadata.layouts.push(FrameLayout {
+                        left: field(0) as u32,
+                        top: field(2) as u32,
+                        width: field(4) as u32,
+                        height: field(6) as u32,
+                        dispose: std::mem::replace(&mut dispose, gif::DisposalMethod::Any),
+                        extensions: std::mem::take(&mut extensions),
+                    });
+
+                    // Skip the local color table, LZW code size and image data
+                    skip_color_table(reader, descriptor[8])?;
+                    reader.seek(SeekFrom::Current(1))?;
+                    read_sub_blocks(reader)?;
                 }
                 _ => {
-                    // Unknown block, stop parsing to avoid confusion
+                    // Trailer, or an unknown block we can't safely read past
                     break;
                 }
             }
         }
 
-        // Reset the reader position
-        reader.seek(SeekFrom::Start(0))?;
-
-        Ok((repeat, speed))
+        metadata.extensions = extensions;
+        Ok(())
     }
 
     pub fn get_frame_delay(&self, frame_idx: usize) -> u16 {
@@ -292,12 +803,55 @@ impl Gif {
         config.validate()?;
 
         log::debug!("Processing gif pixels ({}x{})", self.width, self.height);
-        for frame in &mut self.frames {
+        let mut history = FrameHistory::default();
+        for (index, frame) in self.frames.iter_mut().enumerate() {
             let (w, h) = (frame.buffer().width(), frame.buffer().height());
-            processing::process_pixels(frame.buffer_mut().as_mut(), w, h, config).await?;
+            let frame_config = config.for_frame(index);
+            let source = config
+                .temporal_coherence
+                .then(|| frame.buffer().as_raw().clone());
+            processing::process_pixels(frame.buffer_mut().as_mut(), w, h, &frame_config).await?;
+            if let Some(source) = source {
+                history.stabilize(&source, frame.buffer_mut().as_mut(), config);
+            }
         }
         log::debug!("Pixel processing complete.");
 
+        // Interpolated gradients blend between palette colors like smoothed output does
+        let exact = match config.mapping {
+            Mapping::Smoothed => false,
+            Mapping::GradientMap => !config.gradient_interpolate,
+            Mapping::Palettized => true,
+        };
+        if exact {
+            let mut palette = config.palette.colors.clone();
+            palette.push(Rgb([0, 0, 0]));
+            self.palette = Some(palette);
+        }
+
         Ok(())
     }
 }
+
+/// Skips the color table announced by the packed fields of a screen or image descriptor.
+fn skip_color_table<R: Read + Seek>(reader: &mut R, packed: u8) -> Result<()> {
+    if packed & 0x80 != 0 {
+        reader.seek(SeekFrom::Current(3 * (2i64 << (packed & 0b111))))?;
+    }
+    Ok(())
+}
+
+/// Reads data sub-blocks up to the block terminator.
+fn read_sub_blocks<R: Read>(reader: &mut R) -> Result<Vec<Vec<u8>>> {
+    let mut blocks = Vec::new();
+    let mut size = [0u8; 1];
+    loop {
+        reader.read_exact(&mut size)?;
+        if size[0] == 0 {
+            return Ok(blocks);
+        }
+        let mut block = vec![0u8; size[0] as usize];
+        reader.read_exact(&mut block)?;
+        blocks.push(block);
+    }
+}
@@ -1,24 +1,137 @@
 use crate::{
     config::Config,
     error::{Error, Result},
-    processing, Filter,
+    processing, Filter, Mapping,
 };
 use ico::{IconDir, IconDirEntry, IconImage, ResourceType};
 
-use image::RgbaImage;
+use image::{imageops, Rgb, RgbaImage};
 
-use std::io::Cursor;
+use std::collections::HashMap;
+use std::io::{Cursor, Write};
 use std::path::Path;
 use std::{fs::File, path::PathBuf};
 
+/// Largest entry the ICO and CUR formats can hold.
+pub(super) const MAX_ICON_SIZE: u32 = 256;
+
+/// Sizes of a standard icon set, from small shell icons up to the largest entry.
+pub const STANDARD_ICON_SIZES: [u32; 7] = [16, 24, 32, 48, 64, 128, 256];
+
+/// How icon entries are stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum, strum_macros::Display))]
+pub enum IcoEncoding {
+    /// Indexed BMP for palettized entries below 256 pixels, otherwise whichever suits the entry
+    #[default]
+    Auto,
+    /// BMP for every entry, indexed when palettized, for classic icon consumers
+    Bmp,
+    /// PNG for every entry
+    Png,
+}
+
 #[derive(Clone)]
 pub struct Ico {
     pub buffers: Vec<RgbaImage>,
     pub widths: Vec<u32>,
     pub heights: Vec<u32>,
+    /// Hotspot of each entry; set for cursors, which are written as CUR files
+    pub hotspots: Option<Vec<(u16, u16)>>,
+    /// Colors of an exact mapping, shared by every entry. The last entry is the transparent
+    /// placeholder.
+    pub palette: Option<Vec<Rgb<u8>>>,
+    pub encoding: IcoEncoding,
+}
+
+/// An encoded entry, ready to be placed after the icon directory.
+struct Entry {
+    num_colors: u8,
+    planes: u16,
+    bits_per_pixel: u16,
+    data: Vec<u8>,
 }
 
 impl Ico {
+    /// An icon with one entry per buffer.
+    pub fn from_buffers(buffers: Vec<RgbaImage>) -> Self {
+        Self {
+            widths: buffers.iter().map(RgbaImage::width).collect(),
+            heights: buffers.iter().map(RgbaImage::height).collect(),
+            buffers,
+            hotspots: None,
+            palette: None,
+            encoding: IcoEncoding::default(),
+        }
+    }
+
+    /// Builds an icon set with a square entry for each of `sizes`, every one scaled from
+    /// `source` rather than from another entry. Sources that aren't square are centered on a
+    /// transparent background.
+    pub fn icon_set(source: &RgbaImage, sizes: &[u32], filter: Filter) -> Result<Self> {
+        let (width, height) = source.dimensions();
+        let mut buffers = Vec::with_capacity(sizes.len());
+
+        for &size in sizes {
+            if size == 0 || size > MAX_ICON_SIZE {
+                return Err(Error::InvalidIconSize {
+                    size,
+                    max: MAX_ICON_SIZE,
+                });
+            }
+
+            let scale = size as f32 / width.max(height) as f32;
+            let fit_width = ((width as f32 * scale).round() as u32).clamp(1, size);
+            let fit_height = ((height as f32 * scale).round() as u32).clamp(1, size);
+            log::debug!("Scaling {width}x{height} to a {size}x{size} icon entry");
+
+            let scaled = imageops::resize(source, fit_width, fit_height, filter.into());
+            let mut canvas = RgbaImage::new(size, size);
+            imageops::replace(
+                &mut canvas,
+                &scaled,
+                ((size - fit_width) / 2) as i64,
+                ((size - fit_height) / 2) as i64,
+            );
+            buffers.push(canvas);
+        }
+
+        Ok(Self::from_buffers(buffers))
+    }
+
+    /// Replaces the entries with an icon set scaled from the largest one. Cursors keep their
+    /// hotspot at the same spot of the source.
+    pub fn regenerate(&mut self, sizes: &[u32], filter: Filter) -> Result<()> {
+        let Some(largest) = self.largest_index() else {
+            return Ok(());
+        };
+        let source = &self.buffers[largest];
+        let hotspot = self.hotspots.as_ref().map(|hotspots| {
+            // Where the hotspot lands in the largest square entry
+            let size = sizes.iter().copied().max().unwrap_or(1);
+            let (width, height) = source.dimensions();
+            let scale = size as f32 / width.max(height) as f32;
+            let fit_width = ((width as f32 * scale).round() as u32).clamp(1, size);
+            let fit_height = ((height as f32 * scale).round() as u32).clamp(1, size);
+            let (x, y) = hotspots.get(largest).copied().unwrap_or((0, 0));
+            (
+                (size - fit_width) as u16 / 2 + scale_coordinate(x, width, fit_width),
+                (size - fit_height) as u16 / 2 + scale_coordinate(y, height, fit_height),
+            )
+        });
+
+        let set = Self::icon_set(source, sizes, filter)?;
+        self.buffers = set.buffers;
+        self.widths = set.widths;
+        self.heights = set.heights;
+        self.hotspots = None;
+        if let Some((x, y)) = hotspot {
+            self.set_hotspot(x, y);
+        }
+
+        Ok(())
+    }
+
     pub fn from_memory(icon_bytes: &[u8]) -> Result<Self> {
         let cursor = Cursor::new(icon_bytes);
         Self::read_from_reader(cursor)
@@ -49,25 +162,75 @@ impl Ico {
             heights.push(height);
         }
 
+        let hotspots = (icon_dir.resource_type() == ResourceType::Cursor).then(|| {
+            icon_dir
+                .entries()
+                .iter()
+                .map(|entry| entry.cursor_hotspot().unwrap_or((0, 0)))
+                .collect()
+        });
+
         Ok(Self {
             buffers,
             widths,
             heights,
+            hotspots,
+            palette: None,
+            encoding: IcoEncoding::default(),
         })
     }
 
+    /// "cur" for cursors, "ico" otherwise.
+    pub fn extension(&self) -> &'static str {
+        if self.hotspots.is_some() {
+            "cur"
+        } else {
+            "ico"
+        }
+    }
+
+    /// The entry with the most pixels.
+    pub fn largest(&self) -> Option<&RgbaImage> {
+        self.largest_index().map(|i| &self.buffers[i])
+    }
+
+    fn largest_index(&self) -> Option<usize> {
+        (0..self.buffers.len()).max_by_key(|&i| self.widths[i] * self.heights[i])
+    }
+
+    /// Turns the icon into a cursor with its hotspot at (`x`, `y`) in the largest entry, and at
+    /// the same relative spot in every other entry.
+    pub fn set_hotspot(&mut self, x: u16, y: u16) {
+        let Some(largest) = self.largest_index() else {
+            return;
+        };
+        let (largest_width, largest_height) = (self.widths[largest], self.heights[largest]);
+
+        self.hotspots = Some(
+            (0..self.buffers.len())
+                .map(|i| {
+                    (
+                        scale_coordinate(x, largest_width, self.widths[i]),
+                        scale_coordinate(y, largest_height, self.heights[i]),
+                    )
+                })
+                .collect(),
+        );
+    }
+
     pub fn write_to_file<P: AsRef<Path>>(&self, path: P) -> Result<()> {
         let path = path.as_ref();
+        let extension = self.extension();
 
-        if matches!(path.extension(), Some(ext) if ext != "ico") {
+        if matches!(path.extension(), Some(ext) if ext != extension) {
             log::debug!(
-                "Output path {} has a non-icon extension; replacing with .ico",
+                "Output path {} has a non-{extension} extension; replacing with .{extension}",
                 path.display()
             );
         }
 
         let mut path_with_ext = PathBuf::from(path);
-        path_with_ext.set_extension("ico");
+        path_with_ext.set_extension(extension);
 
         let file = File::create(&path_with_ext)?;
         self.write_to_writer(file)?;
@@ -76,33 +239,101 @@ impl Ico {
 
     pub fn write_to_memory(&self) -> Result<Vec<u8>> {
         let mut buffer = Vec::new();
-        {
-            let writer = Cursor::new(&mut buffer);
-            self.write_to_writer(writer)?;
-        }
+        self.write_to_writer(&mut buffer)?;
         Ok(buffer)
     }
 
-    fn write_to_writer<W: std::io::Write + std::io::Seek>(&self, mut writer: W) -> Result<()> {
-        let mut icon_dir = IconDir::new(ResourceType::Icon);
+    fn write_to_writer<W: Write>(&self, mut writer: W) -> Result<()> {
+        let entries = (0..self.buffers.len())
+            .map(|i| self.encode_entry(i))
+            .collect::<Result<Vec<_>>>()?;
+
+        // Resource type 1 is an icon, 2 a cursor
+        let resource_type: u16 = if self.hotspots.is_some() { 2 } else { 1 };
+
+        let mut directory = Vec::with_capacity(6 + 16 * entries.len());
+        directory.extend_from_slice(&0u16.to_le_bytes());
+        directory.extend_from_slice(&resource_type.to_le_bytes());
+        directory.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+
+        let mut offset = (6 + 16 * entries.len()) as u32;
+        for (i, entry) in entries.iter().enumerate() {
+            // Cursors store their hotspot in place of the planes and bit count
+            let (planes, bits_per_pixel) = match &self.hotspots {
+                Some(hotspots) => hotspots.get(i).copied().unwrap_or((0, 0)),
+                None => (entry.planes, entry.bits_per_pixel),
+            };
 
-        for image_buffer in &self.buffers {
-            let width = image_buffer.width();
-            let height = image_buffer.height();
+            // A size of 256 wraps around to 0, which is how the format spells it
+            directory.push(self.buffers[i].width() as u8);
+            directory.push(self.buffers[i].height() as u8);
+            directory.push(entry.num_colors);
+            directory.push(0);
+            directory.extend_from_slice(&planes.to_le_bytes());
+            directory.extend_from_slice(&bits_per_pixel.to_le_bytes());
+            directory.extend_from_slice(&(entry.data.len() as u32).to_le_bytes());
+            directory.extend_from_slice(&offset.to_le_bytes());
+            offset += entry.data.len() as u32;
+        }
 
-            let rgba_data_vec = image_buffer.to_vec();
+        writer.write_all(&directory)?;
+        for entry in &entries {
+            writer.write_all(&entry.data)?;
+        }
 
-            let icon_image = IconImage::from_rgba_data(width, height, rgba_data_vec);
+        Ok(())
+    }
 
-            let entry = IconDirEntry::encode(&icon_image)?;
-            icon_dir.add_entry(entry);
+    fn encode_entry(&self, i: usize) -> Result<Entry> {
+        let buffer = &self.buffers[i];
+        let (width, height) = buffer.dimensions();
+        if width > MAX_ICON_SIZE || height > MAX_ICON_SIZE {
+            return Err(Error::InvalidIconSize {
+                size: width.max(height),
+                max: MAX_ICON_SIZE,
+            });
         }
 
-        icon_dir.write(&mut writer)?;
+        let indexed = match (self.encoding, &self.palette) {
+            (IcoEncoding::Bmp, Some(palette)) => indexed_bmp(buffer, palette),
+            (IcoEncoding::Auto, Some(palette)) if width < MAX_ICON_SIZE => {
+                indexed_bmp(buffer, palette)
+            }
+            _ => None,
+        };
+        if let Some(entry) = indexed {
+            log::debug!(
+                "Writing icon {i} ({width}x{height}) as a {}-bit BMP",
+                entry.bits_per_pixel
+            );
+            return Ok(entry);
+        }
 
-        Ok(())
+        let icon_image = IconImage::from_rgba_data(width, height, buffer.to_vec());
+        let entry = match self.encoding {
+            IcoEncoding::Auto => IconDirEntry::encode(&icon_image)?,
+            IcoEncoding::Bmp => IconDirEntry::encode_as_bmp(&icon_image)?,
+            IcoEncoding::Png => IconDirEntry::encode_as_png(&icon_image)?,
+        };
+
+        let bits_per_pixel = entry.bits_per_pixel();
+        let (num_colors, planes) = if entry.is_png() {
+            (0, 0)
+        } else if bits_per_pixel < 8 {
+            (1 << bits_per_pixel, 1)
+        } else {
+            (0, 1)
+        };
+        Ok(Entry {
+            num_colors,
+            planes,
+            bits_per_pixel,
+            data: entry.data().to_vec(),
+        })
     }
 
+    /// Resizes the largest entry to the given dimensions or scale, and every other entry by the
+    /// same factor so the set keeps its range of sizes. Entries are kept within 256 pixels.
     pub fn resize(
         &mut self,
         target_width: Option<u32>,
@@ -128,51 +359,77 @@ impl Ico {
             }
         }
 
-        for (i, buffer) in self.buffers.iter_mut().enumerate() {
+        if target_width.is_none() && target_height.is_none() && scale.is_none() {
+            log::debug!("Skipping icon resize: No target dimensions or scale provided.");
+            return Ok(());
+        }
+
+        let Some(largest) = self.largest_index() else {
+            return Ok(());
+        };
+        let orig_width = self.widths[largest];
+        let orig_height = self.heights[largest];
+
+        // Determine base dimensions before scaling
+        let (base_width, base_height) = match (target_width, target_height) {
+            (Some(w), Some(h)) => (w, h),
+            (Some(w), None) => {
+                let aspect_ratio = orig_height as f32 / orig_width as f32;
+                let h = (w as f32 * aspect_ratio).round() as u32;
+                (w, if h == 0 { 1 } else { h })
+            }
+            (None, Some(h)) => {
+                let aspect_ratio = orig_width as f32 / orig_height as f32;
+                let w = (h as f32 * aspect_ratio).round() as u32;
+                (if w == 0 { 1 } else { w }, h)
+            }
+            (None, None) => (orig_width, orig_height),
+        };
+
+        // Apply scale if provided
+        let (final_width, final_height) = if let Some(s) = scale {
+            let w = ((base_width as f32) * s).round() as u32;
+            let h = ((base_height as f32) * s).round() as u32;
+            (if w == 0 { 1 } else { w }, if h == 0 { 1 } else { h })
+        } else {
+            (base_width, base_height)
+        };
+
+        if final_width > MAX_ICON_SIZE || final_height > MAX_ICON_SIZE {
+            log::warn!(
+                "Icons hold at most {MAX_ICON_SIZE}x{MAX_ICON_SIZE} pixels; clamping {final_width}x{final_height}"
+            );
+        }
+
+        let width_factor = final_width as f32 / orig_width as f32;
+        let height_factor = final_height as f32 / orig_height as f32;
+
+        for i in 0..self.buffers.len() {
             let orig_width = self.widths[i];
             let orig_height = self.heights[i];
+            let new_width =
+                ((orig_width as f32 * width_factor).round() as u32).clamp(1, MAX_ICON_SIZE);
+            let new_height =
+                ((orig_height as f32 * height_factor).round() as u32).clamp(1, MAX_ICON_SIZE);
 
-            // Determine base dimensions before scaling
-            let (base_width, base_height) = match (target_width, target_height) {
-                (Some(w), Some(h)) => (w, h),
-                (Some(w), None) => {
-                    let aspect_ratio = orig_height as f32 / orig_width as f32;
-                    let h = (w as f32 * aspect_ratio).round() as u32;
-                    (w, if h == 0 { 1 } else { h })
-                }
-                (None, Some(h)) => {
-                    let aspect_ratio = orig_width as f32 / orig_height as f32;
-                    let w = (h as f32 * aspect_ratio).round() as u32;
-                    (if w == 0 { 1 } else { w }, h)
-                }
-                (None, None) => (orig_width, orig_height),
-            };
-
-            // Apply scale if provided
-            let (final_width, final_height) = if let Some(s) = scale {
-                let w = ((base_width as f32) * s).round() as u32;
-                let h = ((base_height as f32) * s).round() as u32;
-                (if w == 0 { 1 } else { w }, if h == 0 { 1 } else { h })
-            } else {
-                if target_width.is_none() && target_height.is_none() {
-                    // No operation specified
-                    log::debug!(
-                        "Skipping resize for icon {i}: No target dimensions or scale provided."
-                    );
-                    continue;
-                }
-                (base_width, base_height)
-            };
+            if new_width == orig_width && new_height == orig_height {
+                log::debug!("Skipping resize for icon {i}: Target dimensions match original.");
+                continue;
+            }
 
-            if final_width != orig_width || final_height != orig_height {
-                log::debug!(
-                    "Resizing icon {i} from {orig_width}x{orig_height} to {final_width}x{final_height} using filter {filter:?}"
+            log::debug!(
+                "Resizing icon {i} from {orig_width}x{orig_height} to {new_width}x{new_height} using filter {filter:?}"
+            );
+            self.buffers[i] =
+                imageops::resize(&self.buffers[i], new_width, new_height, filter.into());
+            self.widths[i] = new_width;
+            self.heights[i] = new_height;
+
+            if let Some(hotspot) = self.hotspots.as_mut().and_then(|h| h.get_mut(i)) {
+                *hotspot = (
+                    scale_coordinate(hotspot.0, orig_width, new_width),
+                    scale_coordinate(hotspot.1, orig_height, new_height),
                 );
-                *buffer = image::imageops::resize(buffer, final_width, final_height, filter.into());
-                self.widths[i] = final_width;
-                self.heights[i] = final_height;
-            } else {
-                log::debug!("Skipping resize for icon {i}: Target dimensions match original.");
             }
         }
 
@@ -191,6 +448,116 @@ impl Ico {
         }
 
         log::debug!("All icons in ico palettified.");
+
+        // Interpolated gradients blend between palette colors like smoothed output does
+        let exact = match config.mapping {
+            Mapping::Smoothed => false,
+            Mapping::GradientMap => !config.gradient_interpolate,
+            Mapping::Palettized => true,
+        };
+        if exact {
+            let mut palette = config.palette.colors.clone();
+            palette.push(Rgb([0, 0, 0]));
+            self.palette = Some(palette);
+        }
+
         Ok(())
     }
 }
+
+/// Moves a coordinate along an axis resized from `from` to `to` pixels.
+fn scale_coordinate(value: u16, from: u32, to: u32) -> u16 {
+    (value as u32 * to / from.max(1)).min(to.saturating_sub(1)) as u16
+}
+
+/// Encodes an entry as a 4 or 8-bit BMP with a 1-bit transparency mask, listing the colors it
+/// uses in palette order. Returns `None` for entries with partial transparency or more than 256
+/// colors, which need a 32-bit entry.
+fn indexed_bmp(buffer: &RgbaImage, palette: &[Rgb<u8>]) -> Option<Entry> {
+    let (transparent_color, colors) = palette.split_last()?;
+
+    let mut used = HashMap::new();
+    let mut has_transparency = false;
+    for pixel in buffer.pixels() {
+        match pixel.0[3] {
+            0 => has_transparency = true,
+            255 => {
+                used.insert(Rgb([pixel.0[0], pixel.0[1], pixel.0[2]]), 0u8);
+            }
+            _ => return None,
+        }
+    }
+
+    // Locked colors outside the palette follow the palette colors
+    let mut table: Vec<Rgb<u8>> = colors
+        .iter()
+        .filter(|color| used.contains_key(color))
+        .copied()
+        .collect();
+    let mut extra: Vec<Rgb<u8>> = used
+        .keys()
+        .filter(|color| !colors.contains(color))
+        .copied()
+        .collect();
+    extra.sort_by_key(|color| color.0);
+    table.extend(extra);
+    // Masked pixels are drawn with the placeholder, which stays black so the mask shows through
+    if has_transparency && !used.contains_key(transparent_color) {
+        table.push(*transparent_color);
+    }
+    if table.len() > 256 {
+        return None;
+    }
+    for (index, color) in table.iter().enumerate() {
+        used.insert(*color, index as u8);
+    }
+
+    let bits_per_pixel: u16 = if table.len() <= 16 { 4 } else { 8 };
+    let (width, height) = buffer.dimensions();
+    let (width, height) = (width as usize, height as usize);
+    let row_size = (width * bits_per_pixel as usize).div_ceil(32) * 4;
+    let mask_row_size = width.div_ceil(32) * 4;
+
+    let mut data =
+        Vec::with_capacity(40 + 4 * (1 << bits_per_pixel) + height * (row_size + mask_row_size));
+    // BITMAPINFOHEADER, whose height covers both the color bitmap and the mask
+    data.extend_from_slice(&40u32.to_le_bytes());
+    data.extend_from_slice(&(width as i32).to_le_bytes());
+    data.extend_from_slice(&(2 * height as i32).to_le_bytes());
+    data.extend_from_slice(&1u16.to_le_bytes());
+    data.extend_from_slice(&bits_per_pixel.to_le_bytes());
+    // Compression, image size, resolution and color counts are all left at zero
+    data.extend_from_slice(&[0; 24]);
+    for index in 0..1 << bits_per_pixel {
+        let [r, g, b] = table.get(index).map_or([0; 3], |color| color.0);
+        data.extend_from_slice(&[b, g, r, 0]);
+    }
+
+    // Both bitmaps are stored bottom-up with rows padded to four bytes
+    let mut pixels = vec![0u8; height * row_size];
+    let mut mask = vec![0u8; height * mask_row_size];
+    for (x, y, pixel) in buffer.enumerate_pixels() {
+        let (x, row) = (x as usize, height - 1 - y as usize);
+        let color = if pixel.0[3] == 0 {
+            mask[row * mask_row_size + x / 8] |= 0x80 >> (x % 8);
+            *transparent_color
+        } else {
+            Rgb([pixel.0[0], pixel.0[1], pixel.0[2]])
+        };
+        let index = used[&color];
+        if bits_per_pixel == 4 {
+            pixels[row * row_size + x / 2] |= index << (4 * (1 - x % 2));
+        } else {
+            pixels[row * row_size + x] = index;
+        }
+    }
+    data.extend_from_slice(&pixels);
+    data.extend_from_slice(&mask);
+
+    Some(Entry {
+        num_colors: if bits_per_pixel == 4 { 16 } else { 0 },
+        planes: 1,
+        bits_per_pixel,
+        data,
+    })
+}
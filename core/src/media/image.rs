@@ -1,15 +1,19 @@
+use super::metadata::{Metadata, XMP_KEYWORD};
 use crate::{
     config::Config,
     error::{Error, Result},
     processing, Filter, Mapping,
 };
 
-use image::{EncodableLayout, ImageFormat, Rgb, RgbaImage};
+use image::{
+    metadata::Orientation, DynamicImage, EncodableLayout, ImageFormat, Rgb, Rgba, RgbaImage,
+};
 
 use std::path::Path;
 use std::{
+    borrow::Cow,
     collections::HashMap,
-    io::{BufReader, BufWriter, Cursor},
+    io::{BufWriter, Cursor},
 };
 use std::{fs::File, path::PathBuf};
 
@@ -21,49 +25,205 @@ pub struct Image {
     pub width: u32,
     pub height: u32,
     pub palette: Option<Vec<Rgb<u8>>>,
+    /// Format written on output
+    pub format: ImageFormat,
+    pub png: PngOptions,
+    /// Metadata read from the input, written back out where the output format allows
+    pub metadata: Metadata,
+}
+
+/// How PNGs are encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PngOptions {
+    pub compression: PngCompression,
+    pub filter: PngFilter,
+    /// Order indexed palettes by how often each entry is used, dropping unused entries and
+    /// trimming the transparency chunk to the entries that need it
+    pub optimize: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum, strum_macros::Display))]
+pub enum PngCompression {
+    #[default]
+    Fast,
+    Default,
+    /// Smallest files, slowest to write
+    Best,
+}
+
+/// Filter applied to every row before compression.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum, strum_macros::Display))]
+pub enum PngFilter {
+    /// No filtering, usually smallest for indexed images
+    None,
+    #[default]
+    Sub,
+    Up,
+    Avg,
+    Paeth,
+    /// Pick the best filter for every row
+    Adaptive,
+}
+
+impl PngOptions {
+    pub(super) fn apply<W: std::io::Write>(&self, encoder: &mut Encoder<W>) {
+        encoder.set_compression(match self.compression {
+            PngCompression::Fast => png::Compression::Fast,
+            PngCompression::Default => png::Compression::Default,
+            PngCompression::Best => png::Compression::Best,
+        });
+
+        let filter = match self.filter {
+            PngFilter::None => png::FilterType::NoFilter,
+            PngFilter::Sub | PngFilter::Adaptive => png::FilterType::Sub,
+            PngFilter::Up => png::FilterType::Up,
+            PngFilter::Avg => png::FilterType::Avg,
+            PngFilter::Paeth => png::FilterType::Paeth,
+        };
+        encoder.set_filter(filter);
+        if self.filter == PngFilter::Adaptive {
+            encoder.set_adaptive_filter(png::AdaptiveFilterType::Adaptive);
+        }
+    }
+}
+
+/// Drops palette entries no pixel uses and remaps `indices`. With `optimize`, the rest are
+/// reordered from the most to the least used, transparent ones first so the transparency chunk
+/// can stop early.
+fn compact_palette(entries: &[Rgba<u8>], indices: &mut [u8], optimize: bool) -> Vec<Rgba<u8>> {
+    let mut counts = vec![0usize; entries.len()];
+    for &index in indices.iter() {
+        counts[index as usize] += 1;
+    }
+
+    let mut order: Vec<usize> = (0..entries.len()).filter(|&i| counts[i] > 0).collect();
+    if optimize {
+        order.sort_by_key(|&i| (entries[i].0[3] == 255, std::cmp::Reverse(counts[i])));
+    }
+
+    let mut remap = vec![0u8; entries.len()];
+    for (new, &old) in order.iter().enumerate() {
+        remap[old] = new as u8;
+    }
+    for index in indices.iter_mut() {
+        *index = remap[*index as usize];
+    }
+
+    order.into_iter().map(|i| entries[i]).collect()
+}
+
+/// Packs one index per pixel into rows of `depth` bits per index, high bits first.
+fn pack(indices: &[u8], width: usize, depth: BitDepth) -> Vec<u8> {
+    let bits = depth as usize;
+    if bits == 8 {
+        return indices.to_vec();
+    }
+
+    let per_byte = 8 / bits;
+    let row_bytes = width.div_ceil(per_byte);
+    let mut packed = Vec::with_capacity(row_bytes * indices.len() / width.max(1));
+    for row in indices.chunks(width.max(1)) {
+        for chunk in row.chunks(per_byte) {
+            let mut byte = 0u8;
+            for (i, &index) in chunk.iter().enumerate() {
+                byte |= index << (8 - bits * (i + 1));
+            }
+            packed.push(byte);
+        }
+    }
+    packed
+}
+
+/// Format an input is written back as. JPEG output would bring back colors outside of the
+/// palette, so it is written as PNG instead.
+fn output_format(input: ImageFormat) -> ImageFormat {
+    match input {
+        ImageFormat::Bmp
+        | ImageFormat::Tga
+        | ImageFormat::Qoi
+        | ImageFormat::Pnm
+        | ImageFormat::WebP => input,
+        _ => ImageFormat::Png,
+    }
 }
 
 impl Image {
-    pub fn from_memory(image_bytes: &[u8]) -> Result<Self> {
-        let dynamic_image = image::load_from_memory(image_bytes)?;
-        let buffer = dynamic_image.into_rgba8();
-        let width = buffer.width();
-        let height = buffer.height();
-        Ok(Self {
+    /// Wraps an RGBA buffer with no metadata, written as PNG unless `format` is changed.
+    pub fn from_rgba(buffer: RgbaImage) -> Self {
+        Self {
+            width: buffer.width(),
+            height: buffer.height(),
             buffer,
-            width,
-            height,
             palette: None,
-        })
+            format: ImageFormat::Png,
+            png: PngOptions::default(),
+            metadata: Metadata::default(),
+        }
+    }
+
+    pub fn from_memory(image_bytes: &[u8]) -> Result<Self> {
+        let format = image::guess_format(image_bytes)?;
+        Self::decode(image_bytes, format)
     }
 
     pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let file = File::open(&path)?;
         let format = ImageFormat::from_path(&path)?;
-        let dynamic_image = image::load(BufReader::new(file), format)?;
-        let buffer = dynamic_image.into_rgba8();
-        let width = buffer.width();
-        let height = buffer.height();
+        Self::decode(&std::fs::read(&path)?, format)
+    }
+
+    /// Decodes an image, turning it upright according to its EXIF orientation.
+    fn decode(bytes: &[u8], format: ImageFormat) -> Result<Self> {
+        let mut dynamic_image = image::load_from_memory_with_format(bytes, format)?;
+        let mut metadata = Metadata::read(bytes, format);
+
+        let orientation = metadata.orientation();
+        if let Some(orientation) = Orientation::from_exif(orientation).filter(|_| orientation != 1)
+        {
+            log::debug!("Applying EXIF orientation {orientation:?}");
+            dynamic_image.apply_orientation(orientation);
+            metadata.clear_orientation();
+        }
+
         Ok(Self {
-            buffer,
-            width,
-            height,
-            palette: None,
+            format: output_format(format),
+            metadata,
+            ..Self::from_rgba(dynamic_image.into_rgba8())
         })
     }
 
+    /// File extension of the output format.
+    pub fn extension(&self) -> &'static str {
+        match self.format {
+            // Written as a binary pixmap
+            ImageFormat::Pnm => "ppm",
+            format => format.extensions_str().first().copied().unwrap_or("png"),
+        }
+    }
+
     pub fn write_to_file<P: AsRef<Path>>(&self, path: P) -> Result<()> {
         let path = path.as_ref();
+        // Keep alternative spellings of the extension, like .jpeg
+        let extension = match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext)
+                if self.format != ImageFormat::Pnm
+                    && self.format.extensions_str().contains(&ext) =>
+            {
+                ext
+            }
+            _ => self.extension(),
+        };
 
-        if matches!(path.extension(), Some(ext) if ext != "png") {
+        if matches!(path.extension(), Some(ext) if ext != extension) {
             log::debug!(
-                "Output path {} has a non-png extension; replacing with .png",
+                "Output path {} has a non-{extension} extension; replacing with .{extension}",
                 path.display()
             );
         }
 
         let mut path_with_ext = PathBuf::from(path);
-        path_with_ext.set_extension("png");
+        path_with_ext.set_extension(extension);
 
         let file = File::create(&path_with_ext)?;
         let writer = BufWriter::new(file);
@@ -80,84 +240,156 @@ impl Image {
     }
 
     fn write_to_writer<W: std::io::Write + std::io::Seek>(&self, mut writer: W) -> Result<()> {
+        match self.format {
+            ImageFormat::Png => {}
+            // Neither pixmaps nor JPEGs have an alpha channel
+            ImageFormat::Pnm | ImageFormat::Jpeg => {
+                let mut bytes = Vec::new();
+                DynamicImage::ImageRgba8(self.buffer.clone())
+                    .into_rgb8()
+                    .write_to(&mut Cursor::new(&mut bytes), self.format)?;
+                writer.write_all(&self.metadata.embed(bytes, self.format))?;
+                return Ok(());
+            }
+            format => {
+                let mut bytes = Vec::new();
+                self.buffer.write_to(&mut Cursor::new(&mut bytes), format)?;
+                writer.write_all(&self.metadata.embed(bytes, format))?;
+                return Ok(());
+            }
+        }
+
         if let Some(palette) = &self.palette {
             log::debug!(
                 "Attempting to write indexed PNG with {} palette colors.",
                 palette.len()
             );
 
-            let mut encoder = Encoder::new(&mut writer, self.width, self.height);
-            encoder.set_color(ColorType::Indexed);
-            encoder.set_depth(BitDepth::Eight);
-            encoder.set_compression(png::Compression::Fast);
+            // The last palette entry is the transparent placeholder
+            let (transparent_color, colors) = palette.split_last().unwrap();
 
-            let mut plte_palette: Vec<u8> = Vec::with_capacity(palette.len() * 3);
-            let mut trns_alphas: Vec<u8> = Vec::with_capacity(palette.len());
+            let mut entries: Vec<Rgba<u8>> = colors
+                .iter()
+                .map(|color| Rgba([color.0[0], color.0[1], color.0[2], 255]))
+                .collect();
+            let mut color_to_index: HashMap<Rgba<u8>, u8> = entries
+                .iter()
+                .enumerate()
+                .map(|(i, color)| (*color, i as u8))
+                .collect();
 
-            for color in palette.iter().take(palette.len() - 1) {
-                plte_palette.push(color.0[0]); // R
-                plte_palette.push(color.0[1]); // G
-                plte_palette.push(color.0[2]); // B
-                trns_alphas.push(255u8); // A (Opaque)
+            // Partially transparent pixels (alpha levels) and locked colors outside the palette
+            // each need their own entry
+            for pixel in self.buffer.pixels() {
+                if pixel.0[3] == 0 || color_to_index.contains_key(pixel) {
+                    continue;
+                }
+                if entries.len() >= 255 {
+                    log::warn!("Too many colors for an indexed PNG; writing RGBA instead.");
+                    return self.write_rgba_png(writer);
+                }
+                color_to_index.insert(*pixel, entries.len() as u8);
+                entries.push(*pixel);
             }
 
-            let transparent_color = &palette[palette.len() - 1];
-            plte_palette.push(transparent_color.0[0]);
-            plte_palette.push(transparent_color.0[1]);
-            plte_palette.push(transparent_color.0[2]);
-            trns_alphas.push(0u8); // Transparent
+            let transparent_index = entries.len() as u8;
+            entries.push(Rgba([
+                transparent_color.0[0],
+                transparent_color.0[1],
+                transparent_color.0[2],
+                0,
+            ]));
+
+            let mut indices: Vec<u8> = self
+                .buffer
+                .pixels()
+                .map(|pixel| {
+                    if pixel.0[3] == 0 {
+                        transparent_index
+                    } else {
+                        color_to_index[pixel]
+                    }
+                })
+                .collect();
 
-            encoder.set_palette(plte_palette);
-            encoder.set_trns(trns_alphas);
+            // Unused entries, like the transparent placeholder in opaque images, would push
+            // small palettes to a larger bit depth
+            let entries = compact_palette(&entries, &mut indices, self.png.optimize);
 
-            let mut writer = encoder.write_header()?;
+            let depth = match entries.len() {
+                0..=2 => BitDepth::One,
+                3..=4 => BitDepth::Two,
+                5..=16 => BitDepth::Four,
+                _ => BitDepth::Eight,
+            };
+            log::debug!("Writing {} palette entries at {depth:?}", entries.len());
 
-            // last entry in palette is the transparent placeholder
-            let transparent_index = (palette.len() - 1) as u8;
+            let mut encoder = self.png_encoder(&mut writer)?;
+            encoder.set_color(ColorType::Indexed);
+            encoder.set_depth(depth);
+            self.png.apply(&mut encoder);
 
-            let color_to_index: HashMap<Rgb<u8>, u8> = palette
+            let plte_palette: Vec<u8> = entries
                 .iter()
-                .take(palette.len() - 1) // skip the last entry
-                .enumerate()
-                .map(|(i, color)| (*color, i as u8))
+                .flat_map(|color| [color.0[0], color.0[1], color.0[2]])
                 .collect();
-
-            let width = self.width as usize;
-            let height = self.height as usize;
-            let mut indices = Vec::with_capacity(width * height);
-
-            for y in 0..self.height {
-                for x in 0..self.width {
-                    let pixel_rgba = self.buffer.get_pixel(x, y);
-                    let r = pixel_rgba[0];
-                    let g = pixel_rgba[1];
-                    let b = pixel_rgba[2];
-                    let a = pixel_rgba[3];
-
-                    if a == 0 {
-                        indices.push(transparent_index);
-                    } else {
-                        let current_color = Rgb([r, g, b]);
-                        match color_to_index.get(&current_color) {
-                            Some(&idx) => indices.push(idx),
-                            None => {
-                                log::error!(
-                                "Pixel color ({r},{g},{b}) not found in palette! Defaulting to index 0."
-                            );
-                                indices.push(0);
-                            }
-                        }
-                    }
-                }
+            let mut trns_alphas: Vec<u8> = entries.iter().map(|color| color.0[3]).collect();
+            if self.png.optimize {
+                // Entries past the last transparent one default to opaque
+                let len = trns_alphas
+                    .iter()
+                    .rposition(|&a| a < 255)
+                    .map_or(0, |i| i + 1);
+                trns_alphas.truncate(len);
             }
 
-            writer.write_image_data(&indices)?;
+            encoder.set_palette(plte_palette);
+            if trns_alphas.iter().any(|&a| a < 255) {
+                encoder.set_trns(trns_alphas);
+            }
+            let mut writer = encoder.write_header()?;
+            writer.write_image_data(&pack(&indices, self.width as usize, depth))?;
         } else {
-            self.buffer.write_to(&mut writer, ImageFormat::Png)?
+            self.write_rgba_png(writer)?;
         }
         Ok(())
     }
 
+    /// PNG encoder carrying the image's metadata.
+    fn png_encoder<W: std::io::Write>(&self, writer: W) -> Result<Encoder<'_, W>> {
+        let mut info = png::Info::with_size(self.width, self.height);
+        info.icc_profile = self.metadata.icc.as_deref().map(Cow::Borrowed);
+        info.exif_metadata = self.metadata.exif.as_deref().map(Cow::Borrowed);
+
+        let mut encoder = Encoder::with_info(writer, info)?;
+        for (keyword, text) in &self.metadata.text {
+            // tEXt only holds Latin-1
+            if text.is_ascii() {
+                encoder.add_text_chunk(keyword.clone(), text.clone())?;
+            } else {
+                encoder.add_itxt_chunk(keyword.clone(), text.clone())?;
+            }
+        }
+        if let Some(xmp) = &self.metadata.xmp {
+            encoder.add_itxt_chunk(
+                XMP_KEYWORD.to_string(),
+                String::from_utf8_lossy(xmp).into_owned(),
+            )?;
+        }
+        Ok(encoder)
+    }
+
+    fn write_rgba_png<W: std::io::Write>(&self, mut writer: W) -> Result<()> {
+        let mut encoder = self.png_encoder(&mut writer)?;
+        encoder.set_color(ColorType::Rgba);
+        encoder.set_depth(BitDepth::Eight);
+        self.png.apply(&mut encoder);
+        encoder
+            .write_header()?
+            .write_image_data(self.buffer.as_raw())?;
+        Ok(())
+    }
+
     pub fn resize(
         &mut self,
         target_width: Option<u32>,
@@ -0,0 +1,473 @@
+use super::animation::{png_chunk, riff_chunks, u24_le, write_riff_chunk};
+
+use image::ImageFormat;
+
+/// Which metadata is written back out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum, strum_macros::Display))]
+pub enum MetadataMode {
+    /// Keep everything that was read
+    #[default]
+    Keep,
+    /// Write no metadata at all
+    Strip,
+    /// Keep color profiles, text and EXIF without its GPS location; XMP is dropped since it may
+    /// repeat the location
+    Safe,
+}
+
+/// Metadata read from a PNG, JPEG or WebP, written back out when the output format can hold it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metadata {
+    /// EXIF as a TIFF structure, without the `Exif\0\0` prefix JPEG stores it with
+    pub exif: Option<Vec<u8>>,
+    /// ICC color profile
+    pub icc: Option<Vec<u8>>,
+    /// XMP packet
+    pub xmp: Option<Vec<u8>>,
+    /// PNG text chunks and JPEG comments as (keyword, text)
+    pub text: Vec<(String, String)>,
+}
+
+const EXIF_PREFIX: &[u8] = b"Exif\0\0";
+const XMP_PREFIX: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const ICC_PREFIX: &[u8] = b"ICC_PROFILE\0";
+/// Keyword PNG stores XMP under, in an iTXt chunk
+pub(crate) const XMP_KEYWORD: &str = "XML:com.adobe.xmp";
+/// Keyword JPEG comments are kept under
+const COMMENT_KEYWORD: &str = "Comment";
+
+const ORIENTATION_TAG: u16 = 0x0112;
+const GPS_IFD_TAG: u16 = 0x8825;
+
+impl Metadata {
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    /// Reads the metadata of an encoded image. Formats without metadata support, and anything
+    /// that can't be parsed, give empty metadata.
+    pub(crate) fn read(bytes: &[u8], format: ImageFormat) -> Self {
+        let mut metadata = match format {
+            ImageFormat::Png => Self::read_png(bytes),
+            ImageFormat::Jpeg => Self::read_jpeg(bytes),
+            ImageFormat::WebP => Self::read_webp(bytes),
+            _ => Self::default(),
+        };
+
+        if let Some(exif) = &mut metadata.exif {
+            if exif.starts_with(EXIF_PREFIX) {
+                exif.drain(..EXIF_PREFIX.len());
+            }
+        }
+        metadata
+    }
+
+    fn read_png(bytes: &[u8]) -> Self {
+        let mut metadata = Self::default();
+        let reader = match png::Decoder::new(bytes).read_info() {
+            Ok(reader) => reader,
+            Err(err) => {
+                log::debug!("Could not read PNG metadata: {err}");
+                return metadata;
+            }
+        };
+        let info = reader.info();
+
+        metadata.icc = info.icc_profile.as_ref().map(|icc| icc.to_vec());
+        // The decoder skips eXIf, which has to come before the image data
+        metadata.exif = png_chunk(bytes, b"eXIf").map(<[u8]>::to_vec);
+        for chunk in &info.uncompressed_latin1_text {
+            metadata
+                .text
+                .push((chunk.keyword.clone(), chunk.text.clone()));
+        }
+        for chunk in &info.compressed_latin1_text {
+            if let Ok(text) = chunk.get_text() {
+                metadata.text.push((chunk.keyword.clone(), text));
+            }
+        }
+        for chunk in &info.utf8_text {
+            let Ok(text) = chunk.get_text() else {
+                continue;
+            };
+            if chunk.keyword == XMP_KEYWORD {
+                metadata.xmp = Some(text.into_bytes());
+            } else {
+                metadata.text.push((chunk.keyword.clone(), text));
+            }
+        }
+
+        metadata
+    }
+
+    fn read_jpeg(bytes: &[u8]) -> Self {
+        let mut metadata = Self::default();
+        let mut icc_chunks: Vec<(u8, &[u8])> = Vec::new();
+
+        for (marker, data) in jpeg_segments(bytes) {
+            match marker {
+                0xE1 if data.starts_with(EXIF_PREFIX) => {
+                    metadata.exif = Some(data[EXIF_PREFIX.len()..].to_vec());
+                }
+                0xE1 if data.starts_with(XMP_PREFIX) => {
+                    metadata.xmp = Some(data[XMP_PREFIX.len()..].to_vec());
+                }
+                // Profiles are split over numbered chunks
+                0xE2 if data.starts_with(ICC_PREFIX) && data.len() >= ICC_PREFIX.len() + 2 => {
+                    let sequence = data[ICC_PREFIX.len()];
+                    icc_chunks.push((sequence, &data[ICC_PREFIX.len() + 2..]));
+                }
+                0xFE => metadata.text.push((
+                    COMMENT_KEYWORD.to_string(),
+                    String::from_utf8_lossy(data).into_owned(),
+                )),
+                _ => {}
+            }
+        }
+
+        if !icc_chunks.is_empty() {
+            icc_chunks.sort_by_key(|(sequence, _)| *sequence);
+            metadata.icc = Some(
+                icc_chunks
+                    .into_iter()
+                    .flat_map(|(_, data)| data.iter().copied())
+                    .collect(),
+            );
+        }
+        metadata
+    }
+
+    fn read_webp(bytes: &[u8]) -> Self {
+        let mut metadata = Self::default();
+        for (id, data) in riff_chunks(bytes) {
+            match &id {
+                b"EXIF" => metadata.exif = Some(data.to_vec()),
+                b"ICCP" => metadata.icc = Some(data.to_vec()),
+                b"XMP " => metadata.xmp = Some(data.to_vec()),
+                _ => {}
+            }
+        }
+        metadata
+    }
+
+    /// Drops whatever `mode` doesn't keep.
+    pub fn retain(&mut self, mode: MetadataMode) {
+        match mode {
+            MetadataMode::Keep => {}
+            MetadataMode::Strip => *self = Self::default(),
+            MetadataMode::Safe => {
+                if let Some(exif) = &mut self.exif {
+                    if remove_gps(exif).is_none() {
+                        log::debug!("Could not parse EXIF to remove its location; dropping it");
+                        self.exif = None;
+                    }
+                }
+                self.xmp = None;
+            }
+        }
+    }
+
+    /// EXIF orientation, from 1 (upright) to 8.
+    pub(crate) fn orientation(&self) -> u8 {
+        self.exif
+            .as_deref()
+            .and_then(|exif| {
+                let tiff = Tiff::new(exif)?;
+                let entry = tiff.find(tiff.first_ifd()?, ORIENTATION_TAG)?;
+                tiff.u16_at(entry + 8)
+            })
+            .and_then(|orientation| u8::try_from(orientation).ok())
+            .filter(|orientation| (1..=8).contains(orientation))
+            .unwrap_or(1)
+    }
+
+    /// Marks the image as upright, once the orientation has been applied to its pixels.
+    pub(crate) fn clear_orientation(&mut self) {
+        let Some(exif) = &mut self.exif else {
+            return;
+        };
+        let Some((entry, big_endian)) = Tiff::new(exif).and_then(|tiff| {
+            Some((
+                tiff.find(tiff.first_ifd()?, ORIENTATION_TAG)?,
+                tiff.big_endian,
+            ))
+        }) else {
+            return;
+        };
+        let value = if big_endian {
+            1u16.to_be_bytes()
+        } else {
+            1u16.to_le_bytes()
+        };
+        exif[entry + 8..entry + 10].copy_from_slice(&value);
+    }
+
+    /// Adds the metadata to an encoded JPEG or WebP. PNGs get theirs from the encoder.
+    pub(crate) fn embed(&self, bytes: Vec<u8>, format: ImageFormat) -> Vec<u8> {
+        if self.is_empty() {
+            return bytes;
+        }
+        match format {
+            ImageFormat::Jpeg => self.embed_jpeg(bytes),
+            ImageFormat::WebP => self.embed_webp(bytes),
+            _ => {
+                log::debug!("{format:?} output can't hold metadata; dropping it");
+                bytes
+            }
+        }
+    }
+
+    fn embed_jpeg(&self, bytes: Vec<u8>) -> Vec<u8> {
+        let mut segments = Vec::new();
+        let mut push = |marker: u8, parts: &[&[u8]]| {
+            let length: usize = 2 + parts.iter().map(|part| part.len()).sum::<usize>();
+            if length > u16::MAX as usize {
+                log::warn!("JPEG segment {marker:#04X} is too large to write; skipping it");
+                return;
+            }
+            segments.extend_from_slice(&[0xFF, marker]);
+            segments.extend_from_slice(&(length as u16).to_be_bytes());
+            for part in parts {
+                segments.extend_from_slice(part);
+            }
+        };
+
+        if let Some(exif) = &self.exif {
+            push(0xE1, &[EXIF_PREFIX, exif]);
+        }
+        if let Some(xmp) = &self.xmp {
+            push(0xE1, &[XMP_PREFIX, xmp]);
+        }
+        if let Some(icc) = &self.icc {
+            // Segment length, prefix, sequence number and chunk count leave this much per chunk
+            let chunks: Vec<&[u8]> = icc
+                .chunks(u16::MAX as usize - 2 - ICC_PREFIX.len() - 2)
+                .collect();
+            for (i, chunk) in chunks.iter().enumerate() {
+                push(
+                    0xE2,
+                    &[ICC_PREFIX, &[i as u8 + 1, chunks.len() as u8], chunk],
+                );
+            }
+        }
+        for (keyword, text) in &self.text {
+            if keyword == COMMENT_KEYWORD {
+                push(0xFE, &[text.as_bytes()]);
+            }
+        }
+
+        // After SOI and the JFIF header, where readers expect application segments
+        let mut offset = 2;
+        if let Some((0xE0, data)) = jpeg_segments(&bytes).next() {
+            offset += 4 + data.len();
+        }
+
+        let mut out = Vec::with_capacity(bytes.len() + segments.len());
+        out.extend_from_slice(&bytes[..offset]);
+        out.extend_from_slice(&segments);
+        out.extend_from_slice(&bytes[offset..]);
+        out
+    }
+
+    /// Rewrites a simple WebP as an extended one with ICCP, EXIF and XMP chunks around the image.
+    fn embed_webp(&self, bytes: Vec<u8>) -> Vec<u8> {
+        let image_chunks: Vec<([u8; 4], &[u8])> = riff_chunks(&bytes)
+            .filter(|(id, _)| !matches!(id, b"VP8X" | b"ICCP" | b"EXIF" | b"XMP "))
+            .collect();
+        let Some(size) = image_chunks
+            .iter()
+            .find_map(|(id, data)| webp_size(id, data))
+        else {
+            log::debug!("Could not find the WebP canvas size; dropping metadata");
+            return bytes;
+        };
+
+        // Alpha is always flagged, the lossless bitstream says whether it's used
+        let mut flags = 0x10;
+        if self.icc.is_some() {
+            flags |= 0x20;
+        }
+        if self.exif.is_some() {
+            flags |= 0x08;
+        }
+        if self.xmp.is_some() {
+            flags |= 0x04;
+        }
+
+        let mut chunks = Vec::new();
+        let mut vp8x = vec![flags, 0, 0, 0];
+        vp8x.extend_from_slice(&u24_le(size.0.max(1) - 1));
+        vp8x.extend_from_slice(&u24_le(size.1.max(1) - 1));
+        write_riff_chunk(&mut chunks, b"VP8X", &vp8x);
+        if let Some(icc) = &self.icc {
+            write_riff_chunk(&mut chunks, b"ICCP", icc);
+        }
+        for (id, data) in &image_chunks {
+            write_riff_chunk(&mut chunks, id, data);
+        }
+        if let Some(exif) = &self.exif {
+            write_riff_chunk(&mut chunks, b"EXIF", exif);
+        }
+        if let Some(xmp) = &self.xmp {
+            write_riff_chunk(&mut chunks, b"XMP ", xmp);
+        }
+
+        let mut out = Vec::with_capacity(12 + chunks.len());
+        out.extend_from_slice(b"RIFF");
+        out.extend_from_slice(&(4 + chunks.len() as u32).to_le_bytes());
+        out.extend_from_slice(b"WEBP");
+        out.extend_from_slice(&chunks);
+        out
+    }
+}
+
+/// Segments of a JPEG ahead of the scan data as (marker, data).
+fn jpeg_segments(bytes: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+    let mut offset = if bytes.starts_with(&[0xFF, 0xD8]) {
+        2
+    } else {
+        bytes.len()
+    };
+
+    std::iter::from_fn(move || {
+        // Markers may be padded with fill bytes
+        while bytes.get(offset) == Some(&0xFF) && bytes.get(offset + 1) == Some(&0xFF) {
+            offset += 1;
+        }
+        if bytes.get(offset) != Some(&0xFF) {
+            return None;
+        }
+        let marker = *bytes.get(offset + 1)?;
+        // Start of scan, or end of image
+        if marker == 0xDA || marker == 0xD9 {
+            return None;
+        }
+        let length = u16::from_be_bytes([*bytes.get(offset + 2)?, *bytes.get(offset + 3)?]);
+        let data = bytes.get(offset + 4..offset + 2 + length as usize)?;
+        offset += 2 + length as usize;
+        Some((marker, data))
+    })
+}
+
+/// Canvas size stored in a VP8L or VP8 image chunk.
+fn webp_size(id: &[u8; 4], data: &[u8]) -> Option<(u32, u32)> {
+    match id {
+        // Signature, then 14-bit width and height minus one
+        b"VP8L" if data.first() == Some(&0x2F) && data.len() >= 5 => {
+            let bits = u32::from_le_bytes(data[1..5].try_into().unwrap());
+            Some(((bits & 0x3FFF) + 1, ((bits >> 14) & 0x3FFF) + 1))
+        }
+        // Frame tag and start code, then 14-bit width and height
+        b"VP8 " if data.len() >= 10 => Some((
+            u16::from_le_bytes([data[6], data[7]]) as u32 & 0x3FFF,
+            u16::from_le_bytes([data[8], data[9]]) as u32 & 0x3FFF,
+        )),
+        _ => None,
+    }
+}
+
+/// Removes the GPS directory from EXIF, clearing its entries and values so no trace of the
+/// location is left in the bytes.
+fn remove_gps(exif: &mut [u8]) -> Option<()> {
+    let tiff = Tiff::new(exif)?;
+    let ifd = tiff.first_ifd()?;
+    let Some(entry) = tiff.find(ifd, GPS_IFD_TAG) else {
+        return Some(());
+    };
+
+    let gps = tiff.u32_at(entry + 8)? as usize;
+    let mut cleared = Vec::new();
+    let gps_count = tiff.u16_at(gps)? as usize;
+    for i in 0..gps_count {
+        let gps_entry = gps + 2 + 12 * i;
+        let size = value_size(tiff.u16_at(gps_entry + 2)?) * tiff.u32_at(gps_entry + 4)? as usize;
+        if size > 4 {
+            let offset = tiff.u32_at(gps_entry + 8)? as usize;
+            cleared.push(offset..offset + size);
+        }
+    }
+    cleared.push(gps..gps + 2 + 12 * gps_count + 4);
+
+    // Drop the pointer from the first directory, moving later entries and the next directory
+    // offset up one place
+    let count = tiff.u16_at(ifd)? as usize;
+    let end = ifd + 2 + 12 * count + 4;
+    let big_endian = tiff.big_endian;
+    if end > exif.len() {
+        return None;
+    }
+    exif.copy_within(entry + 12..end, entry);
+    exif[end - 12..end].fill(0);
+    let count = (count - 1) as u16;
+    let count = if big_endian {
+        count.to_be_bytes()
+    } else {
+        count.to_le_bytes()
+    };
+    exif[ifd..ifd + 2].copy_from_slice(&count);
+
+    for range in cleared {
+        if let Some(bytes) = exif.get_mut(range) {
+            bytes.fill(0);
+        }
+    }
+    Some(())
+}
+
+/// Size in bytes of one value of a TIFF field type.
+fn value_size(field_type: u16) -> usize {
+    match field_type {
+        3 | 8 => 2,
+        4 | 9 | 11 => 4,
+        5 | 10 | 12 => 8,
+        _ => 1,
+    }
+}
+
+/// Read access to the TIFF structure EXIF is stored as.
+struct Tiff<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> Tiff<'a> {
+    fn new(data: &'a [u8]) -> Option<Self> {
+        let big_endian = match data.get(..4)? {
+            b"MM\0*" => true,
+            b"II*\0" => false,
+            _ => return None,
+        };
+        Some(Self { data, big_endian })
+    }
+
+    fn u16_at(&self, offset: usize) -> Option<u16> {
+        let bytes: [u8; 2] = self.data.get(offset..offset + 2)?.try_into().unwrap();
+        Some(if self.big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    }
+
+    fn u32_at(&self, offset: usize) -> Option<u32> {
+        let bytes: [u8; 4] = self.data.get(offset..offset + 4)?.try_into().unwrap();
+        Some(if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+
+    fn first_ifd(&self) -> Option<usize> {
+        Some(self.u32_at(4)? as usize)
+    }
+
+    /// Offset of the entry for `tag` in the directory at `ifd`.
+    fn find(&self, ifd: usize, tag: u16) -> Option<usize> {
+        let count = self.u16_at(ifd)? as usize;
+        (0..count)
+            .map(|i| ifd + 2 + 12 * i)
+            .find(|&entry| self.u16_at(entry) == Some(tag))
+    }
+}
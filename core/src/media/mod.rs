@@ -1,16 +1,25 @@
+mod animation;
 mod gif;
 mod ico;
 mod image;
+mod metadata;
+#[cfg(feature = "tiff")]
+mod tiff;
 #[cfg(feature = "video")]
 mod video;
 
-use ::image::{guess_format, ImageFormat};
-pub use gif::Gif;
-pub use ico::Ico;
-pub use image::Image;
+use ::image::{guess_format, Frame, ImageFormat, RgbaImage};
+pub use animation::{animated_format, Animation, AnimationFormat};
+pub use gif::{ExtensionBlock, FrameLayout, Gif, GifOptimization};
+use ico::MAX_ICON_SIZE;
+pub use ico::{Ico, IcoEncoding, STANDARD_ICON_SIZES};
+pub use image::{Image, PngCompression, PngFilter, PngOptions};
+pub use metadata::{Metadata, MetadataMode};
+#[cfg(feature = "tiff")]
+pub use tiff::Tiff;
 
 #[cfg(feature = "video")]
-pub use video::Video;
+pub use video::{Video, VideoPassthrough};
 
 use crate::{
     config::Config,
@@ -19,11 +28,81 @@ use crate::{
 };
 use std::path::Path;
 
+/// Format media is written as, independent of the format it was read from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum, strum_macros::Display))]
+pub enum OutputFormat {
+    /// PNG, or APNG for more than one frame
+    Png,
+    Jpeg,
+    /// WebP, animated for more than one frame
+    #[cfg_attr(feature = "cli", value(name = "webp"))]
+    WebP,
+    Gif,
+    Ico,
+    /// Cursor: an icon with a hotspot in each entry
+    Cur,
+    Bmp,
+    /// TIFF with one page per frame
+    Tiff,
+    Tga,
+    Qoi,
+    /// Binary pixmap (PPM)
+    Pnm,
+    Mp4,
+}
+
+impl OutputFormat {
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        Some(match extension.to_ascii_lowercase().as_str() {
+            "png" | "apng" => OutputFormat::Png,
+            "jpg" | "jpeg" => OutputFormat::Jpeg,
+            "webp" => OutputFormat::WebP,
+            "gif" => OutputFormat::Gif,
+            "ico" => OutputFormat::Ico,
+            "cur" => OutputFormat::Cur,
+            "bmp" => OutputFormat::Bmp,
+            "tif" | "tiff" => OutputFormat::Tiff,
+            "tga" => OutputFormat::Tga,
+            "qoi" => OutputFormat::Qoi,
+            "pnm" | "pbm" | "pgm" | "ppm" | "pam" => OutputFormat::Pnm,
+            "mp4" => OutputFormat::Mp4,
+            _ => return None,
+        })
+    }
+
+    /// Format named by the extension of `path`, if any.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        Self::from_extension(path.extension()?.to_str()?)
+    }
+
+    /// Format of a single still frame, `None` for formats that hold several.
+    fn still(self) -> Option<ImageFormat> {
+        match self {
+            OutputFormat::Png => Some(ImageFormat::Png),
+            OutputFormat::Jpeg => Some(ImageFormat::Jpeg),
+            OutputFormat::WebP => Some(ImageFormat::WebP),
+            OutputFormat::Bmp => Some(ImageFormat::Bmp),
+            OutputFormat::Tga => Some(ImageFormat::Tga),
+            OutputFormat::Qoi => Some(ImageFormat::Qoi),
+            OutputFormat::Pnm => Some(ImageFormat::Pnm),
+            OutputFormat::Gif
+            | OutputFormat::Ico
+            | OutputFormat::Cur
+            | OutputFormat::Tiff
+            | OutputFormat::Mp4 => None,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub enum Media {
+    Animation(Animation),
     Gif(Gif),
     Ico(Ico),
     Image(Image),
+    #[cfg(feature = "tiff")]
+    Tiff(Tiff),
     #[cfg(feature = "video")]
     Video(Video),
 }
@@ -37,35 +116,191 @@ impl Media {
             }
         }
 
+        if is_cursor_path(path) {
+            return Ok(Media::Ico(Ico::from_file(path)?));
+        }
+
         let format = ImageFormat::from_path(path)?;
         match format {
             ImageFormat::Gif => Ok(Media::Gif(Gif::from_file(path)?)),
             ImageFormat::Ico => Ok(Media::Ico(Ico::from_file(path)?)),
-            ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::WebP => {
-                Ok(Media::Image(Image::from_file(path)?))
-            }
+            ImageFormat::Png | ImageFormat::WebP => still_or_animated(&std::fs::read(path)?),
+            ImageFormat::Jpeg
+            | ImageFormat::Bmp
+            | ImageFormat::Tga
+            | ImageFormat::Qoi
+            | ImageFormat::Pnm => Ok(Media::Image(Image::from_file(path)?)),
+            #[cfg(feature = "tiff")]
+            ImageFormat::Tiff => Ok(Media::Tiff(Tiff::from_file(path)?)),
             _ => Err(Error::UnsupportedFormat),
         }
     }
 
     pub fn from_memory(bytes: &[u8]) -> Result<Self> {
         // TODO: Actual proper video detection
+        if is_cursor(bytes) {
+            return Ok(Media::Ico(Ico::from_memory(bytes)?));
+        }
         let format = guess_format(bytes)?;
         match format {
             ImageFormat::Gif => Ok(Media::Gif(Gif::from_memory(bytes)?)),
             ImageFormat::Ico => Ok(Media::Ico(Ico::from_memory(bytes)?)),
-            ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::WebP => {
+            ImageFormat::Png | ImageFormat::WebP => still_or_animated(bytes),
+            ImageFormat::Jpeg | ImageFormat::Bmp | ImageFormat::Qoi | ImageFormat::Pnm => {
                 Ok(Media::Image(Image::from_memory(bytes)?))
             }
+            #[cfg(feature = "tiff")]
+            ImageFormat::Tiff => Ok(Media::Tiff(Tiff::from_memory(bytes)?)),
             _ => Err(Error::UnsupportedFormat),
         }
     }
 
+    /// Converts to be written as `format`. Still images become one-frame animations, and
+    /// animations or videos written to a still format keep only their first frame.
+    pub fn convert(self, format: OutputFormat) -> Result<Self> {
+        match (self, format) {
+            (media @ Media::Gif(_), OutputFormat::Gif) => Ok(media),
+            (Media::Ico(mut ico), OutputFormat::Ico) => {
+                ico.hotspots = None;
+                Ok(Media::Ico(ico))
+            }
+            (Media::Ico(mut ico), OutputFormat::Cur) => {
+                if ico.hotspots.is_none() {
+                    ico.set_hotspot(0, 0);
+                }
+                Ok(Media::Ico(ico))
+            }
+            #[cfg(feature = "tiff")]
+            (media @ Media::Tiff(_), OutputFormat::Tiff) => Ok(media),
+            #[cfg(feature = "video")]
+            (media @ Media::Video(_), OutputFormat::Mp4) => Ok(media),
+            // Keeps the metadata and encoding options along with the pixels
+            (Media::Image(mut img), format) if format.still().is_some() => {
+                img.format = format.still().unwrap();
+                Ok(Media::Image(img))
+            }
+            (Media::Animation(mut anim), OutputFormat::Png | OutputFormat::WebP)
+                if anim.frames.len() > 1 =>
+            {
+                anim.format = match format {
+                    OutputFormat::Png => AnimationFormat::Png,
+                    _ => AnimationFormat::WebP,
+                };
+                Ok(Media::Animation(anim))
+            }
+            (media, format) => {
+                log::debug!("Converting media to {format:?}");
+                let loop_count = media.loop_count();
+                Self::from_frames(media.into_frames()?, loop_count, format)
+            }
+        }
+    }
+
+    /// Times an animation plays, 0 for forever.
+    fn loop_count(&self) -> u32 {
+        match self {
+            Media::Animation(anim) => anim.loop_count,
+            Media::Gif(gif) => match gif.repeat {
+                // The first play isn't counted as a repeat
+                Some(::image::codecs::gif::Repeat::Finite(count)) => count as u32 + 1,
+                Some(::image::codecs::gif::Repeat::Infinite) => 0,
+                // No loop block
+                None => 1,
+            },
+            _ => 0,
+        }
+    }
+
+    fn into_frames(self) -> Result<Vec<Frame>> {
+        Ok(match self {
+            Media::Animation(anim) => anim.frames,
+            Media::Gif(gif) => gif.frames,
+            // The largest entry stands for the whole icon set
+            Media::Ico(ico) => ico
+                .buffers
+                .into_iter()
+                .max_by_key(|buffer| buffer.width() * buffer.height())
+                .map(Frame::new)
+                .into_iter()
+                .collect(),
+            Media::Image(img) => vec![Frame::new(img.buffer)],
+            #[cfg(feature = "tiff")]
+            Media::Tiff(tiff) => tiff.pages.into_iter().map(Frame::new).collect(),
+            #[cfg(feature = "video")]
+            Media::Video(vid) => vid.frames()?,
+        })
+    }
+
+    fn from_frames(frames: Vec<Frame>, loop_count: u32, format: OutputFormat) -> Result<Self> {
+        let (width, height) = frames
+            .first()
+            .ok_or(Error::UnsupportedFormat)?
+            .buffer()
+            .dimensions();
+
+        match format {
+            OutputFormat::Gif => Ok(Media::Gif(Gif::from_frames(
+                on_canvas(frames, width, height),
+                width,
+                height,
+                loop_count,
+            ))),
+            OutputFormat::Png | OutputFormat::WebP if frames.len() > 1 => {
+                Ok(Media::Animation(Animation {
+                    frames: on_canvas(frames, width, height),
+                    width,
+                    height,
+                    format: match format {
+                        OutputFormat::Png => AnimationFormat::Png,
+                        _ => AnimationFormat::WebP,
+                    },
+                    loop_count,
+                    png: PngOptions::default(),
+                }))
+            }
+            OutputFormat::Ico | OutputFormat::Cur => {
+                let buffer = fit_icon(frames.into_iter().next().unwrap().into_buffer());
+                let mut ico = Ico::from_buffers(vec![buffer]);
+                if format == OutputFormat::Cur {
+                    ico.set_hotspot(0, 0);
+                }
+                Ok(Media::Ico(ico))
+            }
+            #[cfg(feature = "tiff")]
+            OutputFormat::Tiff => Ok(Media::Tiff(Tiff {
+                pages: frames.into_iter().map(Frame::into_buffer).collect(),
+            })),
+            #[cfg(not(feature = "tiff"))]
+            OutputFormat::Tiff => Err(Error::UnsupportedFormat),
+            #[cfg(feature = "video")]
+            OutputFormat::Mp4 => Ok(Media::Video(Video::from_frames(&on_canvas(
+                frames, width, height,
+            ))?)),
+            #[cfg(not(feature = "video"))]
+            OutputFormat::Mp4 => Err(Error::VideoFeatureDisabled),
+            still => {
+                if frames.len() > 1 {
+                    log::warn!(
+                        "{still:?} holds a single image; keeping the first of {} frames",
+                        frames.len()
+                    );
+                }
+                Ok(Media::Image(Image {
+                    format: still.still().unwrap_or(ImageFormat::Png),
+                    ..Image::from_rgba(frames.into_iter().next().unwrap().into_buffer())
+                }))
+            }
+        }
+    }
+
     pub fn write_to_file<P: AsRef<Path>>(&self, path: P) -> Result<()> {
         match self {
+            Media::Animation(anim) => anim.write_to_file(path),
             Media::Gif(gif) => gif.write_to_file(path),
             Media::Ico(ico) => ico.write_to_file(path),
             Media::Image(img) => img.write_to_file(path),
+            #[cfg(feature = "tiff")]
+            Media::Tiff(tiff) => tiff.write_to_file(path),
             #[cfg(feature = "video")]
             Media::Video(vid) => vid.write_to_file(path),
         }
@@ -73,9 +308,12 @@ impl Media {
 
     pub fn write_to_memory(&self) -> Result<Vec<u8>> {
         match self {
+            Media::Animation(anim) => anim.write_to_memory(),
             Media::Gif(gif) => gif.write_to_memory(),
             Media::Ico(ico) => ico.write_to_memory(),
             Media::Image(img) => img.write_to_memory(),
+            #[cfg(feature = "tiff")]
+            Media::Tiff(tiff) => tiff.write_to_memory(),
             #[cfg(feature = "video")]
             Media::Video(vid) => vid.write_to_memory(),
         }
@@ -89,9 +327,12 @@ impl Media {
         filter: Filter,
     ) -> Result<()> {
         match self {
+            Media::Animation(anim) => anim.resize(target_width, target_height, scale, filter),
             Media::Gif(gif) => gif.resize(target_width, target_height, scale, filter),
             Media::Ico(ico) => ico.resize(target_width, target_height, scale, filter),
             Media::Image(img) => img.resize(target_width, target_height, scale, filter),
+            #[cfg(feature = "tiff")]
+            Media::Tiff(tiff) => tiff.resize(target_width, target_height, scale, filter),
             #[cfg(feature = "video")]
             Media::Video(vid) => vid.resize(target_width, target_height, scale, filter),
         }
@@ -99,9 +340,12 @@ impl Media {
 
     pub async fn palettify(&mut self, config: &Config) -> Result<()> {
         match self {
+            Media::Animation(anim) => anim.palettify(config).await,
             Media::Gif(gif) => gif.palettify(config).await,
             Media::Ico(ico) => ico.palettify(config).await,
             Media::Image(img) => img.palettify(config).await,
+            #[cfg(feature = "tiff")]
+            Media::Tiff(tiff) => tiff.palettify(config).await,
             #[cfg(feature = "video")]
             Media::Video(vid) => vid.palettify(config).await,
         }
@@ -109,9 +353,12 @@ impl Media {
 
     pub fn default_extension(&self) -> &'static str {
         match self {
+            Media::Animation(anim) => anim.format.extension(),
             Media::Gif(_) => "gif",
-            Media::Ico(_) => "ico",
-            Media::Image(_) => "png",
+            Media::Ico(ico) => ico.extension(),
+            Media::Image(img) => img.extension(),
+            #[cfg(feature = "tiff")]
+            Media::Tiff(_) => "tiff",
             #[cfg(feature = "video")]
             Media::Video(_) => "mp4",
         }
@@ -131,26 +378,103 @@ pub fn load_media_from_path(path: &Path) -> Result<Media> {
         }
     }
 
+    if is_cursor_path(path) {
+        return Ok(Media::Ico(Ico::from_file(path)?));
+    }
+
     let format = ImageFormat::from_path(path)?;
     match format {
         ImageFormat::Gif => Ok(Media::Gif(Gif::from_file(path)?)),
         ImageFormat::Ico => Ok(Media::Ico(Ico::from_file(path)?)),
-        ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::WebP => {
-            Ok(Media::Image(Image::from_file(path)?))
-        }
+        ImageFormat::Png | ImageFormat::WebP => still_or_animated(&std::fs::read(path)?),
+        ImageFormat::Jpeg
+        | ImageFormat::Bmp
+        | ImageFormat::Tga
+        | ImageFormat::Qoi
+        | ImageFormat::Pnm => Ok(Media::Image(Image::from_file(path)?)),
+        #[cfg(feature = "tiff")]
+        ImageFormat::Tiff => Ok(Media::Tiff(Tiff::from_file(path)?)),
         _ => Err(Error::UnsupportedFormat),
     }
 }
 
 pub fn load_media_from_memory(bytes: &[u8]) -> Result<Media> {
     // TODO: Actual proper video detection
+    if is_cursor(bytes) {
+        return Ok(Media::Ico(Ico::from_memory(bytes)?));
+    }
     let format = guess_format(bytes)?;
     match format {
         ImageFormat::Gif => Ok(Media::Gif(Gif::from_memory(bytes)?)),
         ImageFormat::Ico => Ok(Media::Ico(Ico::from_memory(bytes)?)),
-        ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::WebP => {
+        ImageFormat::Png | ImageFormat::WebP => still_or_animated(bytes),
+        ImageFormat::Jpeg | ImageFormat::Bmp | ImageFormat::Qoi | ImageFormat::Pnm => {
             Ok(Media::Image(Image::from_memory(bytes)?))
         }
+        #[cfg(feature = "tiff")]
+        ImageFormat::Tiff => Ok(Media::Tiff(Tiff::from_memory(bytes)?)),
         _ => Err(Error::UnsupportedFormat),
     }
 }
+
+/// Whether `path` names a CUR file, which the image crate doesn't recognize.
+fn is_cursor_path(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("cur"))
+}
+
+/// Whether `bytes` start with a CUR header. Uncompressed TGAs can start the same way, so this
+/// also checks that the first entry's data follows the directory.
+fn is_cursor(bytes: &[u8]) -> bool {
+    if bytes.len() < 22 || bytes[..4] != [0, 0, 2, 0] {
+        return false;
+    }
+    let count = u16::from_le_bytes([bytes[4], bytes[5]]) as u32;
+    let first_offset = u32::from_le_bytes([bytes[18], bytes[19], bytes[20], bytes[21]]);
+    count > 0 && first_offset == 6 + 16 * count
+}
+
+/// Places every frame on a transparent canvas of the given size, for formats whose frames all
+/// share one.
+fn on_canvas(frames: Vec<Frame>, width: u32, height: u32) -> Vec<Frame> {
+    frames
+        .into_iter()
+        .map(|frame| {
+            if frame.buffer().dimensions() == (width, height) {
+                return frame;
+            }
+            let delay = frame.delay();
+            let mut canvas = RgbaImage::new(width, height);
+            ::image::imageops::replace(&mut canvas, frame.buffer(), 0, 0);
+            Frame::from_parts(canvas, 0, 0, delay)
+        })
+        .collect()
+}
+
+/// Scales an image down to fit in an icon entry, keeping its aspect ratio.
+fn fit_icon(buffer: RgbaImage) -> RgbaImage {
+    let (width, height) = buffer.dimensions();
+    if width <= MAX_ICON_SIZE && height <= MAX_ICON_SIZE {
+        return buffer;
+    }
+
+    let scale = MAX_ICON_SIZE as f32 / width.max(height) as f32;
+    let new_width = ((width as f32 * scale).round() as u32).clamp(1, MAX_ICON_SIZE);
+    let new_height = ((height as f32 * scale).round() as u32).clamp(1, MAX_ICON_SIZE);
+    log::debug!("Scaling {width}x{height} down to {new_width}x{new_height} for an icon");
+    ::image::imageops::resize(
+        &buffer,
+        new_width,
+        new_height,
+        ::image::imageops::FilterType::Lanczos3,
+    )
+}
+
+/// PNGs and WebPs are animations when they say so, still images otherwise.
+fn still_or_animated(bytes: &[u8]) -> Result<Media> {
+    if animated_format(bytes).is_some() {
+        Ok(Media::Animation(Animation::from_memory(bytes)?))
+    } else {
+        Ok(Media::Image(Image::from_memory(bytes)?))
+    }
+}
@@ -0,0 +1,259 @@
+use crate::{
+    config::Config,
+    error::{Error, Result},
+    processing, Filter, Image,
+};
+use tiff::{
+    decoder::{Decoder, DecodingResult},
+    encoder::{colortype::RGBA8, TiffEncoder},
+    tags::{PhotometricInterpretation, Tag},
+    ColorType, TiffError, TiffUnsupportedError,
+};
+
+use image::RgbaImage;
+
+use std::io::{BufWriter, Cursor};
+use std::path::Path;
+use std::{fs::File, path::PathBuf};
+
+/// A TIFF with one buffer per page (image file directory).
+#[derive(Clone)]
+pub struct Tiff {
+    pub pages: Vec<RgbaImage>,
+}
+
+impl Tiff {
+    pub fn from_memory(bytes: &[u8]) -> Result<Self> {
+        let mut decoder = Decoder::new(Cursor::new(bytes))?;
+        let mut pages = Vec::new();
+        // Copy of `bytes` with palette pages relabelled as gray, made on the first one
+        let mut relabelled: Option<Vec<u8>> = None;
+
+        loop {
+            let (width, height) = decoder.dimensions()?;
+            let page = match decoder.colortype() {
+                Ok(color) => to_rgba(width, height, color, decoder.read_image()?)?,
+                Err(TiffError::UnsupportedError(TiffUnsupportedError::InterpretationWithBits(
+                    PhotometricInterpretation::RGBPalette,
+                    _,
+                ))) => {
+                    let relabelled = relabelled.get_or_insert_with(|| bytes.to_vec());
+                    read_palette_page(&mut decoder, relabelled, pages.len())?
+                }
+                Err(err) => return Err(err.into()),
+            };
+            pages.push(page);
+
+            if !decoder.more_images() {
+                break;
+            }
+            decoder.next_image()?;
+        }
+
+        log::debug!("Read {} TIFF page(s)", pages.len());
+        Ok(Self { pages })
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_memory(&std::fs::read(path)?)
+    }
+
+    pub fn write_to_file<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+
+        if matches!(path.extension(), Some(ext) if ext != "tiff" && ext != "tif") {
+            log::debug!(
+                "Output path {} has a non-tiff extension; replacing with .tiff",
+                path.display()
+            );
+        }
+
+        let mut path_with_ext = PathBuf::from(path);
+        if !matches!(path.extension(), Some(ext) if ext == "tif") {
+            path_with_ext.set_extension("tiff");
+        }
+
+        let file = File::create(&path_with_ext)?;
+        self.write_to_writer(BufWriter::new(file))
+    }
+
+    pub fn write_to_memory(&self) -> Result<Vec<u8>> {
+        let mut buffer = Vec::new();
+        self.write_to_writer(Cursor::new(&mut buffer))?;
+        Ok(buffer)
+    }
+
+    fn write_to_writer<W: std::io::Write + std::io::Seek>(&self, writer: W) -> Result<()> {
+        let mut encoder = TiffEncoder::new(writer)?;
+        for page in &self.pages {
+            encoder.write_image::<RGBA8>(page.width(), page.height(), page.as_raw())?;
+        }
+        Ok(())
+    }
+
+    pub fn resize(
+        &mut self,
+        target_width: Option<u32>,
+        target_height: Option<u32>,
+        scale: Option<f32>,
+        filter: Filter,
+    ) -> Result<()> {
+        for page in &mut self.pages {
+            let mut image = Image::from_rgba(page.clone());
+            image.resize(target_width, target_height, scale, filter)?;
+            *page = image.buffer;
+        }
+
+        Ok(())
+    }
+
+    pub async fn palettify(&mut self, config: &Config) -> Result<()> {
+        config.validate()?;
+
+        for (i, page) in self.pages.iter_mut().enumerate() {
+            let (width, height) = page.dimensions();
+            log::debug!("Processing TIFF page {i} ({width}x{height})");
+            processing::process_pixels(page.as_mut(), width, height, config).await?;
+        }
+
+        log::debug!("All TIFF pages palettified.");
+        Ok(())
+    }
+}
+
+/// Expands a decoded page to 8-bit RGBA. 16-bit samples keep their high byte and 1, 2 and 4-bit
+/// gray samples are scaled up to 8 bits.
+fn to_rgba(width: u32, height: u32, color: ColorType, data: DecodingResult) -> Result<RgbaImage> {
+    let unsupported = || Error::UnsupportedTiffColor(color);
+
+    let samples = match (color, data) {
+        (ColorType::Gray(bits @ (1 | 2 | 4)), DecodingResult::U8(packed)) => {
+            let max = (1 << bits) - 1;
+            unpack(&packed, width, bits)
+                .map(|s| (u16::from(s) * 255 / max) as u8)
+                .collect()
+        }
+        (_, DecodingResult::U8(samples)) => samples,
+        (_, DecodingResult::U16(samples)) => samples.iter().map(|&s| (s >> 8) as u8).collect(),
+        _ => return Err(unsupported()),
+    };
+
+    let pixels = match color {
+        ColorType::Gray(1 | 2 | 4 | 8 | 16) => {
+            samples.iter().flat_map(|&g| [g, g, g, 255]).collect()
+        }
+        ColorType::GrayA(8 | 16) => samples
+            .chunks_exact(2)
+            .flat_map(|p| [p[0], p[0], p[0], p[1]])
+            .collect(),
+        ColorType::RGB(8 | 16) => samples
+            .chunks_exact(3)
+            .flat_map(|p| [p[0], p[1], p[2], 255])
+            .collect(),
+        ColorType::RGBA(8 | 16) => samples,
+        _ => return Err(unsupported()),
+    };
+
+    RgbaImage::from_raw(width, height, pixels).ok_or_else(unsupported)
+}
+
+/// Decodes the palette page at `index`. tiff rejects palette pages but decodes their indices
+/// when the page is labelled as gray, so the page is relabelled in `bytes` and decoded again,
+/// then its indices are looked up in the ColorMap.
+fn read_palette_page(
+    decoder: &mut Decoder<Cursor<&[u8]>>,
+    bytes: &mut [u8],
+    index: usize,
+) -> Result<RgbaImage> {
+    let (width, height) = decoder.dimensions()?;
+    let colormap = decoder.get_tag_u16_vec(Tag::ColorMap)?;
+    let ifd = decoder.ifd_pointer().ok_or(Error::UnsupportedFormat)?;
+    relabel_as_gray(bytes, ifd.0 as usize).ok_or(Error::UnsupportedFormat)?;
+
+    let mut gray = Decoder::new(Cursor::new(&*bytes))?;
+    gray.seek_to_image(index)?;
+    let (bits, indices): (u8, Vec<usize>) = match (gray.colortype()?, gray.read_image()?) {
+        (ColorType::Gray(bits @ (1 | 2 | 4)), DecodingResult::U8(packed)) => (
+            bits,
+            unpack(&packed, width, bits).map(usize::from).collect(),
+        ),
+        (ColorType::Gray(8), DecodingResult::U8(samples)) => {
+            (8, samples.into_iter().map(usize::from).collect())
+        }
+        (ColorType::Gray(16), DecodingResult::U16(samples)) => {
+            (16, samples.into_iter().map(usize::from).collect())
+        }
+        (ColorType::Gray(bits), _) => {
+            return Err(Error::UnsupportedTiffColor(ColorType::Palette(bits)))
+        }
+        (color, _) => return Err(Error::UnsupportedTiffColor(color)),
+    };
+
+    // The ColorMap holds every red value, then every green, then every blue
+    let entries = 1 << bits;
+    if colormap.len() != 3 * entries {
+        return Err(Error::UnsupportedTiffColor(ColorType::Palette(bits)));
+    }
+    let pixels = indices
+        .into_iter()
+        .flat_map(|i| {
+            let [r, g, b] = [0, 1, 2].map(|c| (colormap[c * entries + i] >> 8) as u8);
+            [r, g, b, 255]
+        })
+        .collect();
+
+    RgbaImage::from_raw(width, height, pixels)
+        .ok_or(Error::UnsupportedTiffColor(ColorType::Palette(bits)))
+}
+
+/// Splits rows of `bits`-bit samples, packed from the high bit down and padded to whole bytes.
+fn unpack(packed: &[u8], width: u32, bits: u8) -> impl Iterator<Item = u8> + '_ {
+    let width = width as usize;
+    let bits = bits as usize;
+    let mask = (1 << bits) - 1;
+
+    packed
+        .chunks_exact((width * bits).div_ceil(8).max(1))
+        .flat_map(move |row| {
+            (0..width).map(move |x| {
+                let bit = x * bits;
+                (row[bit / 8] >> (8 - bits - bit % 8)) & mask
+            })
+        })
+}
+
+/// Sets the PhotometricInterpretation of the IFD at `ifd` to BlackIsZero.
+fn relabel_as_gray(bytes: &mut [u8], ifd: usize) -> Option<()> {
+    let big_endian = bytes.starts_with(b"MM");
+    let read = |bytes: &[u8], at: usize, len: usize| -> Option<u64> {
+        let field = bytes.get(at..at + len)?;
+        let push = |acc: u64, &b: &u8| acc << 8 | u64::from(b);
+        Some(if big_endian {
+            field.iter().fold(0, push)
+        } else {
+            field.iter().rev().fold(0, push)
+        })
+    };
+
+    // BigTIFF (version 43) widens the entry count and each entry's value to 8 bytes
+    let (count, first, entry_len, value_at) = if read(bytes, 2, 2)? == 43 {
+        (read(bytes, ifd, 8)?, ifd + 8, 20, 12)
+    } else {
+        (read(bytes, ifd, 2)?, ifd + 2, 12, 8)
+    };
+    let photometric = u64::from(Tag::PhotometricInterpretation.to_u16());
+    let entry = (0..count as usize)
+        .map(|i| first + i * entry_len)
+        .find(|&entry| read(bytes, entry, 2) == Some(photometric))?;
+
+    let black_is_zero = PhotometricInterpretation::BlackIsZero.to_u16();
+    let value = if big_endian {
+        black_is_zero.to_be_bytes()
+    } else {
+        black_is_zero.to_le_bytes()
+    };
+    bytes
+        .get_mut(entry + value_at..entry + value_at + 2)?
+        .copy_from_slice(&value);
+    Some(())
+}
@@ -1,9 +1,13 @@
 use crate::{
     config::Config,
     error::{Error, Result},
-    processing, Filter,
+    processing,
+    temporal::FrameHistory,
+    Filter,
 };
 
+#[cfg(ffmpeg_6_1)]
+use ffmpeg::ffi::AVPacketSideDataType::AV_PKT_DATA_DISPLAYMATRIX;
 use ffmpeg_next as ffmpeg;
 
 use std::io::{Seek, SeekFrom, Write};
@@ -11,16 +15,69 @@ use std::path::Path;
 use std::time::{SystemTime, UNIX_EPOCH};
 use std::{fs::File, path::PathBuf};
 
+/// What a video carries over from its source besides the picture.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VideoPassthrough {
+    pub audio: bool,
+    pub subtitles: bool,
+    /// Data streams, such as timecodes and chapters
+    pub data: bool,
+    /// Container and stream tags, such as the title, language and creation time
+    pub metadata: bool,
+    /// The display matrix players rotate the picture by. Needs FFmpeg 6.1 or newer.
+    pub rotation: bool,
+}
+
+impl Default for VideoPassthrough {
+    fn default() -> Self {
+        Self {
+            audio: true,
+            subtitles: true,
+            data: true,
+            metadata: true,
+            rotation: true,
+        }
+    }
+}
+
+impl VideoPassthrough {
+    /// Whether streams of `medium` are copied. The picture is never a copied stream.
+    pub fn keeps(&self, medium: ffmpeg::media::Type) -> bool {
+        match medium {
+            ffmpeg::media::Type::Audio => self.audio,
+            ffmpeg::media::Type::Subtitle => self.subtitles,
+            ffmpeg::media::Type::Data => self.data,
+            _ => false,
+        }
+    }
+}
+
+/// A stream remuxed without decoding, such as audio or subtitles.
+#[derive(Clone)]
+struct CopiedStream {
+    codec_params: ffmpeg::codec::Parameters,
+    time_base: ffmpeg::Rational,
+    metadata: Vec<(String, String)>,
+    packets: Vec<ffmpeg::codec::packet::Packet>,
+}
+
 pub struct Video {
     pub width: u32,
     pub height: u32,
     pub framerate: ffmpeg::Rational,
     pub time_base: ffmpeg::Rational,
     pub duration_ts: i64,
+    pub passthrough: VideoPassthrough,
 
     // For decoding
     codec_params: ffmpeg::codec::Parameters,
     packets: Vec<ffmpeg::codec::packet::Packet>,
+
+    // Carried over from the source untouched
+    metadata: Vec<(String, String)>,
+    stream_metadata: Vec<(String, String)>,
+    display_matrix: Option<Vec<u8>>,
+    streams: Vec<CopiedStream>,
 }
 
 impl Clone for Video {
@@ -31,8 +88,13 @@ impl Clone for Video {
             framerate: self.framerate,
             time_base: self.time_base,
             duration_ts: self.duration_ts,
+            passthrough: self.passthrough,
             codec_params: self.codec_params.clone(),
             packets: self.packets.to_vec(),
+            metadata: self.metadata.clone(),
+            stream_metadata: self.stream_metadata.clone(),
+            display_matrix: self.display_matrix.clone(),
+            streams: self.streams.clone(),
         }
     }
 }
@@ -81,17 +143,42 @@ impl Video {
         let framerate = stream.rate();
         let time_base = stream.time_base();
         let duration_ts = stream.duration();
+        let stream_metadata = tags(stream.metadata());
+        let display_matrix = display_matrix(&codec_params);
+
+        let metadata = tags(ictx.metadata());
+        let mut streams = Vec::new();
+        let mut copied_index = vec![None; ictx.nb_streams() as usize];
+        for stream in ictx.streams() {
+            let medium = stream.parameters().medium();
+            if stream.index() == stream_idx
+                || !matches!(
+                    medium,
+                    ffmpeg::media::Type::Audio
+                        | ffmpeg::media::Type::Subtitle
+                        | ffmpeg::media::Type::Data
+                )
+            {
+                continue;
+            }
+            log::debug!("Copying {medium:?} stream {}", stream.index());
+            copied_index[stream.index()] = Some(streams.len());
+            streams.push(CopiedStream {
+                codec_params: stream.parameters(),
+                time_base: stream.time_base(),
+                metadata: tags(stream.metadata()),
+                packets: Vec::new(),
+            });
+        }
 
-        let packets = ictx
-            .packets()
-            .filter_map(|(s, p)| {
-                if s.index() == stream_idx {
-                    Some(p)
-                } else {
-                    None
-                }
-            })
-            .collect::<Vec<_>>();
+        let mut packets = Vec::new();
+        for (s, p) in ictx.packets() {
+            if s.index() == stream_idx {
+                packets.push(p);
+            } else if let Some(i) = copied_index[s.index()] {
+                streams[i].packets.push(p);
+            }
+        }
 
         Ok(Video {
             width,
@@ -99,8 +186,13 @@ impl Video {
             framerate,
             time_base,
             duration_ts,
+            passthrough: VideoPassthrough::default(),
             codec_params,
             packets,
+            metadata,
+            stream_metadata,
+            display_matrix,
+            streams,
         })
     }
 
@@ -117,28 +209,66 @@ impl Video {
         ffmpeg::init()?;
 
         let mut octx = ffmpeg::format::output(&path_with_ext)?;
+        let keep = self.passthrough;
+        if keep.metadata {
+            octx.set_metadata(dictionary(&self.metadata));
+        }
 
-        let stream_mapping = [0];
-        let ist_time_bases = [self.time_base];
+        // Time base and packets of each output stream, the video first
+        let mut sources = vec![(self.time_base, self.packets.as_slice())];
 
         let mut ost = octx.add_stream(
             ffmpeg::encoder::find(self.codec_params.id()).ok_or(Error::StreamNotFound)?,
         )?;
         ost.set_parameters(self.codec_params.clone());
+        if keep.metadata {
+            ost.set_metadata(dictionary(&self.stream_metadata));
+        }
         unsafe {
-            (*ost.parameters().as_mut_ptr()).codec_tag = 0;
+            let params = ost.parameters().as_mut_ptr();
+            (*params).codec_tag = 0;
+            set_display_matrix(
+                params,
+                self.display_matrix.as_deref().filter(|_| keep.rotation),
+            );
+        }
+
+        for stream in &self.streams {
+            let medium = stream.codec_params.medium();
+            if !keep.keeps(medium) {
+                log::debug!("Dropping {medium:?} stream");
+                continue;
+            }
+
+            let id = stream.codec_params.id();
+            let supported = unsafe {
+                ffmpeg::ffi::avformat_query_codec(
+                    octx.format().as_ptr(),
+                    id.into(),
+                    ffmpeg::ffi::FF_COMPLIANCE_NORMAL as _,
+                )
+            } == 1;
+            if !supported {
+                log::warn!("Dropping {medium:?} stream: MP4 can't hold {id:?}");
+                continue;
+            }
+
+            let mut ost = octx.add_stream(ffmpeg::encoder::find(ffmpeg::codec::Id::None))?;
+            ost.set_parameters(stream.codec_params.clone());
+            if keep.metadata {
+                ost.set_metadata(dictionary(&stream.metadata));
+            }
+            unsafe {
+                (*ost.parameters().as_mut_ptr()).codec_tag = 0;
+            }
+            sources.push((stream.time_base, stream.packets.as_slice()));
         }
 
         octx.write_header()?;
 
-        for packet in &self.packets {
-            let mut packet = packet.clone();
-            let ist_index = 0;
-            let ost_index = stream_mapping[ist_index];
-            let ost = octx.stream(ost_index as _).unwrap();
-            packet.rescale_ts(ist_time_bases[ist_index], ost.time_base());
-            packet.set_position(-1);
-            packet.set_stream(ost_index as _);
+        // The muxer may change the time bases while writing the header
+        let time_bases: Vec<_> = octx.streams().map(|stream| stream.time_base()).collect();
+        for packet in interleave(&sources, &time_bases) {
             packet.write_interleaved(&mut octx)?;
         }
 
@@ -169,22 +299,9 @@ impl Video {
         let decoder_ctx = ffmpeg::codec::Context::from_parameters(self.codec_params.clone())?;
         let mut decoder = decoder_ctx.decoder().video()?;
 
-        let encoder_codec = ffmpeg::encoder::find_by_name("libx264rgb").unwrap();
-
-        let encoder_ctx = ffmpeg::codec::Context::new_with_codec(encoder_codec);
-        let mut encoder = encoder_ctx.encoder().video()?;
-
-        encoder.set_width(self.width);
-        encoder.set_height(self.height);
-
         let output_pix_fmt = ffmpeg::format::Pixel::RGB24;
-        encoder.set_format(output_pix_fmt);
-        encoder.set_time_base(self.time_base);
-        encoder.set_frame_rate(Some(self.framerate));
-        encoder.set_colorspace(ffmpeg::color::Space::RGB);
-
-        let opts = ffmpeg::Dictionary::from_iter([("crf", "0")]);
-        let mut encoder = encoder.open_as_with(encoder_codec, opts)?;
+        let mut encoder =
+            Self::rgb_encoder(self.width, self.height, self.time_base, self.framerate)?;
 
         let mut to_rgba_scaler = ffmpeg::software::scaling::context::Context::get(
             decoder.format(),
@@ -207,6 +324,8 @@ impl Video {
         )?;
 
         let mut new_packets = Vec::new();
+        let mut frame_index = 0;
+        let mut history = FrameHistory::default();
         for packet in &self.packets {
             decoder.send_packet(packet)?;
 
@@ -221,7 +340,13 @@ impl Video {
 
                 let mut img_buf = Self::frame_to_img_buf(&rgba_frame)?;
                 let (w, h) = (img_buf.width(), img_buf.height());
-                processing::process_pixels(img_buf.as_mut(), w, h, config).await?;
+                let frame_config = config.for_frame(frame_index);
+                frame_index += 1;
+                let source = config.temporal_coherence.then(|| img_buf.as_raw().clone());
+                processing::process_pixels(img_buf.as_mut(), w, h, &frame_config).await?;
+                if let Some(source) = source {
+                    history.stabilize(&source, img_buf.as_mut(), config);
+                }
                 let processed_rgba_frame = Self::img_buf_to_frame(&img_buf)?;
 
                 let mut output_frame =
@@ -251,6 +376,147 @@ impl Video {
         Ok(())
     }
 
+    /// Lossless RGB H.264 encoder, which every palettified or converted video is written with.
+    fn rgb_encoder(
+        width: u32,
+        height: u32,
+        time_base: ffmpeg::Rational,
+        framerate: ffmpeg::Rational,
+    ) -> Result<ffmpeg::encoder::Video> {
+        let encoder_codec = ffmpeg::encoder::find_by_name("libx264rgb").ok_or_else(|| {
+            Error::Video("FFmpeg was built without the libx264rgb encoder".into())
+        })?;
+
+        let encoder_ctx = ffmpeg::codec::Context::new_with_codec(encoder_codec);
+        let mut encoder = encoder_ctx.encoder().video()?;
+
+        encoder.set_width(width);
+        encoder.set_height(height);
+        encoder.set_format(ffmpeg::format::Pixel::RGB24);
+        encoder.set_time_base(time_base);
+        encoder.set_frame_rate(Some(framerate));
+        encoder.set_colorspace(ffmpeg::color::Space::RGB);
+
+        let opts = ffmpeg::Dictionary::from_iter([("crf", "0")]);
+        Ok(encoder.open_as_with(encoder_codec, opts)?)
+    }
+
+    /// Encodes still frames as a video, timed by their delays.
+    pub fn from_frames(frames: &[image::Frame]) -> Result<Self> {
+        let first = frames
+            .first()
+            .ok_or_else(|| Error::Video("No frames to encode".into()))?;
+        let (width, height) = first.buffer().dimensions();
+        ffmpeg::init()?;
+
+        // Millisecond timestamps keep variable delays exact
+        let time_base = ffmpeg::Rational::new(1, 1000);
+        let total_ms: u32 = frames.iter().map(frame_delay_ms).sum();
+        let average_ms = (total_ms / frames.len() as u32).max(1);
+        let framerate = ffmpeg::Rational::new(1000, average_ms as i32);
+
+        let mut encoder = Self::rgb_encoder(width, height, time_base, framerate)?;
+        let mut to_encoder_scaler = ffmpeg::software::scaling::context::Context::get(
+            ffmpeg::format::Pixel::RGBA,
+            width,
+            height,
+            ffmpeg::format::Pixel::RGB24,
+            width,
+            height,
+            ffmpeg::software::scaling::flag::Flags::POINT,
+        )?;
+
+        let mut packets = Vec::new();
+        let mut pts = 0i64;
+        for frame in frames {
+            let rgba_frame = Self::img_buf_to_frame(frame.buffer())?;
+            let mut output_frame =
+                ffmpeg::util::frame::video::Video::new(ffmpeg::format::Pixel::RGB24, width, height);
+            to_encoder_scaler.run(&rgba_frame, &mut output_frame)?;
+            output_frame.set_pts(Some(pts));
+            pts += frame_delay_ms(frame).max(1) as i64;
+
+            encoder.send_frame(&output_frame)?;
+            let mut encoded_packet = ffmpeg::codec::packet::Packet::empty();
+            while encoder.receive_packet(&mut encoded_packet).is_ok() {
+                packets.push(encoded_packet.clone());
+            }
+        }
+
+        encoder.send_eof()?;
+        let mut encoded_packet = ffmpeg::codec::packet::Packet::empty();
+        while encoder.receive_packet(&mut encoded_packet).is_ok() {
+            packets.push(encoded_packet.clone());
+        }
+
+        Ok(Video {
+            width,
+            height,
+            framerate,
+            time_base: encoder.time_base(),
+            duration_ts: pts,
+            passthrough: VideoPassthrough::default(),
+            codec_params: ffmpeg::codec::Parameters::from(&encoder),
+            packets,
+            metadata: Vec::new(),
+            stream_metadata: Vec::new(),
+            display_matrix: None,
+            streams: Vec::new(),
+        })
+    }
+
+    /// Decodes every frame to RGBA, each shown for one frame interval.
+    pub fn frames(&self) -> Result<Vec<image::Frame>> {
+        ffmpeg::init()?;
+
+        let decoder_ctx = ffmpeg::codec::Context::from_parameters(self.codec_params.clone())?;
+        let mut decoder = decoder_ctx.decoder().video()?;
+        let mut to_rgba_scaler = ffmpeg::software::scaling::context::Context::get(
+            decoder.format(),
+            decoder.width(),
+            decoder.height(),
+            ffmpeg::format::Pixel::RGBA,
+            self.width,
+            self.height,
+            ffmpeg::software::scaling::flag::Flags::POINT,
+        )?;
+
+        let delay = image::Delay::from_numer_denom_ms(
+            1000 * self.framerate.denominator().max(1) as u32,
+            self.framerate.numerator().max(1) as u32,
+        );
+
+        let mut frames = Vec::new();
+        let mut receive = |decoder: &mut ffmpeg::decoder::Video| -> Result<()> {
+            let mut decoded_frame = ffmpeg::util::frame::video::Video::empty();
+            while decoder.receive_frame(&mut decoded_frame).is_ok() {
+                let mut rgba_frame = ffmpeg::util::frame::video::Video::new(
+                    ffmpeg::format::Pixel::RGBA,
+                    self.width,
+                    self.height,
+                );
+                to_rgba_scaler.run(&decoded_frame, &mut rgba_frame)?;
+                frames.push(image::Frame::from_parts(
+                    Self::frame_to_img_buf(&rgba_frame)?,
+                    0,
+                    0,
+                    delay,
+                ));
+            }
+            Ok(())
+        };
+
+        for packet in &self.packets {
+            decoder.send_packet(packet)?;
+            receive(&mut decoder)?;
+        }
+        decoder.send_eof()?;
+        receive(&mut decoder)?;
+
+        log::debug!("Decoded {} video frames", frames.len());
+        Ok(frames)
+    }
+
     pub fn resize(
         &mut self,
         target_width: Option<u32>,
@@ -445,3 +711,154 @@ impl Video {
         Ok(frame)
     }
 }
+
+/// Tags of a container or stream, in their original order.
+fn tags(dictionary: ffmpeg::DictionaryRef<'_>) -> Vec<(String, String)> {
+    dictionary
+        .iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+fn dictionary(tags: &[(String, String)]) -> ffmpeg::Dictionary<'_> {
+    ffmpeg::Dictionary::from_iter(
+        tags.iter()
+            .map(|(key, value)| (key.as_str(), value.as_str())),
+    )
+}
+
+/// The display matrix attached to a stream, which players rotate and flip the picture by.
+#[cfg(ffmpeg_6_1)]
+fn display_matrix(params: &ffmpeg::codec::Parameters) -> Option<Vec<u8>> {
+    unsafe {
+        let params = params.as_ptr();
+        let side_data = ffmpeg::ffi::av_packet_side_data_get(
+            (*params).coded_side_data,
+            (*params).nb_coded_side_data,
+            AV_PKT_DATA_DISPLAYMATRIX,
+        );
+        if side_data.is_null() {
+            return None;
+        }
+        Some(std::slice::from_raw_parts((*side_data).data, (*side_data).size).to_vec())
+    }
+}
+
+/// Replaces the display matrix of an output stream, removing it when `matrix` is `None`.
+///
+/// # Safety
+///
+/// `params` must point to valid codec parameters.
+#[cfg(ffmpeg_6_1)]
+unsafe fn set_display_matrix(params: *mut ffmpeg::ffi::AVCodecParameters, matrix: Option<&[u8]>) {
+    ffmpeg::ffi::av_packet_side_data_remove(
+        (*params).coded_side_data,
+        &mut (*params).nb_coded_side_data,
+        AV_PKT_DATA_DISPLAYMATRIX,
+    );
+
+    let Some(matrix) = matrix else {
+        return;
+    };
+    let side_data = ffmpeg::ffi::av_packet_side_data_new(
+        &mut (*params).coded_side_data,
+        &mut (*params).nb_coded_side_data,
+        AV_PKT_DATA_DISPLAYMATRIX,
+        matrix.len(),
+        0,
+    );
+    if !side_data.is_null() {
+        std::ptr::copy_nonoverlapping(matrix.as_ptr(), (*side_data).data, matrix.len());
+    }
+}
+
+/// FFmpeg before 6.1 keeps side data on the stream rather than its codec parameters, so the
+/// rotation isn't carried over.
+#[cfg(not(ffmpeg_6_1))]
+fn display_matrix(_params: &ffmpeg::codec::Parameters) -> Option<Vec<u8>> {
+    log::debug!("FFmpeg is older than 6.1; not keeping the display matrix");
+    None
+}
+
+/// # Safety
+///
+/// Does nothing, so it is always safe.
+#[cfg(not(ffmpeg_6_1))]
+unsafe fn set_display_matrix(_params: *mut ffmpeg::ffi::AVCodecParameters, _matrix: Option<&[u8]>) {
+}
+
+/// Packets of every output stream in one queue, ordered by time so players don't have to read
+/// ahead. `sources` holds the input time base and packets of each output stream, and `time_bases`
+/// the output time bases they are rescaled to.
+fn interleave(
+    sources: &[(ffmpeg::Rational, &[ffmpeg::codec::packet::Packet])],
+    time_bases: &[ffmpeg::Rational],
+) -> Vec<ffmpeg::codec::packet::Packet> {
+    let mut queue = Vec::new();
+    for (ost_index, (&(ist_time_base, packets), &ost_time_base)) in
+        sources.iter().zip(time_bases).enumerate()
+    {
+        // Packets without timestamps stay right after the previous packet of their stream
+        let mut last_time = 0.0;
+        for packet in packets {
+            let mut packet = packet.clone();
+            packet.rescale_ts(ist_time_base, ost_time_base);
+            packet.set_position(-1);
+            packet.set_stream(ost_index);
+            if let Some(ts) = packet.dts().or(packet.pts()) {
+                last_time = ts as f64 * f64::from(ost_time_base);
+            }
+            queue.push((last_time, packet));
+        }
+    }
+    // Stable, so packets of the same time keep their stream's order
+    queue.sort_by(|a, b| a.0.total_cmp(&b.0));
+    queue.into_iter().map(|(_, packet)| packet).collect()
+}
+
+fn frame_delay_ms(frame: &image::Frame) -> u32 {
+    let (numer, denom) = frame.delay().numer_denom_ms();
+    numer / denom.max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ffmpeg::{codec::packet::Packet, Rational};
+
+    fn packet(ts: Option<i64>) -> Packet {
+        let mut packet = Packet::empty();
+        packet.set_pts(ts);
+        packet.set_dts(ts);
+        packet
+    }
+
+    #[test]
+    fn interleave_orders_streams_by_time() {
+        // Frames every 100ms, and an audio packet every 1024 samples at 48kHz, about 21ms, one of
+        // which lost its timestamps
+        let video: Vec<_> = (0..3).map(|i| packet(Some(i * 100))).collect();
+        let audio: Vec<_> = (0..15)
+            .map(|i| packet((i != 7).then_some(i * 1024)))
+            .collect();
+        let sources = [
+            (Rational::new(1, 1000), video.as_slice()),
+            (Rational::new(1, 48_000), audio.as_slice()),
+        ];
+        let time_bases = [Rational::new(1, 12_800), Rational::new(1, 48_000)];
+
+        let queue = interleave(&sources, &time_bases);
+        let streams: Vec<_> = queue.iter().map(Packet::stream).collect();
+        assert_eq!(
+            streams,
+            [0, 1, 1, 1, 1, 1, 0, 1, 1, 1, 1, 1, 0, 1, 1, 1, 1, 1],
+            "each frame is followed by the audio up to the next one"
+        );
+
+        // Timestamps are rescaled to the output stream, and the untimed packet keeps its place
+        let dts: Vec<_> = queue.iter().map(Packet::dts).collect();
+        assert_eq!(dts[..3], [Some(0), Some(0), Some(1024)]);
+        assert_eq!(dts[6], Some(1280));
+        assert_eq!(dts[8..10], [Some(6 * 1024), None]);
+    }
+}
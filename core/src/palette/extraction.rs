@@ -1,18 +1,23 @@
 use image::Rgb;
 
 use super::Palette;
+#[cfg(feature = "tiff")]
+use crate::media::Tiff;
 use crate::{
     color::{ConvertToLab, Lab},
     error::{Error, Result},
-    media::{Gif, Ico, Image, Media},
+    media::{Animation, Gif, Ico, Image, Media},
 };
 
 impl Palette {
     pub fn from_media(media: &Media, k_colors: usize) -> Result<Self> {
         match media {
+            Media::Animation(anim) => Palette::from_animation(anim, k_colors),
             Media::Gif(gif) => Palette::from_gif(gif, k_colors),
             Media::Ico(ico) => Palette::from_ico(ico, k_colors),
             Media::Image(img) => Palette::from_image(img, k_colors),
+            #[cfg(feature = "tiff")]
+            Media::Tiff(tiff) => Palette::from_tiff(tiff, k_colors),
             #[cfg(feature = "video")]
             &Media::Video(_) => todo!(),
         }
@@ -35,6 +40,23 @@ impl Palette {
             .build())
     }
 
+    pub fn from_animation(animation: &Animation, k_colors: usize) -> Result<Self> {
+        let mut lab_pixels: Vec<Lab> = Vec::new();
+
+        for frame in &animation.frames {
+            for pixel_data in frame.buffer().pixels() {
+                lab_pixels.push(pixel_data.to_lab());
+            }
+        }
+
+        let extracted_colors = Self::extract_colors_from_lab_pixels(&lab_pixels, k_colors)?;
+
+        Ok(Self::builder()
+            .colors(extracted_colors)
+            .source("extracted from animation".to_string())
+            .build())
+    }
+
     pub fn from_ico(ico: &Ico, k_colors: usize) -> Result<Self> {
         let mut lab_pixels: Vec<Lab> = Vec::new();
 
@@ -55,6 +77,24 @@ impl Palette {
             .build())
     }
 
+    #[cfg(feature = "tiff")]
+    pub fn from_tiff(tiff: &Tiff, k_colors: usize) -> Result<Self> {
+        let mut lab_pixels: Vec<Lab> = Vec::new();
+
+        for page in &tiff.pages {
+            for pixel_data in page.pixels() {
+                lab_pixels.push(pixel_data.to_lab());
+            }
+        }
+
+        let extracted_colors = Self::extract_colors_from_lab_pixels(&lab_pixels, k_colors)?;
+
+        Ok(Self::builder()
+            .colors(extracted_colors)
+            .source("extracted from TIFF".to_string())
+            .build())
+    }
+
     pub fn from_image(image: &Image, k_colors: usize) -> Result<Self> {
         let mut lab_pixels: Vec<Lab> = Vec::with_capacity((image.width * image.height) as usize);
         for pixel_data in image.buffer.pixels() {
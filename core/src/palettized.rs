@@ -5,25 +5,33 @@ use serde::{Deserialize, Serialize};
 use tsify::Tsify;
 
 use crate::{
-    color::{ConvertToLab, Lab},
+    blue_noise,
+    color::{self, ConvertToLab, Lab},
     color_difference,
     config::Config,
-    error::Result,
+    error::{Error, Result},
     Mapping,
 };
 
 pub(crate) fn closest_rgb(reference: &Lab, colors: &[Lab], config: &Config) -> Rgb<u8> {
-    let index = color_difference::delta_e_batch(reference, colors, config.diff_formula)
+    config.palette.colors[closest_index(reference, colors, config)]
+}
+
+fn closest_index(reference: &Lab, colors: &[Lab], config: &Config) -> usize {
+    color_difference::delta_e_batch(reference, colors, config.diff_formula, &config.diff_weights)
         .iter()
         .enumerate()
         .min_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal))
         .map(|(index, _)| index)
-        .unwrap();
-
-    config.palette.colors[index]
+        .unwrap()
 }
 
 use rayon::prelude::*;
+use std::collections::HashMap;
+use std::sync::{
+    atomic::{AtomicU32, AtomicUsize, Ordering},
+    Mutex,
+};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 #[cfg_attr(feature = "wasm", derive(Tsify, Serialize, Deserialize))]
@@ -35,112 +43,648 @@ pub enum Dithering {
     Fs,
     /// BlueNoise
     Bn,
+    /// Atkinson (diffuses 3/4 of the error)
+    Atkinson,
+    /// Jarvis-Judice-Ninke
+    Jjn,
+    /// Stucki
+    Stucki,
+    /// Burkes
+    Burkes,
+    /// Sierra (three-row)
+    Sierra,
+    /// Two-row Sierra
+    Sierra2,
+    /// Sierra Lite
+    SierraLite,
+    /// Ordered Bayer matrix
+    Bayer,
+    /// Knoll-Yliluoma pattern dithering
+    Pattern,
 }
 
-pub(crate) fn floyd_steinberg(
+/// Color space in which error-diffusion algorithms accumulate and spread quantization error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "wasm", derive(Tsify, Serialize, Deserialize))]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum, strum_macros::Display))]
+pub enum DitherSpace {
+    /// Gamma-encoded sRGB channel values
+    #[default]
+    Srgb,
+    /// Linear RGB intensities
+    Linear,
+    /// CIE Lab, the space colors are matched in
+    Lab,
+}
+
+/// How out-of-range colors produced by accumulated error are handled during error diffusion.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "wasm", derive(Tsify, Serialize, Deserialize))]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum, strum_macros::Display))]
+pub enum ErrorClamp {
+    /// Clamp the color before matching but diffuse the full error
+    #[default]
+    Value,
+    /// Clamp the color and diffuse only the error of the clamped color
+    Error,
+    /// Clamp the color and cap the diffused error at half the channel range
+    Limit,
+}
+
+/// Quantization of the alpha channel in palettized mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "wasm", derive(Tsify, Serialize, Deserialize))]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum, strum_macros::Display))]
+pub enum AlphaDithering {
+    /// Round alpha to the nearest level
+    #[default]
+    None,
+    /// Ordered Bayer dithering between alpha levels
+    Ordered,
+    /// Floyd-Steinberg error diffusion between alpha levels
+    Diffusion,
+}
+
+/// Attenuation of dithering around edges, to keep text and line art crisp.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "wasm", derive(Tsify, Serialize, Deserialize))]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum, strum_macros::Display))]
+pub enum EdgeAwareness {
+    #[default]
+    None,
+    /// Reduce dithering in proportion to the gradient magnitude
+    Edge,
+    /// Like `Edge`, but only across oriented structures such as strokes and lines, leaving
+    /// texture dithered
+    Structure,
+}
+
+impl ErrorClamp {
+    /// Error left after replacing `value` (clamped to `clamped` for matching) with `target`.
+    #[inline]
+    fn error(self, value: [f32; 3], clamped: [f32; 3], target: [f32; 3], limit: f32) -> [f32; 3] {
+        match self {
+            ErrorClamp::Value => std::array::from_fn(|c| value[c] - target[c]),
+            ErrorClamp::Error => std::array::from_fn(|c| clamped[c] - target[c]),
+            ErrorClamp::Limit => {
+                std::array::from_fn(|c| (value[c] - target[c]).clamp(-limit, limit))
+            }
+        }
+    }
+}
+
+impl Dithering {
+    /// The diffusion kernel, for algorithms that diffuse quantization error.
+    pub(crate) fn kernel(self) -> Option<&'static Kernel> {
+        match self {
+            Dithering::None | Dithering::Bn | Dithering::Bayer | Dithering::Pattern => None,
+            Dithering::Fs => Some(&FLOYD_STEINBERG),
+            Dithering::Atkinson => Some(&ATKINSON),
+            Dithering::Jjn => Some(&JARVIS_JUDICE_NINKE),
+            Dithering::Stucki => Some(&STUCKI),
+            Dithering::Burkes => Some(&BURKES),
+            Dithering::Sierra => Some(&SIERRA),
+            Dithering::Sierra2 => Some(&SIERRA_TWO_ROW),
+            Dithering::SierraLite => Some(&SIERRA_LITE),
+        }
+    }
+}
+
+/// An error-diffusion kernel.
+///
+/// Each tap is `(dx, dy, weight)` relative to the current pixel, in scan direction. Taps on the
+/// current row must point forward (`dx > 0`), and taps may reach at most [`MAX_REACH`] pixels to
+/// either side.
+#[derive(Debug)]
+pub(crate) struct Kernel {
+    taps: &'static [(i32, usize, f32)],
+    divisor: f32,
+    rows: usize,
+}
+
+impl Kernel {
+    /// Taps as `(dx, dy, weight)`, with weights normalized by the divisor.
+    pub(crate) fn weights(&self) -> impl Iterator<Item = (i32, usize, f32)> + '_ {
+        self.taps
+            .iter()
+            .map(|&(dx, dy, weight)| (dx, dy, weight / self.divisor))
+    }
+}
+
+const MAX_REACH: usize = 2;
+
+#[rustfmt::skip]
+static FLOYD_STEINBERG: Kernel = Kernel {
+    taps: &[
+                                (1, 0, 7.0),
+        (-1, 1, 3.0), (0, 1, 5.0), (1, 1, 1.0),
+    ],
+    divisor: 16.0,
+    rows: 1,
+};
+
+#[rustfmt::skip]
+static ATKINSON: Kernel = Kernel {
+    taps: &[
+                                (1, 0, 1.0), (2, 0, 1.0),
+        (-1, 1, 1.0), (0, 1, 1.0), (1, 1, 1.0),
+                      (0, 2, 1.0),
+    ],
+    divisor: 8.0,
+    rows: 2,
+};
+
+#[rustfmt::skip]
+static JARVIS_JUDICE_NINKE: Kernel = Kernel {
+    taps: &[
+                                              (1, 0, 7.0), (2, 0, 5.0),
+        (-2, 1, 3.0), (-1, 1, 5.0), (0, 1, 7.0), (1, 1, 5.0), (2, 1, 3.0),
+        (-2, 2, 1.0), (-1, 2, 3.0), (0, 2, 5.0), (1, 2, 3.0), (2, 2, 1.0),
+    ],
+    divisor: 48.0,
+    rows: 2,
+};
+
+#[rustfmt::skip]
+static STUCKI: Kernel = Kernel {
+    taps: &[
+                                              (1, 0, 8.0), (2, 0, 4.0),
+        (-2, 1, 2.0), (-1, 1, 4.0), (0, 1, 8.0), (1, 1, 4.0), (2, 1, 2.0),
+        (-2, 2, 1.0), (-1, 2, 2.0), (0, 2, 4.0), (1, 2, 2.0), (2, 2, 1.0),
+    ],
+    divisor: 42.0,
+    rows: 2,
+};
+
+#[rustfmt::skip]
+static BURKES: Kernel = Kernel {
+    taps: &[
+                                              (1, 0, 8.0), (2, 0, 4.0),
+        (-2, 1, 2.0), (-1, 1, 4.0), (0, 1, 8.0), (1, 1, 4.0), (2, 1, 2.0),
+    ],
+    divisor: 32.0,
+    rows: 1,
+};
+
+#[rustfmt::skip]
+static SIERRA: Kernel = Kernel {
+    taps: &[
+                                              (1, 0, 5.0), (2, 0, 3.0),
+        (-2, 1, 2.0), (-1, 1, 4.0), (0, 1, 5.0), (1, 1, 4.0), (2, 1, 2.0),
+                      (-1, 2, 2.0), (0, 2, 3.0), (1, 2, 2.0),
+    ],
+    divisor: 32.0,
+    rows: 2,
+};
+
+#[rustfmt::skip]
+static SIERRA_TWO_ROW: Kernel = Kernel {
+    taps: &[
+                                              (1, 0, 4.0), (2, 0, 3.0),
+        (-2, 1, 1.0), (-1, 1, 2.0), (0, 1, 3.0), (1, 1, 2.0), (2, 1, 1.0),
+    ],
+    divisor: 16.0,
+    rows: 1,
+};
+
+#[rustfmt::skip]
+static SIERRA_LITE: Kernel = Kernel {
+    taps: &[
+                      (1, 0, 2.0),
+        (-1, 1, 1.0), (0, 1, 1.0),
+    ],
+    divisor: 4.0,
+    rows: 1,
+};
+
+/// Error carried along the current row: slot `k` holds the error for the pixel `k + 1` steps
+/// ahead in scan direction.
+type Carry = [[f32; 3]; MAX_REACH];
+
+/// Geometry shared by the serial and wavefront error-diffusion schedulers.
+#[derive(Clone, Copy)]
+struct Scan<'a> {
+    kernel: &'a Kernel,
+    width: usize,
+    height: usize,
+    serpentine: bool,
+}
+
+impl Scan<'_> {
+    /// Whether row `y` is scanned right to left.
+    #[inline]
+    fn reversed(&self, y: usize) -> bool {
+        self.serpentine && y % 2 == 1
+    }
+
+    /// Absolute column of the `step`-th pixel visited in row `y`.
+    #[inline]
+    fn column(&self, y: usize, step: usize) -> usize {
+        if self.reversed(y) {
+            self.width - 1 - step
+        } else {
+            step
+        }
+    }
+
+    /// Number of pixels row `source` must have finished before pixel `x` of row `y` has received
+    /// all of its error from that row.
+    fn required_progress(&self, y: usize, x: usize, source: usize) -> usize {
+        let dy = y - source;
+        let source_reversed = self.reversed(source);
+        let mut required = 0;
+        for &(dx, tap_dy, _) in self.kernel.taps {
+            if tap_dy != dy {
+                continue;
+            }
+            let dx = if source_reversed { -dx } else { dx };
+            let sx = x as i64 - dx as i64;
+            if sx < 0 || sx >= self.width as i64 {
+                continue;
+            }
+            let sx = sx as usize;
+            let done = if source_reversed {
+                self.width - sx
+            } else {
+                sx + 1
+            };
+            required = required.max(done);
+        }
+        required
+    }
+
+    /// Distributes a pixel's error: taps on later rows go through `add(dy, index, value)`,
+    /// indexed into an interleaved RGB row, and forward taps on the current row are added to
+    /// `carry`, which is shifted by one pixel.
+    #[inline]
+    fn diffuse(
+        &self,
+        err: [f32; 3],
+        y: usize,
+        x: usize,
+        carry: &mut Carry,
+        mut add: impl FnMut(usize, usize, f32),
+    ) {
+        let reversed = self.reversed(y);
+        carry.rotate_left(1);
+        carry[MAX_REACH - 1] = [0.0; 3];
+
+        for &(dx, dy, weight) in self.kernel.taps {
+            let dx = if reversed { -dx } else { dx };
+            let nx = x as i64 + dx as i64;
+            if nx < 0 || nx >= self.width as i64 || y + dy >= self.height {
+                continue;
+            }
+            let nx = nx as usize;
+            for (c, &e) in err.iter().enumerate() {
+                let value = e * weight / self.kernel.divisor;
+                if dy == 0 {
+                    let slot = nx.abs_diff(x) - 1;
+                    carry[slot][c] += value;
+                } else {
+                    add(dy, nx * 3 + c, value);
+                }
+            }
+        }
+    }
+}
+
+/// Error accumulated for a pixel: the contributions of each earlier row, farthest first,
+/// followed by those carried along the current row.
+#[inline]
+fn gather_error(
+    rows: usize,
+    x: usize,
+    carry: &Carry,
+    load: impl Fn(usize, usize) -> f32,
+) -> [f32; 3] {
+    let mut error = [0.0f32; 3];
+    for (c, e) in error.iter_mut().enumerate() {
+        let mut sum = load(rows, x * 3 + c);
+        for dy in (1..rows).rev() {
+            sum += load(dy, x * 3 + c);
+        }
+        *e = sum + carry[0][c];
+    }
+    error
+}
+
+/// Applies the error-diffusion algorithm selected by `config.dither_algorithm`.
+pub(crate) fn error_diffusion(
     image: &mut RgbaImage,
     config: &Config,
     lab_colors: &[Lab],
+    edges: Option<&[f32]>,
+    locked: Option<&[Option<[u8; 3]>]>,
 ) -> Result<()> {
-    log::debug!(
-        "Applying Floyd-Steinberg dithering with mapping: {:?}",
-        config.mapping
-    );
-    let width = image.width() as usize;
-    let height = image.height() as usize;
-    let strength = config.dither_strength;
-
-    // Error buffers for R, G, B channels (current and next row)
-    let mut error_r_rows: [Vec<f32>; 2] = [vec![0.0; width], vec![0.0; width]];
-    let mut error_g_rows: [Vec<f32>; 2] = [vec![0.0; width], vec![0.0; width]];
-    let mut error_b_rows: [Vec<f32>; 2] = [vec![0.0; width], vec![0.0; width]];
-
-    for y in 0..height {
-        let current_row = y % 2;
-        let next_row = (y + 1) % 2;
-
-        // Clear next row's error buffer
-        if y + 1 < height {
-            error_r_rows[next_row].fill(0.0);
-            error_g_rows[next_row].fill(0.0);
-            error_b_rows[next_row].fill(0.0);
-        }
+    let Some(kernel) = config.dither_algorithm.kernel() else {
+        return Err(Error::Internal(format!(
+            "{:?} is not an error-diffusion algorithm",
+            config.dither_algorithm
+        )));
+    };
 
-        for x in 0..width {
-            let px = image.get_pixel(x as u32, y as u32);
-
-            // Make pixel fully transparent if below threshold
-            if px.0[3] < config.transparency_threshold
-                && !(config.mapping == Mapping::Smoothed
-                    && config.dither_algorithm != Dithering::None)
-            {
-                image.put_pixel(x as u32, y as u32, Rgba([0, 0, 0, 0]));
-                error_r_rows[current_row][x] = 0.0;
-                error_g_rows[current_row][x] = 0.0;
-                error_b_rows[current_row][x] = 0.0;
-                continue;
-            }
+    let scan = Scan {
+        kernel,
+        width: image.width() as usize,
+        height: image.height() as usize,
+        serpentine: config.dither_serpentine,
+    };
+    if scan.width == 0 || scan.height == 0 {
+        return Ok(());
+    }
 
-            // Choose the color to which error will be added
-            let target_rgb: [u8; 3] = [px.0[0], px.0[1], px.0[2]];
+    let num_threads = config.num_threads.max(1).min(scan.height);
 
-            // Add error to RGB components
-            let r_mod = target_rgb[0] as f32 + error_r_rows[current_row][x];
-            let g_mod = target_rgb[1] as f32 + error_g_rows[current_row][x];
-            let b_mod = target_rgb[2] as f32 + error_b_rows[current_row][x];
+    // Threads are unavailable on wasm, so it always takes the serial path
+    if num_threads > 1 && !cfg!(target_arch = "wasm32") {
+        log::debug!(
+            "Applying {:?} dithering ({num_threads} threads, serpentine: {}) with mapping: {:?}",
+            config.dither_algorithm,
+            scan.serpentine,
+            config.mapping
+        );
+        error_diffusion_wavefront(image, config, lab_colors, edges, locked, scan, num_threads)
+    } else {
+        log::debug!(
+            "Applying {:?} dithering (serpentine: {}) with mapping: {:?}",
+            config.dither_algorithm,
+            scan.serpentine,
+            config.mapping
+        );
+        error_diffusion_serial(image, config, lab_colors, edges, locked, scan)
+    }
+}
+
+/// Share of the error a pixel receives and emits: reduced on edges, and none at all for locked
+/// pixels, whose output is replaced by their locked color afterwards.
+#[inline]
+pub(crate) fn error_share(
+    edges: Option<&[f32]>,
+    locked: Option<&[Option<[u8; 3]>]>,
+    index: usize,
+) -> f32 {
+    if locked.is_some_and(|locked| locked[index].is_some()) {
+        return 0.0;
+    }
+    edges.map_or(1.0, |edges| 1.0 - edges[index])
+}
+
+/// Quantizes a single pixel after adding the diffused error to it.
+///
+/// Both the received and the emitted error are scaled by `keep` (`1.0` minus the pixel's edge
+/// strength), so little error crosses strong edges.
+///
+/// Returns the output pixel and the (strength-scaled) error to distribute, or `None` if the
+/// pixel is below the transparency threshold and should be cleared without diffusing anything.
+#[inline]
+fn quantize_with_error(
+    px: [u8; 4],
+    error: [f32; 3],
+    keep: f32,
+    config: &Config,
+    lab_colors: &[Lab],
+) -> Option<([u8; 4], [f32; 3])> {
+    // Make pixel fully transparent if below threshold
+    if px[3] < config.transparency_threshold
+        && !(config.mapping == Mapping::Smoothed && config.dither_algorithm != Dithering::None)
+    {
+        return None;
+    }
 
-            let r = r_mod.clamp(0.0, 255.0) as u8;
-            let g = g_mod.clamp(0.0, 255.0) as u8;
-            let b = b_mod.clamp(0.0, 255.0) as u8;
+    // Set alpha
+    let alpha = if config.mapping == Mapping::Smoothed {
+        px[3]
+    } else {
+        255
+    };
+
+    let strength = config.dither_strength * keep;
+    let error = error.map(|e| e * keep);
+    let (index, error) = match config.dither_space {
+        DitherSpace::Srgb => {
+            // Add error to RGB components
+            let value = std::array::from_fn(|c| px[c] as f32 + error[c]);
+            let clamped = value.map(|v| v.clamp(0.0, 255.0));
 
             // Quantize to palette
-            let lab = Rgba([r, g, b, px.0[3]]).to_lab();
-            let quantized = closest_rgb(&lab, lab_colors, config);
+            let lab = Rgba([clamped[0] as u8, clamped[1] as u8, clamped[2] as u8, px[3]]).to_lab();
+            let index = closest_index(&lab, lab_colors, config);
+            let target = config.palette.colors[index].0.map(|v| v as f32);
 
-            // Set alpha
-            let alpha = if config.mapping == Mapping::Smoothed {
-                px.0[3]
-            } else {
-                255
+            let error = config.dither_clamp.error(value, clamped, target, 127.5);
+            (index, error)
+        }
+        DitherSpace::Linear => {
+            let value = std::array::from_fn(|c| color::srgb_to_linear(px[c]) + error[c]);
+            let clamped = value.map(|v| v.clamp(0.0, 1.0));
+
+            let index = closest_index(&color::linear_to_lab(clamped), lab_colors, config);
+            let target = config.palette.colors[index].0.map(color::srgb_to_linear);
+
+            let error = config.dither_clamp.error(value, clamped, target, 0.5);
+            (index, error)
+        }
+        DitherSpace::Lab => {
+            let lab = Rgba(px).to_lab();
+            let value = [lab.l + error[0], lab.a + error[1], lab.b + error[2]];
+            let clamped = [
+                value[0].clamp(0.0, 100.0),
+                value[1].clamp(-128.0, 127.0),
+                value[2].clamp(-128.0, 127.0),
+            ];
+
+            let reference = Lab {
+                l: clamped[0],
+                a: clamped[1],
+                b: clamped[2],
             };
+            let index = closest_index(&reference, lab_colors, config);
+            let target = [
+                lab_colors[index].l,
+                lab_colors[index].a,
+                lab_colors[index].b,
+            ];
 
-            image.put_pixel(
-                x as u32,
-                y as u32,
-                Rgba([quantized.0[0], quantized.0[1], quantized.0[2], alpha]),
-            );
-
-            // Calculate error
-            let err_r = (r_mod - quantized.0[0] as f32) * strength;
-            let err_g = (g_mod - quantized.0[1] as f32) * strength;
-            let err_b = (b_mod - quantized.0[2] as f32) * strength;
-
-            // Distribute error (Floyd-Steinberg)
-            if x + 1 < width {
-                error_r_rows[current_row][x + 1] += err_r * 7.0 / 16.0;
-                error_g_rows[current_row][x + 1] += err_g * 7.0 / 16.0;
-                error_b_rows[current_row][x + 1] += err_b * 7.0 / 16.0;
-            }
-            if x > 0 && y + 1 < height {
-                error_r_rows[next_row][x - 1] += err_r * 3.0 / 16.0;
-                error_g_rows[next_row][x - 1] += err_g * 3.0 / 16.0;
-                error_b_rows[next_row][x - 1] += err_b * 3.0 / 16.0;
-            }
-            if y + 1 < height {
-                error_r_rows[next_row][x] += err_r * 5.0 / 16.0;
-                error_g_rows[next_row][x] += err_g * 5.0 / 16.0;
-                error_b_rows[next_row][x] += err_b * 5.0 / 16.0;
-            }
-            if x + 1 < width && y + 1 < height {
-                error_r_rows[next_row][x + 1] += err_r * 1.0 / 16.0;
-                error_g_rows[next_row][x + 1] += err_g * 1.0 / 16.0;
-                error_b_rows[next_row][x + 1] += err_b * 1.0 / 16.0;
+            let error = config.dither_clamp.error(value, clamped, target, 50.0);
+            (index, error)
+        }
+    };
+
+    let quantized = config.palette.colors[index];
+    Some((
+        [quantized.0[0], quantized.0[1], quantized.0[2], alpha],
+        error.map(|e| e * strength),
+    ))
+}
+
+fn error_diffusion_serial(
+    image: &mut RgbaImage,
+    config: &Config,
+    lab_colors: &[Lab],
+    edges: Option<&[f32]>,
+    locked: Option<&[Option<[u8; 3]>]>,
+    scan: Scan,
+) -> Result<()> {
+    let width = scan.width;
+    let rows = scan.kernel.rows;
+
+    // Error received by upcoming rows, one interleaved RGB plane per source distance:
+    // `pending[i][dy - 1]` holds what row `y + i` received from row `y + i - dy`. Keeping the
+    // planes apart makes every pixel sum its error in the same order as the wavefront scheduler,
+    // so both paths are bit-identical.
+    let mut pending: Vec<Vec<Vec<f32>>> = (0..=rows)
+        .map(|_| (0..rows).map(|_| vec![0.0; width * 3]).collect())
+        .collect();
+
+    for (y, row) in image.as_mut().chunks_exact_mut(width * 4).enumerate() {
+        let (current, below) = pending.split_first_mut().unwrap();
+
+        let mut carry: Carry = [[0.0; 3]; MAX_REACH];
+        for step in 0..width {
+            let x = scan.column(y, step);
+            let i = x * 4;
+            let px = [row[i], row[i + 1], row[i + 2], row[i + 3]];
+            let error = gather_error(rows, x, &carry, |dy, j| current[dy - 1][j]);
+
+            let keep = error_share(edges, locked, y * width + x);
+            match quantize_with_error(px, error, keep, config, lab_colors) {
+                Some((out, err)) => {
+                    row[i..i + 4].copy_from_slice(&out);
+                    scan.diffuse(err, y, x, &mut carry, |dy, j, v| {
+                        below[dy - 1][dy - 1][j] += v;
+                    });
+                }
+                None => {
+                    row[i..i + 4].copy_from_slice(&[0, 0, 0, 0]);
+                    carry.rotate_left(1);
+                    carry[MAX_REACH - 1] = [0.0; 3];
+                }
             }
         }
+
+        pending.rotate_left(1);
+        for plane in pending.last_mut().unwrap() {
+            plane.fill(0.0);
+        }
     }
     Ok(())
 }
 
+/// Row-lagged wavefront schedule for error diffusion.
+///
+/// Each row is handled by a single worker and may quantize a pixel once every earlier row the
+/// kernel reaches from has finished the pixels that feed it. Since each error buffer entry
+/// receives its contributions in the same order as in the serial scan, the output is
+/// bit-identical to [`error_diffusion_serial`].
+///
+/// Rows are claimed strictly in order from a shared iterator, so a worker only ever waits on rows
+/// that are already being processed. Rayon is deliberately avoided here: work stealing could park
+/// every worker on a row whose predecessor was never scheduled.
+fn error_diffusion_wavefront(
+    image: &mut RgbaImage,
+    config: &Config,
+    lab_colors: &[Lab],
+    edges: Option<&[f32]>,
+    locked: Option<&[Option<[u8; 3]>]>,
+    scan: Scan,
+    num_threads: usize,
+) -> Result<()> {
+    let width = scan.width;
+    let height = scan.height;
+    let rows = scan.kernel.rows;
+
+    // Row y reads ring[y % n] and writes ring[(y + dy) % n]; spare buffers per worker mean a
+    // buffer is only reused once the row that consumed it has finished.
+    let ring_len = num_threads + rows + 1;
+    let ring: Vec<Vec<Vec<AtomicU32>>> = (0..ring_len)
+        .map(|_| {
+            (0..rows)
+                .map(|_| (0..width * 3).map(|_| AtomicU32::new(0)).collect())
+                .collect()
+        })
+        .collect();
+    let progress: Vec<AtomicUsize> = (0..height).map(|_| AtomicUsize::new(0)).collect();
+
+    let image_rows = Mutex::new(image.as_mut().chunks_exact_mut(width * 4).enumerate());
+
+    std::thread::scope(|scope| {
+        for _ in 0..num_threads {
+            scope.spawn(|| loop {
+                let Some((y, row)) = image_rows.lock().unwrap_or_else(|e| e.into_inner()).next()
+                else {
+                    break;
+                };
+
+                let current = &ring[y % ring_len];
+
+                // Row y is the only writer of plane dy - 1 of row y + dy
+                for dy in 1..=rows {
+                    let target = y + dy;
+                    if target >= height {
+                        break;
+                    }
+                    if let Some(prev_user) = target.checked_sub(ring_len) {
+                        wait_for(&progress[prev_user], width);
+                    }
+                    for e in &ring[target % ring_len][dy - 1] {
+                        e.store(0, Ordering::Relaxed);
+                    }
+                }
+
+                let mut carry: Carry = [[0.0; 3]; MAX_REACH];
+                for step in 0..width {
+                    let x = scan.column(y, step);
+                    for dy in 1..=rows.min(y) {
+                        let source = y - dy;
+                        wait_for(&progress[source], scan.required_progress(y, x, source));
+                    }
+
+                    let i = x * 4;
+                    let px = [row[i], row[i + 1], row[i + 2], row[i + 3]];
+                    let error = gather_error(rows, x, &carry, |dy, j| {
+                        f32::from_bits(current[dy - 1][j].load(Ordering::Relaxed))
+                    });
+
+                    let keep = error_share(edges, locked, y * width + x);
+                    match quantize_with_error(px, error, keep, config, lab_colors) {
+                        Some((out, err)) => {
+                            row[i..i + 4].copy_from_slice(&out);
+                            scan.diffuse(err, y, x, &mut carry, |dy, j, v| {
+                                let e = &ring[(y + dy) % ring_len][dy - 1][j];
+                                let sum = f32::from_bits(e.load(Ordering::Relaxed)) + v;
+                                e.store(sum.to_bits(), Ordering::Relaxed);
+                            });
+                        }
+                        None => {
+                            row[i..i + 4].copy_from_slice(&[0, 0, 0, 0]);
+                            carry.rotate_left(1);
+                            carry[MAX_REACH - 1] = [0.0; 3];
+                        }
+                    }
+
+                    progress[y].store(step + 1, Ordering::Release);
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
+
+#[inline]
+fn wait_for(progress: &AtomicUsize, target: usize) {
+    let mut spins = 0u32;
+    while progress.load(Ordering::Acquire) < target {
+        if spins < 64 {
+            std::hint::spin_loop();
+            spins += 1;
+        } else {
+            std::thread::yield_now();
+        }
+    }
+}
+
 pub const BLUE_NOISE_64X64: [u8; 4096] = [
     65, 247, 203, 177, 54, 149, 96, 135, 122, 62, 109, 206, 27, 217, 152, 103, 250, 78, 122, 228,
     3, 83, 233, 160, 45, 242, 108, 40, 125, 93, 201, 35, 231, 187, 254, 207, 147, 13, 87, 134, 246,
@@ -343,11 +887,64 @@ pub const BLUE_NOISE_64X64: [u8; 4096] = [
     119, 37, 73, 227, 17, 108, 159, 216, 125, 233, 181, 99, 38, 118, 58, 137, 71, 251, 29, 133,
 ];
 
-pub(crate) fn blue_noise(image: &mut RgbaImage, config: &Config, lab_colors: &[Lab]) -> Result<()> {
+pub(crate) fn blue_noise(
+    image: &mut RgbaImage,
+    config: &Config,
+    lab_colors: &[Lab],
+    edges: Option<&[f32]>,
+) -> Result<()> {
+    let texture = blue_noise::Texture::for_config(config);
     log::debug!(
-        "Applying Blue Noise dithering (64x64) with mapping: {:?}",
+        "Applying Blue Noise dithering ({0}x{0}) with mapping: {1:?}",
+        texture.size,
         config.mapping
     );
+
+    apply_threshold_map(image, config, lab_colors, edges, |x, y| {
+        texture.sample(x, y)
+    });
+
+    Ok(())
+}
+
+pub(crate) fn bayer(
+    image: &mut RgbaImage,
+    config: &Config,
+    lab_colors: &[Lab],
+    edges: Option<&[f32]>,
+) -> Result<()> {
+    let size = config.dither_matrix_size as usize;
+    log::debug!(
+        "Applying Bayer dithering ({size}x{size}) with mapping: {:?}",
+        config.mapping
+    );
+
+    let ranks = bayer_matrix(size);
+    let levels = (size * size) as f32;
+    let thresholds: Vec<f32> = ranks
+        .iter()
+        .map(|&rank| ((rank as f32 + 0.5) / levels - 0.5) * 255.0)
+        .collect();
+
+    apply_threshold_map(image, config, lab_colors, edges, |x, y| {
+        [thresholds[(y % size) * size + (x % size)]; 3]
+    });
+
+    Ok(())
+}
+
+/// Offsets every opaque pixel by the per-channel `noise(x, y) * dither_strength`, attenuated by its
+/// edge strength, and quantizes the result to the palette. Shared by the ordered (threshold map)
+/// algorithms.
+fn apply_threshold_map<F>(
+    image: &mut RgbaImage,
+    config: &Config,
+    lab_colors: &[Lab],
+    edges: Option<&[f32]>,
+    noise: F,
+) where
+    F: Fn(usize, usize) -> [f32; 3] + Sync,
+{
     let width = image.width() as usize;
     let bytes_per_pixel = 4;
 
@@ -373,16 +970,19 @@ pub(crate) fn blue_noise(image: &mut RgbaImage, config: &Config, lab_colors: &[L
                     continue;
                 }
 
-                let mask = BLUE_NOISE_64X64[(y % 64) * 64 + (x % 64)] as f32;
-                let noise = (mask - 127.5) * config.dither_strength;
+                let strength = match edges {
+                    Some(edges) => config.dither_strength * (1.0 - edges[y * width + x]),
+                    None => config.dither_strength,
+                };
+                let noise = noise(x, y).map(|n| n * strength);
 
                 // Choose the color to which noise will be applied
                 let target_rgb: [u8; 3] = [px.0[0], px.0[1], px.0[2]];
 
-                // Add blue noise to each channel and clamp
-                let r = (target_rgb[0] as f32 + noise).clamp(0.0, 255.0) as u8;
-                let g = (target_rgb[1] as f32 + noise).clamp(0.0, 255.0) as u8;
-                let b = (target_rgb[2] as f32 + noise).clamp(0.0, 255.0) as u8;
+                // Add noise to each channel and clamp
+                let r = (target_rgb[0] as f32 + noise[0]).clamp(0.0, 255.0) as u8;
+                let g = (target_rgb[1] as f32 + noise[1]).clamp(0.0, 255.0) as u8;
+                let b = (target_rgb[2] as f32 + noise[2]).clamp(0.0, 255.0) as u8;
 
                 // Quantize to palette
                 let lab = Rgba([r, g, b, px.0[3]]).to_lab();
@@ -394,6 +994,141 @@ pub(crate) fn blue_noise(image: &mut RgbaImage, config: &Config, lab_colors: &[L
                 row[i + 3] = 255;
             }
         });
+}
+
+/// Rank matrix (`0..size * size`) of a `size`x`size` Bayer matrix, row-major. `size` must be a
+/// power of two.
+pub(crate) fn bayer_matrix(size: usize) -> Vec<u32> {
+    let mut matrix = vec![0u32];
+    let mut n = 1;
+
+    while n < size {
+        let next = n * 2;
+        let mut expanded = vec![0u32; next * next];
+        for y in 0..next {
+            for x in 0..next {
+                let quadrant = match (y / n, x / n) {
+                    (0, 0) => 0,
+                    (0, _) => 2,
+                    (_, 0) => 3,
+                    _ => 1,
+                };
+                expanded[y * next + x] = 4 * matrix[(y % n) * n + (x % n)] + quadrant;
+            }
+        }
+        matrix = expanded;
+        n = next;
+    }
+
+    matrix
+}
+
+/// Number of palette entries mixed per pixel by pattern dithering.
+const PATTERN_CANDIDATES: usize = 16;
+
+struct MixingPlan {
+    /// Palette indices sorted by lightness.
+    candidates: [u8; PATTERN_CANDIDATES],
+    /// Palette index closest to the source color.
+    nearest: u8,
+}
+
+/// Knoll-Yliluoma pattern dithering. For each source color, a mixing plan of palette entries is
+/// built by repeatedly quantizing the source plus the accumulated error, the plan is sorted by
+/// lightness, and the Bayer matrix picks which entry of the plan each pixel shows.
+pub(crate) fn pattern(
+    image: &mut RgbaImage,
+    config: &Config,
+    lab_colors: &[Lab],
+    edges: Option<&[f32]>,
+) -> Result<()> {
+    let size = config.dither_matrix_size as usize;
+    log::debug!(
+        "Applying pattern dithering ({size}x{size}) with mapping: {:?}",
+        config.mapping
+    );
+
+    let ranks = bayer_matrix(size);
+    let levels = size * size;
+    let width = image.width() as usize;
+    let bytes_per_pixel = 4;
+
+    image
+        .as_mut()
+        .par_chunks_mut(width * bytes_per_pixel)
+        .enumerate()
+        .for_each_init(
+            || HashMap::<[u8; 3], MixingPlan>::with_capacity(1024),
+            |plans, (y, row)| {
+                for x in 0..width {
+                    let i = x * bytes_per_pixel;
+
+                    if row[i + 3] < config.transparency_threshold {
+                        row[i..i + 4].fill(0);
+                        continue;
+                    }
+
+                    let source = [row[i], row[i + 1], row[i + 2]];
+                    let plan = plans
+                        .entry(source)
+                        .or_insert_with(|| mixing_plan(source, config, lab_colors));
+
+                    let rank = ranks[(y % size) * size + (x % size)] as usize;
+
+                    // On edges, a share of the pixels matching the edge strength shows the
+                    // nearest color instead of its plan entry
+                    let on_edge = edges.is_some_and(|edges| {
+                        (rank as f32 + 0.5) / (levels as f32) < edges[y * width + x]
+                    });
+                    let index = if on_edge {
+                        plan.nearest
+                    } else {
+                        plan.candidates[rank * PATTERN_CANDIDATES / levels]
+                    };
+                    let color = config.palette.colors[index as usize];
+
+                    row[i] = color.0[0];
+                    row[i + 1] = color.0[1];
+                    row[i + 2] = color.0[2];
+                    row[i + 3] = 255;
+                }
+            },
+        );
 
     Ok(())
 }
+
+fn mixing_plan(source: [u8; 3], config: &Config, lab_colors: &[Lab]) -> MixingPlan {
+    let mut candidates = [0u8; PATTERN_CANDIDATES];
+    let mut error = [0.0f32; 3];
+
+    for slot in candidates.iter_mut() {
+        let attempt: [u8; 3] = std::array::from_fn(|c| {
+            (source[c] as f32 + error[c] * config.dither_strength).clamp(0.0, 255.0) as u8
+        });
+        let index = closest_index(
+            &Rgba([attempt[0], attempt[1], attempt[2], 255]).to_lab(),
+            lab_colors,
+            config,
+        );
+        let chosen = config.palette.colors[index];
+        for c in 0..3 {
+            error[c] += source[c] as f32 - chosen.0[c] as f32;
+        }
+        *slot = index as u8;
+    }
+
+    // Nothing has accumulated yet when the first candidate is picked
+    let nearest = candidates[0];
+
+    candidates.sort_by(|&a, &b| {
+        lab_colors[a as usize]
+            .l
+            .partial_cmp(&lab_colors[b as usize].l)
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    MixingPlan {
+        candidates,
+        nearest,
+    }
+}
@@ -1,9 +1,14 @@
 use crate::{
+    alpha,
     color::{ConvertToLab, Lab},
     config::Config,
+    edges,
     error::{Error, Result},
+    gradient_map, lightness,
+    locks::{self, LockedPixels},
+    mask, matte,
     palettized::{self, Dithering},
-    smoothed, Mapping,
+    selection, smoothed, Mapping,
 };
 
 use image::{Rgb, Rgba, RgbaImage};
@@ -124,6 +129,12 @@ pub fn generate_lookup_table(
     lab_colors: &[Lab],
     image_size: Option<usize>,
 ) -> Vec<Rgb<u8>> {
+    // Gradient maps only look at lightness, which is cheaper than a lookup table
+    if config.mapping == Mapping::GradientMap {
+        log::debug!("Skipping LUT generation: Gradient maps don't use one.");
+        return Vec::new();
+    }
+
     // Dithering operates pixel by pixel with error diffusion; LUT is not used
     if config.dither_algorithm != Dithering::None {
         log::debug!("Skipping LUT generation: Dithering algorithm is active.");
@@ -208,6 +219,7 @@ pub(crate) fn compute_mapped_color_rgb(
     match config.mapping {
         Mapping::Palettized => palettized::closest_rgb(&reference, lab_colors, config),
         Mapping::Smoothed => smoothed::closest_rgb(&reference, lab_colors, config),
+        Mapping::GradientMap => gradient_map::closest_rgb(&reference, lab_colors, config),
     }
 }
 
@@ -353,43 +365,137 @@ fn process_non_dithered_pixels(
     Ok(())
 }
 
-pub(crate) fn process_pixels_cpu(
+pub(crate) fn map_pixels_cpu(
     image_data: &mut [u8],
     width: u32,
     height: u32,
     config: &Config,
     lab_colors: &[Lab],
     lookup: Option<&[Rgb<u8>]>,
+    locked: Option<&[Option<[u8; 3]>]>,
 ) -> Result<()> {
     if config.mapping == Mapping::Smoothed {
         return process_non_dithered_pixels(image_data, width, height, config, lab_colors, lookup);
     }
 
+    if config.mapping == Mapping::GradientMap {
+        let edges = edges::edge_weights(image_data, width, height, config);
+        return gradient_map::map(
+            image_data,
+            width,
+            config,
+            lab_colors,
+            edges.as_deref(),
+            locked,
+        );
+    }
+
+    if config.dither_algorithm == Dithering::None {
+        return process_non_dithered_pixels(image_data, width, height, config, lab_colors, lookup);
+    }
+
+    let edges = edges::edge_weights(image_data, width, height, config);
+    let edges = edges.as_deref();
+
+    let mut image = RgbaImage::from_raw(width, height, image_data.to_vec()).ok_or_else(|| {
+        Error::Internal("Failed to create image view from buffer for dithering".to_string())
+    })?;
+    match config.dither_algorithm {
+        Dithering::Bn => palettized::blue_noise(&mut image, config, lab_colors, edges)?,
+        Dithering::Bayer => palettized::bayer(&mut image, config, lab_colors, edges)?,
+        Dithering::Pattern => palettized::pattern(&mut image, config, lab_colors, edges)?,
+        _ => palettized::error_diffusion(&mut image, config, lab_colors, edges, locked)?,
+    }
+    image_data.copy_from_slice(image.as_raw());
+    Ok(())
+}
+
+/// Whether the GPU compute pipeline implements everything the configuration asks for.
+#[cfg(feature = "gpu")]
+fn gpu_supports(config: &Config) -> bool {
+    // The shaders only know the plain formulas
+    if config.diff_weights != crate::color_difference::Weights::NEUTRAL {
+        return false;
+    }
+
+    match config.mapping {
+        // The shaders always blend the whole palette
+        Mapping::Smoothed => {
+            return config.smooth_neighbors.is_none() && config.smooth_cutoff.is_none()
+        }
+        Mapping::GradientMap => return false,
+        Mapping::Palettized => {}
+    }
+
+    if config.dither_edges != palettized::EdgeAwareness::None
+        && config.dither_algorithm != Dithering::None
+    {
+        return false;
+    }
+
     match config.dither_algorithm {
-        Dithering::None => {
-            process_non_dithered_pixels(image_data, width, height, config, lab_colors, lookup)
+        Dithering::None => true,
+        // The shader only knows the built-in texture, sampled identically on every channel
+        Dithering::Bn => {
+            config.blue_noise_size.is_none()
+                && !config.blue_noise_per_channel
+                && !config.blue_noise_animated
         }
         Dithering::Fs => {
-            let mut image =
-                RgbaImage::from_raw(width, height, image_data.to_vec()).ok_or_else(|| {
-                    Error::Internal(
-                        "Failed to create image view from buffer for dithering".to_string(),
-                    )
-                })?;
-            palettized::floyd_steinberg(&mut image, config, lab_colors)?;
-            image_data.copy_from_slice(image.as_raw());
-            Ok(())
+            !config.dither_serpentine
+                && config.dither_space == palettized::DitherSpace::Srgb
+                && config.dither_clamp == palettized::ErrorClamp::Value
         }
-        Dithering::Bn => {
-            let mut image =
-                RgbaImage::from_raw(width, height, image_data.to_vec()).ok_or_else(|| {
-                    Error::Internal(
-                        "Failed to create image view from buffer for dithering".to_string(),
-                    )
-                })?;
-            palettized::blue_noise(&mut image, config, lab_colors)?;
-            image_data.copy_from_slice(image.as_raw());
-            Ok(())
+        _ => false,
+    }
+}
+
+/// State carried around the color mapping itself, shared by the GPU and CPU paths.
+struct Prepared {
+    /// Source pixels and how much of the mapping each keeps, when restricted by a mask or
+    /// selection
+    original: Option<(Vec<u8>, Vec<f32>)>,
+    source_alpha: Option<Vec<u8>>,
+    source_lightness: Option<Vec<f32>>,
+    locked: Option<LockedPixels>,
+}
+
+impl Prepared {
+    fn new(image_data: &mut [u8], width: u32, height: u32, config: &Config) -> Self {
+        let weights = match (
+            mask::weights(config, width, height),
+            selection::weights(image_data, config),
+        ) {
+            (Some(mask), Some(selection)) => {
+                Some(mask.iter().zip(&selection).map(|(m, s)| m * s).collect())
+            }
+            (mask, selection) => mask.or(selection),
+        };
+        let original = weights.map(|weights| (image_data.to_vec(), weights));
+        matte::apply(image_data, config);
+        let source_alpha = alpha::detach(image_data, config);
+        let source_lightness = lightness::capture(image_data, config);
+        let locked = locks::resolve(image_data, config);
+        Self {
+            original,
+            source_alpha,
+            source_lightness,
+            locked,
+        }
+    }
+
+    fn finish(self, image_data: &mut [u8], width: u32, config: &Config) {
+        if let Some(source_lightness) = &self.source_lightness {
+            lightness::restore(image_data, source_lightness);
+        }
+        if let Some(locked) = &self.locked {
+            locks::restore(image_data, locked);
+        }
+        if let Some(source_alpha) = &self.source_alpha {
+            alpha::attach(image_data, source_alpha, width, config);
+        }
+        if let Some((original, weights)) = &self.original {
+            mask::blend(image_data, original, weights);
         }
     }
 }
@@ -400,24 +506,96 @@ pub async fn process_pixels(
     height: u32,
     config: &Config,
 ) -> Result<()> {
+    let prepared = Prepared::new(image_data, width, height, config);
+
     #[cfg(feature = "gpu")]
-    if let Ok(gpu_processor) = get_gpu_processor().await {
-        log::debug!("Processing with GPU");
-        let result = gpu_processor
-            .process_image(image_data, width, height, config)
-            .await?;
-
-        if image_data.len() == result.len() {
-            image_data.copy_from_slice(&result);
-        } else {
-            log::error!("GPU output buffer size mismatch.");
-            return Err(Error::Internal(
-                "GPU output buffer size mismatch".to_string(),
-            ));
-        }
-        return Ok(());
+    let mapped = process_pixels_gpu(image_data, width, height, config, &prepared).await?;
+    #[cfg(not(feature = "gpu"))]
+    let mapped = false;
+
+    if !mapped {
+        map_colors_cpu(
+            image_data,
+            width,
+            height,
+            config,
+            prepared.locked.as_deref(),
+        )?;
     }
 
+    prepared.finish(image_data, width, config);
+    Ok(())
+}
+
+/// Maps the pixels on the GPU if the configuration allows it and a GPU is available. Returns
+/// whether the pixels were mapped.
+#[cfg(feature = "gpu")]
+async fn process_pixels_gpu(
+    image_data: &mut [u8],
+    width: u32,
+    height: u32,
+    config: &Config,
+    prepared: &Prepared,
+) -> Result<bool> {
+    // Locked pixels are put back after mapping, which only holds up when they don't feed error
+    // to their neighbors
+    let diffuses =
+        config.mapping == Mapping::Palettized && config.dither_algorithm.kernel().is_some();
+    if !gpu_supports(config) || (prepared.locked.is_some() && diffuses) {
+        log::debug!("Configuration not supported by the GPU pipeline, processing on CPU");
+        return Ok(false);
+    }
+
+    let Ok(gpu_processor) = get_gpu_processor().await else {
+        return Ok(false);
+    };
+
+    log::debug!("Processing with GPU");
+    let result = gpu_processor
+        .process_image(image_data, width, height, config)
+        .await?;
+
+    if image_data.len() == result.len() {
+        image_data.copy_from_slice(&result);
+    } else {
+        log::error!("GPU output buffer size mismatch.");
+        return Err(Error::Internal(
+            "GPU output buffer size mismatch".to_string(),
+        ));
+    }
+    Ok(true)
+}
+
+/// Same as [`process_pixels`], but always runs on the CPU, even when a GPU is available.
+///
+/// It needs no async runtime and gives the same bytes on every machine, where the GPU pipeline
+/// may round differently. Use it when the output has to be reproducible, such as for caching or
+/// comparing results.
+pub fn process_pixels_cpu(
+    image_data: &mut [u8],
+    width: u32,
+    height: u32,
+    config: &Config,
+) -> Result<()> {
+    let prepared = Prepared::new(image_data, width, height, config);
+    map_colors_cpu(
+        image_data,
+        width,
+        height,
+        config,
+        prepared.locked.as_deref(),
+    )?;
+    prepared.finish(image_data, width, config);
+    Ok(())
+}
+
+fn map_colors_cpu(
+    image_data: &mut [u8],
+    width: u32,
+    height: u32,
+    config: &Config,
+    locked: Option<&[Option<[u8; 3]>]>,
+) -> Result<()> {
     let lab_colors = config
         .palette
         .colors
@@ -432,12 +610,13 @@ pub async fn process_pixels(
         None
     };
 
-    process_pixels_cpu(
+    map_pixels_cpu(
         image_data,
         width,
         height,
         config,
         &lab_colors,
         lookup_table.as_deref(),
+        locked,
     )
 }
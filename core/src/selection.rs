@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+
+use image::Rgb;
+use rayon::prelude::*;
+#[cfg(feature = "wasm")]
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "wasm")]
+use tsify::Tsify;
+
+use crate::{
+    color::ConvertToLab,
+    config::Config,
+    error::{Error, Result},
+};
+
+/// Limits mapping to pixels whose LCh color falls within the given ranges. Unset ranges select
+/// everything.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(feature = "wasm", derive(Tsify, Serialize, Deserialize))]
+#[cfg_attr(feature = "wasm", serde(rename_all = "camelCase", default))]
+pub struct Selection {
+    /// Hue range in degrees, wrapping around through 0 when the start is past the end (e.g.
+    /// `[300, 60]`)
+    pub hue: Option<[f32; 2]>,
+    pub chroma: Option<[f32; 2]>,
+    /// Lightness range, from 0 (black) to 100 (white)
+    pub lightness: Option<[f32; 2]>,
+    /// Width of the soft transition outside of each range, in degrees for hue and Lab units for
+    /// chroma and lightness. 0 makes a hard cut.
+    pub falloff: f32,
+}
+
+/// Below this chroma hue is too unstable to select on, so nearly gray pixels fade out of hue
+/// selections.
+const HUE_CHROMA: f32 = 4.0;
+
+impl Selection {
+    pub(crate) fn validate(&self) -> Result<()> {
+        let ranges = [self.hue, self.chroma, self.lightness];
+        if ranges
+            .iter()
+            .flatten()
+            .flatten()
+            .any(|value| !value.is_finite())
+        {
+            return Err(Error::InvalidSelection("ranges must be finite"));
+        }
+
+        for [start, end] in [self.chroma, self.lightness].into_iter().flatten() {
+            if start > end {
+                return Err(Error::InvalidSelection(
+                    "chroma and lightness ranges must not end before they start",
+                ));
+            }
+        }
+
+        if self.falloff.is_nan() || self.falloff < 0.0 {
+            return Err(Error::InvalidSelection("falloff must not be negative"));
+        }
+
+        Ok(())
+    }
+
+    /// How strongly a color is selected, between 0 and 1.
+    fn weight(&self, rgb: [u8; 3]) -> f32 {
+        let lab = Rgb(rgb).to_lab();
+        let chroma = lab.a.hypot(lab.b);
+        let mut weight = 1.0;
+
+        if let Some(range) = self.lightness {
+            weight *= self.falloff(linear_distance(lab.l, range));
+        }
+        if let Some(range) = self.chroma {
+            weight *= self.falloff(linear_distance(chroma, range));
+        }
+        if let Some(range) = self.hue {
+            let hue = lab.b.atan2(lab.a).to_degrees().rem_euclid(360.0);
+            weight *= self.falloff(hue_distance(hue, range)) * (chroma / HUE_CHROMA).min(1.0);
+        }
+
+        weight
+    }
+
+    fn falloff(&self, distance: f32) -> f32 {
+        if distance <= 0.0 {
+            1.0
+        } else if distance >= self.falloff {
+            0.0
+        } else {
+            let t = 1.0 - distance / self.falloff;
+            t * t * (3.0 - 2.0 * t)
+        }
+    }
+}
+
+fn linear_distance(value: f32, [start, end]: [f32; 2]) -> f32 {
+    (start - value).max(value - end).max(0.0)
+}
+
+fn hue_distance(hue: f32, [start, end]: [f32; 2]) -> f32 {
+    if end - start >= 360.0 {
+        return 0.0;
+    }
+
+    let span = (end - start).rem_euclid(360.0);
+    let offset = (hue - start).rem_euclid(360.0);
+    if offset <= span {
+        0.0
+    } else {
+        // Outside of the arc, so whichever end is closer around the circle
+        (offset - span).min(360.0 - offset)
+    }
+}
+
+/// Selection weight of every pixel, or `None` when no selection is configured.
+pub(crate) fn weights(image_data: &[u8], config: &Config) -> Option<Vec<f32>> {
+    let selection = config.selection.as_ref()?;
+
+    Some(
+        image_data
+            .par_chunks_exact(4)
+            .map_init(HashMap::<[u8; 3], f32>::new, |cache, px| {
+                let rgb = [px[0], px[1], px[2]];
+                *cache.entry(rgb).or_insert_with(|| selection.weight(rgb))
+            })
+            .collect(),
+    )
+}
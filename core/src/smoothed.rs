@@ -89,14 +89,48 @@ fn compute_weight(distance: f32, config: &Config) -> f32 {
 }
 
 pub(crate) fn closest_rgb(reference: &Lab, colors: &[Lab], config: &Config) -> Rgb<u8> {
+    let distance = |color: &Lab| {
+        color_difference::delta_e(color, reference, config.diff_formula, &config.diff_weights)
+    };
+
+    if config.smooth_neighbors.is_none() && config.smooth_cutoff.is_none() {
+        return blend(colors.iter().map(|color| (distance(color), color)), config);
+    }
+
+    // NaN distances never pass the weight threshold of the full blend, so they can't be nearest
+    let mut neighbors: Vec<(f32, &Lab)> = colors
+        .iter()
+        .map(|c| (distance(c), c))
+        .filter(|(d, _)| !d.is_nan())
+        .collect();
+    // Partitioning around the k-th nearest is linear, unlike sorting the whole palette
+    if let Some(k) = config.smooth_neighbors {
+        if k > 0 && k < neighbors.len() {
+            neighbors.select_nth_unstable_by(k - 1, |a, b| a.0.total_cmp(&b.0));
+            neighbors.truncate(k);
+        }
+    }
+    // The nearest color always takes part, even past the cutoff
+    if let Some(cutoff) = config.smooth_cutoff {
+        let nearest = neighbors.iter().copied().min_by(|a, b| a.0.total_cmp(&b.0));
+        neighbors.retain(|(d, _)| *d <= cutoff);
+        if neighbors.is_empty() {
+            neighbors.extend(nearest);
+        }
+    }
+
+    blend(neighbors.into_iter(), config)
+}
+
+/// Weighted average of the given colors by their distance to the reference.
+fn blend<'a>(neighbors: impl Iterator<Item = (f32, &'a Lab)>, config: &Config) -> Rgb<u8> {
     const WEIGHT_THRESHOLD: f32 = 1e-9;
     let mut total_weight: f32 = 0.0;
     let mut sum_l: f32 = 0.0;
     let mut sum_a: f32 = 0.0;
     let mut sum_b: f32 = 0.0;
 
-    for color in colors {
-        let distance = color_difference::delta_e(color, reference, config.diff_formula);
+    for (distance, color) in neighbors {
         let weight = compute_weight(distance, config);
 
         if weight > WEIGHT_THRESHOLD {
@@ -0,0 +1,48 @@
+use crate::config::Config;
+
+/// Per-pixel memory of an animation, used to keep unchanged regions identical between frames.
+///
+/// Every pixel remembers the source color it was last freshly mapped from and the color it was
+/// mapped to. As long as later frames keep that pixel within `temporal_threshold` of the
+/// remembered source, the remembered output is reused instead of the newly dithered one, so
+/// static backgrounds stop shimmering and consecutive frames stay byte-identical there.
+#[derive(Debug, Default)]
+pub(crate) struct FrameHistory {
+    source: Vec<u8>,
+    output: Vec<u8>,
+}
+
+impl FrameHistory {
+    /// Replaces stable pixels of the freshly processed `output` with their previous mapping, and
+    /// records the new mapping of the pixels that did change.
+    ///
+    /// `source` is the frame before processing; both buffers are RGBA. A frame whose size differs
+    /// from the previous one resets the history.
+    pub(crate) fn stabilize(&mut self, source: &[u8], output: &mut [u8], config: &Config) {
+        if self.source.len() != source.len() {
+            self.source = source.to_vec();
+            self.output = output.to_vec();
+            return;
+        }
+
+        let threshold = config.temporal_threshold;
+        for (((src, anchor_src), out), anchor_out) in source
+            .chunks_exact(4)
+            .zip(self.source.chunks_exact_mut(4))
+            .zip(output.chunks_exact_mut(4))
+            .zip(self.output.chunks_exact_mut(4))
+        {
+            let stable = src
+                .iter()
+                .zip(anchor_src.iter())
+                .all(|(&a, &b)| a.abs_diff(b) <= threshold);
+
+            if stable {
+                out.copy_from_slice(anchor_out);
+            } else {
+                anchor_src.copy_from_slice(src);
+                anchor_out.copy_from_slice(out);
+            }
+        }
+    }
+}
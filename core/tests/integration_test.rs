@@ -1,6 +1,18 @@
 extern crate palettum;
 
-use palettum::{find_palette, Config};
+use image::Rgb;
+
+use palettum::{
+    color_difference::{Preserve, Weights},
+    find_palette,
+    media::{
+        animated_format, Animation, AnimationFormat, ExtensionBlock, IcoEncoding, MetadataMode,
+        PngCompression, PngFilter, STANDARD_ICON_SIZES,
+    },
+    palettized::{AlphaDithering, DitherSpace, Dithering, EdgeAwareness, ErrorClamp},
+    process_pixels_cpu, Config, Filter, Gif, GifOptimization, Ico, Image, Mapping, Mask, MatteMode,
+    Media, OutputFormat, Palette, PngOptions, Selection,
+};
 
 #[test]
 fn test_config_builder() {
@@ -8,3 +20,1542 @@ fn test_config_builder() {
         .palette(find_palette("gruvbox").unwrap())
         .build();
 }
+
+fn gradient(width: u32, height: u32) -> Vec<u8> {
+    let mut data = Vec::with_capacity((width * height * 4) as usize);
+    for y in 0..height {
+        for x in 0..width {
+            let r = (x * 255 / width.max(1)) as u8;
+            let g = (y * 255 / height.max(1)) as u8;
+            let b = ((x + y) * 7 % 256) as u8;
+            // Sprinkle in transparent pixels to exercise the threshold path
+            let a = if (x * 31 + y * 17) % 53 == 0 { 0 } else { 255 };
+            data.extend_from_slice(&[r, g, b, a]);
+        }
+    }
+    data
+}
+
+fn dither_with_threads(
+    dither: Dithering,
+    serpentine: bool,
+    threads: usize,
+    width: u32,
+    height: u32,
+) -> Vec<u8> {
+    let config = Config::builder()
+        .palette(find_palette("gruvbox").unwrap())
+        .mapping(Mapping::Palettized)
+        .dither_algorithm(dither)
+        .dither_strength(0.8)
+        .dither_serpentine(serpentine)
+        .num_threads(threads)
+        .build();
+    let mut data = gradient(width, height);
+    process_pixels_cpu(&mut data, width, height, &config).unwrap();
+    data
+}
+
+#[test]
+fn test_parallel_error_diffusion_matches_serial() {
+    let kernels = [
+        Dithering::Fs,
+        Dithering::Atkinson,
+        Dithering::Jjn,
+        Dithering::Stucki,
+        Dithering::Burkes,
+        Dithering::Sierra,
+        Dithering::Sierra2,
+        Dithering::SierraLite,
+    ];
+    for dither in kernels {
+        for serpentine in [false, true] {
+            for (width, height) in [(97, 61), (1, 40), (40, 1), (3, 3)] {
+                let serial = dither_with_threads(dither, serpentine, 1, width, height);
+                for threads in [2, 3, 8] {
+                    let parallel = dither_with_threads(dither, serpentine, threads, width, height);
+                    assert!(
+                        serial == parallel,
+                        "{dither:?} (serpentine: {serpentine}) with {threads} threads differs from serial output at {width}x{height}"
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_dither_matrix_size_validation() {
+    for dither in [Dithering::Bayer, Dithering::Pattern] {
+        for (size, valid) in [
+            (1, false),
+            (2, true),
+            (3, false),
+            (8, true),
+            (16, true),
+            (32, false),
+        ] {
+            let config = Config::builder()
+                .palette(find_palette("gruvbox").unwrap())
+                .mapping(Mapping::Palettized)
+                .dither_algorithm(dither)
+                .dither_matrix_size(size)
+                .build();
+            assert_eq!(
+                config.validate().is_ok(),
+                valid,
+                "{dither:?} with size {size}"
+            );
+        }
+    }
+}
+
+#[test]
+fn test_ordered_dithering_uses_palette_colors() {
+    let palette = find_palette("gruvbox").unwrap();
+    for dither in [Dithering::Bayer, Dithering::Pattern] {
+        for size in [2, 4, 8, 16] {
+            let config = Config::builder()
+                .palette(palette.clone())
+                .mapping(Mapping::Palettized)
+                .dither_algorithm(dither)
+                .dither_matrix_size(size)
+                .build();
+            let mut data = gradient(64, 48);
+            process_pixels_cpu(&mut data, 64, 48, &config).unwrap();
+            for px in data.chunks_exact(4).filter(|px| px[3] != 0) {
+                assert!(
+                    palette.colors.iter().any(|c| c.0 == [px[0], px[1], px[2]]),
+                    "{dither:?} ({size}x{size}) produced {px:?}"
+                );
+            }
+        }
+    }
+}
+
+fn blue_noise(size: Option<u32>, seed: u32, per_channel: bool) -> Vec<u8> {
+    let config = Config::builder()
+        .palette(find_palette("gruvbox").unwrap())
+        .mapping(Mapping::Palettized)
+        .dither_algorithm(Dithering::Bn)
+        .maybe_blue_noise_size(size)
+        .blue_noise_seed(seed)
+        .blue_noise_per_channel(per_channel)
+        .build();
+    config.validate().unwrap();
+    let mut data = gradient(80, 40);
+    process_pixels_cpu(&mut data, 80, 40, &config).unwrap();
+    data
+}
+
+#[test]
+fn test_generated_blue_noise() {
+    let generated = blue_noise(Some(32), 7, false);
+    assert_eq!(generated, blue_noise(Some(32), 7, false));
+    assert_ne!(generated, blue_noise(Some(32), 8, false));
+    assert_ne!(generated, blue_noise(Some(32), 7, true));
+    assert_ne!(blue_noise(None, 0, false), blue_noise(None, 0, true));
+
+    for size in [0, 2, 48, 512] {
+        let config = Config::builder()
+            .palette(find_palette("gruvbox").unwrap())
+            .blue_noise_size(size)
+            .build();
+        assert!(config.validate().is_err(), "size {size} should be rejected");
+    }
+}
+
+#[test]
+fn test_error_diffusion_spaces() {
+    let palette = find_palette("gruvbox").unwrap();
+    for space in [DitherSpace::Srgb, DitherSpace::Linear, DitherSpace::Lab] {
+        for clamp in [ErrorClamp::Value, ErrorClamp::Error, ErrorClamp::Limit] {
+            let run = |threads| {
+                let config = Config::builder()
+                    .palette(palette.clone())
+                    .mapping(Mapping::Palettized)
+                    .dither_algorithm(Dithering::Fs)
+                    .dither_space(space)
+                    .dither_clamp(clamp)
+                    .num_threads(threads)
+                    .build();
+                let mut data = gradient(61, 47);
+                process_pixels_cpu(&mut data, 61, 47, &config).unwrap();
+                data
+            };
+
+            let serial = run(1);
+            assert_eq!(
+                serial,
+                run(4),
+                "{space:?}/{clamp:?} parallel output differs"
+            );
+            for px in serial.chunks_exact(4).filter(|px| px[3] != 0) {
+                assert!(
+                    palette.colors.iter().any(|c| c.0 == [px[0], px[1], px[2]]),
+                    "{space:?}/{clamp:?} produced {px:?}"
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn test_temporal_coherence_reuses_stable_pixels() {
+    let (width, height) = (16, 8);
+    // The second frame nudges every pixel within the threshold, except the first row which
+    // changes completely
+    let first = gradient(width, height);
+    let mut second = first.clone();
+    for (i, px) in second.chunks_exact_mut(4).enumerate() {
+        if i < width as usize {
+            px[..3].iter_mut().for_each(|c| *c = 255 - *c);
+        } else {
+            px[0] = px[0].saturating_add(3);
+        }
+    }
+
+    let palettify = |temporal_coherence: bool| {
+        let delay = image::Delay::from_numer_denom_ms(100, 1);
+        let frames = [&first, &second]
+            .into_iter()
+            .map(|data| {
+                let buffer = image::RgbaImage::from_raw(width, height, data.clone()).unwrap();
+                image::Frame::from_parts(buffer, 0, 0, delay)
+            })
+            .collect();
+        let mut gif = Gif::from_frames(frames, width, height, 0);
+        let config = Config::builder()
+            .palette(find_palette("gruvbox").unwrap())
+            .mapping(Mapping::Palettized)
+            .dither_algorithm(Dithering::Fs)
+            .temporal_coherence(temporal_coherence)
+            .temporal_threshold(4)
+            .build();
+        futures::executor::block_on(gif.palettify(&config)).unwrap();
+        gif.frames
+            .into_iter()
+            .map(|frame| frame.into_buffer().into_raw())
+            .collect::<Vec<_>>()
+    };
+
+    let row = 4 * width as usize;
+    let frames = palettify(true);
+    assert_eq!(
+        frames[1][row..],
+        frames[0][row..],
+        "stable pixels reuse the previous mapping"
+    );
+    assert_ne!(
+        frames[1][..row],
+        frames[0][..row],
+        "changed pixels are remapped"
+    );
+
+    // Dithering alone maps the nudged pixels differently
+    let frames = palettify(false);
+    assert_ne!(frames[1][row..], frames[0][row..]);
+}
+
+#[test]
+fn test_edge_aware_dithering_keeps_edges_crisp() {
+    let (width, height) = (32u32, 16u32);
+    let mut source = Vec::new();
+    for _ in 0..height {
+        for x in 0..width {
+            let v = if x < width / 2 { 40 } else { 200 };
+            source.extend_from_slice(&[v, v + 7, v + 3, 255]);
+        }
+    }
+
+    let run = |dither, edges| {
+        let config = Config::builder()
+            .palette(find_palette("gruvbox").unwrap())
+            .mapping(Mapping::Palettized)
+            .dither_algorithm(dither)
+            .dither_strength(1.0)
+            .dither_edges(edges)
+            .build();
+        let mut data = source.clone();
+        process_pixels_cpu(&mut data, width, height, &config).unwrap();
+        data
+    };
+
+    let nearest = run(Dithering::None, EdgeAwareness::None);
+    for dither in [
+        Dithering::Fs,
+        Dithering::Stucki,
+        Dithering::Bn,
+        Dithering::Bayer,
+        Dithering::Pattern,
+    ] {
+        for edges in [EdgeAwareness::Edge, EdgeAwareness::Structure] {
+            let output = run(dither, edges);
+            for y in 0..height {
+                for x in [width / 2 - 1, width / 2] {
+                    let i = ((y * width + x) * 4) as usize;
+                    assert_eq!(
+                        output[i..i + 4],
+                        nearest[i..i + 4],
+                        "{dither:?}/{edges:?} dithered the edge at ({x}, {y})"
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_alpha_levels_survive_indexed_png() {
+    let (width, height) = (48u32, 8u32);
+    let palette = find_palette("gruvbox").unwrap();
+    let mut data = Vec::new();
+    for _ in 0..height {
+        for x in 0..width {
+            data.extend_from_slice(&[200, 120, 40, (x * 255 / (width - 1)) as u8]);
+        }
+    }
+
+    for alpha_dither in [
+        AlphaDithering::None,
+        AlphaDithering::Ordered,
+        AlphaDithering::Diffusion,
+    ] {
+        let config = Config::builder()
+            .palette(palette.clone())
+            .mapping(Mapping::Palettized)
+            .alpha_dither(alpha_dither)
+            .alpha_levels(vec![128])
+            .build();
+        let mut buffer = data.clone();
+        process_pixels_cpu(&mut buffer, width, height, &config).unwrap();
+
+        let alphas: Vec<u8> = buffer.chunks_exact(4).map(|px| px[3]).collect();
+        assert!(alphas.iter().all(|a| [0, 128, 255].contains(a)));
+        assert!(
+            alphas.contains(&128),
+            "{alpha_dither:?} dropped the partial level"
+        );
+
+        let mut colors = palette.colors.clone();
+        colors.push(image::Rgb([0, 0, 0]));
+        let image = Image {
+            palette: Some(colors),
+            ..Image::from_rgba(image::RgbaImage::from_raw(width, height, buffer).unwrap())
+        };
+        let png = image.write_to_memory().unwrap();
+        let decoded = image::load_from_memory(&png).unwrap().into_rgba8();
+        assert_eq!(decoded, image.buffer);
+    }
+}
+
+#[test]
+fn test_matte_prepares_pixels_before_mapping() {
+    let run = |pixels: &[[u8; 4]], matte, mode, opaque| {
+        let config = Config::builder()
+            .palette(find_palette("gruvbox").unwrap())
+            .mapping(Mapping::Palettized)
+            .transparency_threshold(1)
+            .maybe_matte(matte)
+            .matte_mode(mode)
+            .force_opaque(opaque)
+            .build();
+        let mut data = pixels.concat();
+        process_pixels_cpu(&mut data, pixels.len() as u32, 1, &config).unwrap();
+        data
+    };
+
+    // Half-transparent red over white is a light red
+    let white = Some([255, 255, 255]);
+    assert_eq!(
+        run(&[[255, 0, 0, 128]], white, MatteMode::Composite, false),
+        run(&[[255, 127, 127, 128]], None, MatteMode::Composite, false),
+    );
+
+    // A dark fringe left by blending red over black is red again once un-premultiplied
+    let black = Some([0, 0, 0]);
+    assert_eq!(
+        run(&[[128, 0, 0, 128]], black, MatteMode::Unpremultiply, false),
+        run(&[[255, 0, 0, 128]], None, MatteMode::Composite, false),
+    );
+
+    // Forcing opacity fills transparent pixels with the matte
+    let opaque = run(
+        &[[0, 0, 0, 0], [255, 0, 0, 128]],
+        white,
+        MatteMode::Composite,
+        true,
+    );
+    assert!(opaque.chunks_exact(4).all(|px| px[3] == 255));
+    assert_eq!(
+        opaque,
+        run(
+            &[[255, 255, 255, 255], [255, 127, 127, 255]],
+            None,
+            MatteMode::Composite,
+            false
+        ),
+    );
+}
+
+#[test]
+fn test_locked_colors_bypass_mapping() {
+    let (width, height) = (40u32, 30u32);
+    let mut source = gradient(width, height);
+    for (i, px) in source.chunks_exact_mut(4).enumerate() {
+        match i % 7 {
+            0 => px.copy_from_slice(&[250, 10, 10, 255]),
+            3 => px.copy_from_slice(&[10, 250, 10, 255]),
+            _ => {}
+        }
+    }
+
+    let configs = [
+        (Mapping::Palettized, Dithering::None, 0),
+        (Mapping::Palettized, Dithering::None, 2),
+        (Mapping::Palettized, Dithering::Fs, 0),
+        (Mapping::Palettized, Dithering::Bn, 0),
+        (Mapping::Palettized, Dithering::Pattern, 0),
+        (Mapping::Smoothed, Dithering::None, 0),
+    ];
+    for (mapping, dither, quant_level) in configs {
+        let config = Config::builder()
+            .palette(find_palette("gruvbox").unwrap())
+            .mapping(mapping)
+            .dither_algorithm(dither)
+            .quant_level(quant_level)
+            .protected_colors(vec![[250, 10, 10]])
+            .color_remap(vec![([10, 250, 10], [1, 2, 3])])
+            .build();
+        let mut data = source.clone();
+        process_pixels_cpu(&mut data, width, height, &config).unwrap();
+
+        for (i, px) in data.chunks_exact(4).enumerate() {
+            let expected = match i % 7 {
+                0 => [250, 10, 10],
+                3 => [1, 2, 3],
+                _ => continue,
+            };
+            assert_eq!(
+                px[..3],
+                expected,
+                "{mapping:?}/{dither:?}/q{quant_level} pixel {i}"
+            );
+        }
+    }
+}
+
+#[test]
+fn test_mask_restricts_mapping() {
+    let (width, height) = (32u32, 32u32);
+    let source = gradient(width, height);
+    let palette = find_palette("gruvbox").unwrap();
+    let in_palette = |px: &[u8]| px[3] == 0 || palette.colors.iter().any(|c| c.0 == px[..3]);
+    let rect = Mask::Rect {
+        x: 8,
+        y: 8,
+        width: 16,
+        height: 16,
+    };
+    let inside = |i: usize| (8..24).contains(&(i % 32)) && (8..24).contains(&(i / 32));
+
+    let config = Config::builder()
+        .palette(palette.clone())
+        .mapping(Mapping::Palettized)
+        .mask(rect.clone())
+        .build();
+    let mut data = source.clone();
+    process_pixels_cpu(&mut data, width, height, &config).unwrap();
+    for (i, (px, src)) in data.chunks_exact(4).zip(source.chunks_exact(4)).enumerate() {
+        if inside(i) {
+            assert!(in_palette(px), "pixel {i} inside the mask was not mapped");
+        } else {
+            assert_eq!(px, src, "pixel {i} outside the mask changed");
+        }
+    }
+
+    // A feathered, inverted polygon covering the same square blends along its edges
+    let polygon = Mask::Polygon {
+        points: vec![[8.0, 8.0], [24.0, 8.0], [24.0, 24.0], [8.0, 24.0]],
+    };
+    let config = Config::builder()
+        .palette(palette.clone())
+        .mapping(Mapping::Palettized)
+        .mask(polygon)
+        .mask_invert(true)
+        .mask_feather(6.0)
+        .build();
+    let mut data = source.clone();
+    process_pixels_cpu(&mut data, width, height, &config).unwrap();
+    let px = |x: usize, y: usize| &data[(y * 32 + x) * 4..(y * 32 + x) * 4 + 4];
+    assert_eq!(
+        px(16, 16),
+        &source[(16 * 32 + 16) * 4..(16 * 32 + 16) * 4 + 4]
+    );
+    assert!(in_palette(px(0, 0)));
+    assert!((6..10).any(
+        |x| px(x, 16) != &source[(16 * 32 + x) * 4..(16 * 32 + x) * 4 + 4]
+            && !in_palette(px(x, 16))
+    ));
+
+    let empty = Config::builder()
+        .palette(palette)
+        .mask(Mask::Polygon { points: vec![] })
+        .build();
+    assert!(empty.validate().is_err());
+}
+
+#[test]
+fn test_selection_limits_mapped_colors() {
+    let colors: [[u8; 4]; 4] = [
+        [30, 200, 40, 255],   // green
+        [40, 120, 50, 255],   // darker green
+        [220, 40, 40, 255],   // red
+        [128, 128, 128, 255], // gray
+    ];
+    let source: Vec<u8> = colors.iter().flatten().copied().collect();
+    let palette = find_palette("gruvbox").unwrap();
+
+    let greens = Selection {
+        hue: Some([100.0, 160.0]),
+        ..Default::default()
+    };
+    let config = Config::builder()
+        .palette(palette.clone())
+        .mapping(Mapping::Palettized)
+        .selection(greens)
+        .build();
+    let mut data = source.clone();
+    process_pixels_cpu(&mut data, 4, 1, &config).unwrap();
+    assert_ne!(data[0..4], source[0..4]);
+    assert_ne!(data[4..8], source[4..8]);
+    assert_eq!(data[8..16], source[8..16]);
+
+    // Only the shadows, and wrapping hue ranges select through 0 degrees
+    let shadows = Selection {
+        lightness: Some([0.0, 50.0]),
+        hue: Some([300.0, 160.0]),
+        ..Default::default()
+    };
+    let config = Config::builder()
+        .palette(palette.clone())
+        .mapping(Mapping::Palettized)
+        .selection(shadows)
+        .build();
+    let mut data = source.clone();
+    process_pixels_cpu(&mut data, 4, 1, &config).unwrap();
+    assert_eq!(data[0..4], source[0..4]);
+    assert_ne!(data[4..8], source[4..8]);
+    assert_ne!(data[8..12], source[8..12]);
+    assert_eq!(data[12..16], source[12..16]);
+
+    let inverted = Config::builder()
+        .palette(palette)
+        .selection(Selection {
+            chroma: Some([50.0, 10.0]),
+            ..Default::default()
+        })
+        .build();
+    assert!(inverted.validate().is_err());
+}
+
+#[test]
+fn test_gradient_map_follows_lightness() {
+    let (width, height) = (64u32, 4u32);
+    let mut source = Vec::new();
+    for _ in 0..height {
+        for x in 0..width {
+            let v = (x * 255 / (width - 1)) as u8;
+            source.extend_from_slice(&[v, v, v, 255]);
+        }
+    }
+    let stops = [[10, 20, 80], [250, 200, 40], [120, 40, 40]];
+    let palette = Palette::builder()
+        .colors(stops.iter().map(|&c| Rgb(c)).collect())
+        .build();
+    let map = |config: Config| {
+        let mut data = source.clone();
+        process_pixels_cpu(&mut data, width, height, &config).unwrap();
+        data
+    };
+
+    // Ordered by lightness: darkest stop, then the middle one, then the lightest
+    let data = map(Config::builder()
+        .palette(palette.clone())
+        .mapping(Mapping::GradientMap)
+        .build());
+    assert_eq!(data[..3], stops[0]);
+    assert_eq!(data[32 * 4..32 * 4 + 3], stops[2]);
+    assert_eq!(data[63 * 4..63 * 4 + 3], stops[1]);
+
+    // A user-defined order reverses it
+    let data = map(Config::builder()
+        .palette(palette.clone())
+        .mapping(Mapping::GradientMap)
+        .gradient_order(vec![1, 2, 0])
+        .build());
+    assert_eq!(data[..3], stops[1]);
+    assert_eq!(data[63 * 4..63 * 4 + 3], stops[0]);
+
+    // Dithering only ever uses the stops, interpolation blends between them
+    for dither in [Dithering::Fs, Dithering::Bayer, Dithering::Bn] {
+        let data = map(Config::builder()
+            .palette(palette.clone())
+            .mapping(Mapping::GradientMap)
+            .dither_algorithm(dither)
+            .build());
+        assert!(data
+            .chunks_exact(4)
+            .all(|px| stops.iter().any(|s| s == &px[..3])));
+    }
+    let data = map(Config::builder()
+        .palette(palette.clone())
+        .mapping(Mapping::GradientMap)
+        .gradient_interpolate(true)
+        .build());
+    assert!(data
+        .chunks_exact(4)
+        .any(|px| stops.iter().all(|s| s != &px[..3])));
+
+    let out_of_bounds = Config::builder()
+        .palette(palette)
+        .gradient_order(vec![0, 3])
+        .build();
+    assert!(out_of_bounds.validate().is_err());
+}
+
+#[test]
+fn test_component_weights_and_kept_lightness() {
+    let palette = |colors: &[[u8; 3]]| {
+        Palette::builder()
+            .colors(colors.iter().map(|&c| Rgb(c)).collect())
+            .build()
+    };
+    let closest = |source: [u8; 3], colors: &[[u8; 3]], weights: Weights| {
+        let config = Config::builder()
+            .palette(palette(colors))
+            .mapping(Mapping::Palettized)
+            .dither_algorithm(Dithering::None)
+            .diff_weights(weights)
+            .build();
+        let mut data = vec![source[0], source[1], source[2], 255];
+        process_pixels_cpu(&mut data, 1, 1, &config).unwrap();
+        [data[0], data[1], data[2]]
+    };
+
+    // A dark red next to a brown of similar lightness and a much lighter red
+    let (dark_red, brown, red) = ([110, 20, 25], [95, 55, 30], [215, 35, 40]);
+    assert_eq!(closest(dark_red, &[brown, red], Weights::NEUTRAL), brown);
+    assert_eq!(closest(dark_red, &[brown, red], Preserve::Hue.into()), red);
+    assert_eq!(
+        closest(dark_red, &[brown, red], Preserve::Lightness.into()),
+        brown
+    );
+
+    let invalid = Config::builder()
+        .palette(palette(&[brown, red]))
+        .diff_weights(Weights {
+            lightness: 0.0,
+            chroma: 1.0,
+            hue: 1.0,
+        })
+        .build();
+    assert!(invalid.validate().is_err());
+
+    // Kept lightness takes only the hue and chroma of the mapped color
+    let mut data = vec![dark_red[0], dark_red[1], dark_red[2], 255];
+    let config = Config::builder()
+        .palette(palette(&[red]))
+        .mapping(Mapping::Palettized)
+        .keep_lightness(true)
+        .build();
+    process_pixels_cpu(&mut data, 1, 1, &config).unwrap();
+    let luma = |c: &[u8]| 0.2126 * c[0] as f32 + 0.7152 * c[1] as f32 + 0.0722 * c[2] as f32;
+    let (out, source, mapped) = (luma(&data[..3]), luma(&dark_red), luma(&red));
+    assert!((out - source).abs() < (out - mapped).abs());
+    assert!(data[0] > data[1] && data[0] > data[2]);
+}
+
+#[test]
+fn test_smoothed_neighbors_and_cutoff() {
+    let (width, height) = (32, 32);
+    let palette = find_palette("gruvbox").unwrap();
+    let map = |config: Config| {
+        let mut data = gradient(width, height);
+        process_pixels_cpu(&mut data, width, height, &config).unwrap();
+        data
+    };
+    let smoothed = || {
+        Config::builder()
+            .palette(palette.clone())
+            .mapping(Mapping::Smoothed)
+    };
+
+    let nearest = map(Config::builder()
+        .palette(palette.clone())
+        .mapping(Mapping::Palettized)
+        .dither_algorithm(Dithering::None)
+        .build());
+    let full = map(smoothed().build());
+    assert_ne!(full, nearest);
+
+    let colors = |data: &[u8]| {
+        data.chunks_exact(4)
+            .filter(|px| px[3] != 0)
+            .map(|px| [px[0], px[1], px[2]])
+            .collect::<std::collections::HashSet<_>>()
+            .len()
+    };
+
+    // Blending a single neighbor, or none past a zero cutoff, leaves only the nearest colors
+    let single = map(smoothed().smooth_neighbors(1).build());
+    assert_eq!(map(smoothed().smooth_cutoff(0.0).build()), single);
+    assert_eq!(colors(&single), colors(&nearest));
+    assert!(colors(&map(smoothed().smooth_neighbors(2).build())) > colors(&single));
+    // Every neighbor blends like the full palette, up to the order the weights are summed in
+    let all = map(smoothed().smooth_neighbors(palette.colors.len()).build());
+    assert!(all.iter().zip(&full).all(|(a, b)| a.abs_diff(*b) <= 1));
+
+    assert!(smoothed().smooth_neighbors(0).build().validate().is_err());
+    assert!(smoothed().smooth_cutoff(-1.0).build().validate().is_err());
+}
+
+#[test]
+fn test_palettized_gif_keeps_exact_palette() {
+    let (width, height) = (24, 16);
+    let palette = find_palette("gruvbox").unwrap();
+    let config = Config::builder()
+        .palette(palette.clone())
+        .mapping(Mapping::Palettized)
+        .build();
+
+    let frames = (0..2)
+        .map(|row| {
+            let mut data = gradient(width, height);
+            data.rotate_left(row * width as usize * 4);
+            process_pixels_cpu(&mut data, width, height, &config).unwrap();
+            image::Frame::new(image::RgbaImage::from_raw(width, height, data).unwrap())
+        })
+        .collect::<Vec<_>>();
+    let mut table = palette.colors.clone();
+    table.push(Rgb([0, 0, 0]));
+    let gif = Gif {
+        frames: frames.clone(),
+        width,
+        height,
+        repeat: None,
+        speed: 10,
+        palette: Some(table),
+        optimization: GifOptimization::default(),
+        layouts: Vec::new(),
+        extensions: Vec::new(),
+    };
+
+    let decoded = Gif::from_memory(&gif.write_to_memory().unwrap()).unwrap();
+    assert_eq!(decoded.frames.len(), frames.len());
+    for (decoded, frame) in decoded.frames.iter().zip(&frames) {
+        for (a, b) in decoded.buffer().pixels().zip(frame.buffer().pixels()) {
+            if b[3] == 0 {
+                assert_eq!(a[3], 0);
+            } else {
+                assert_eq!(a, b);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_gif_optimization_round_trips() {
+    let (width, height) = (32, 24);
+    let palette = find_palette("gruvbox").unwrap();
+    let config = Config::builder()
+        .palette(palette.clone())
+        .mapping(Mapping::Palettized)
+        .build();
+
+    let mut first = gradient(width, height);
+    process_pixels_cpu(&mut first, width, height, &config).unwrap();
+    // A small opaque change and a few pixels turning transparent
+    let mut second = first.clone();
+    for (i, px) in second.chunks_exact_mut(4).enumerate() {
+        let (x, y) = (i as u32 % width, i as u32 / width);
+        if (4..10).contains(&x) && (3..7).contains(&y) {
+            px.copy_from_slice(&[
+                palette.colors[0][0],
+                palette.colors[0][1],
+                palette.colors[0][2],
+                255,
+            ]);
+        } else if (20..23).contains(&x) && y == 15 {
+            px.fill(0);
+        }
+    }
+
+    let delay = image::Delay::from_numer_denom_ms(100, 1);
+    let frames: Vec<image::Frame> = [&first, &second, &second]
+        .into_iter()
+        .map(|data| {
+            let buffer = image::RgbaImage::from_raw(width, height, data.clone()).unwrap();
+            image::Frame::from_parts(buffer, 0, 0, delay)
+        })
+        .collect();
+    let mut table = palette.colors.clone();
+    table.push(Rgb([0, 0, 0]));
+    let mut gif = Gif {
+        frames,
+        width,
+        height,
+        repeat: None,
+        speed: 10,
+        palette: Some(table),
+        optimization: GifOptimization::default(),
+        layouts: Vec::new(),
+        extensions: Vec::new(),
+    };
+
+    let optimized = gif.write_to_memory().unwrap();
+    gif.optimization = GifOptimization {
+        delta_frames: false,
+        lossy: 0,
+        merge_frames: false,
+    };
+    let full = gif.write_to_memory().unwrap();
+    assert!(optimized.len() < full.len());
+    assert_eq!(Gif::from_memory(&full).unwrap().frames.len(), 3);
+
+    // Source layouts merge duplicates too
+    gif.optimization.merge_frames = true;
+    let merged = Gif::from_memory(&gif.write_to_memory().unwrap()).unwrap();
+    assert_eq!(merged.frames.len(), 2);
+    assert_eq!(merged.get_frame_delay(1), 20);
+
+    // Identical frames are merged, the rest composite back to the source frames
+    let decoded = Gif::from_memory(&optimized).unwrap();
+    assert_eq!(decoded.frames.len(), 2);
+    assert_eq!(decoded.get_frame_delay(1), 20);
+    for (frame, source) in decoded.frames.iter().zip([&first, &second]) {
+        for (a, b) in frame.buffer().pixels().zip(source.chunks_exact(4)) {
+            if b[3] == 0 {
+                assert_eq!(a[3], 0);
+            } else {
+                assert_eq!(&a.0[..], b);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_gif_keeps_frame_layout_and_extensions() {
+    let palette = [0, 0, 0, 255, 0, 0, 0, 0, 255, 255, 255, 255];
+    let comment = ExtensionBlock {
+        label: 0xFE,
+        blocks: vec![b"made by hand".to_vec()],
+    };
+    let trailing = ExtensionBlock {
+        label: 0xFF,
+        blocks: vec![b"EXAMPLEAPP1.".to_vec(), vec![7; 300]],
+    };
+
+    let mut source = Vec::new();
+    {
+        let mut encoder = gif::Encoder::new(&mut source, 8, 8, &palette).unwrap();
+        encoder
+            .write_frame(&gif::Frame {
+                width: 8,
+                height: 8,
+                delay: 5,
+                buffer: vec![1; 64].into(),
+                ..gif::Frame::default()
+            })
+            .unwrap();
+        encoder
+            .write_raw_extension(gif::AnyExtension(comment.label), &[&comment.blocks[0]])
+            .unwrap();
+        encoder
+            .write_frame(&gif::Frame {
+                left: 4,
+                top: 5,
+                width: 3,
+                height: 2,
+                delay: 7,
+                dispose: gif::DisposalMethod::Background,
+                buffer: vec![2; 6].into(),
+                ..gif::Frame::default()
+            })
+            .unwrap();
+        let blocks: Vec<&[u8]> = trailing.blocks.iter().map(Vec::as_slice).collect();
+        encoder
+            .write_raw_extension(gif::AnyExtension(trailing.label), &blocks)
+            .unwrap();
+    }
+
+    let mut gif = Gif::from_memory(&source).unwrap();
+    assert_eq!(gif.layouts.len(), 2);
+    assert_eq!(gif.layouts[1].extensions, vec![comment.clone()]);
+    // Sub-blocks come back in their stored size
+    assert_eq!(gif.extensions.len(), 1);
+    assert_eq!(gif.extensions[0].blocks.concat(), trailing.blocks.concat());
+
+    gif.resize(None, None, Some(2.0), Filter::Nearest).unwrap();
+    gif.optimization.delta_frames = false;
+    let written = Gif::from_memory(&gif.write_to_memory().unwrap()).unwrap();
+
+    let layout = &written.layouts[1];
+    assert_eq!(
+        (layout.left, layout.top, layout.width, layout.height),
+        (8, 10, 6, 4)
+    );
+    assert_eq!(layout.dispose, gif::DisposalMethod::Background);
+    assert_eq!(layout.extensions, vec![comment]);
+    assert_eq!(written.extensions, gif.extensions);
+    assert_eq!(written.get_frame_delay(1), 7);
+    for (a, b) in written.frames.iter().zip(&gif.frames) {
+        assert_eq!(a.buffer(), b.buffer());
+    }
+}
+
+#[test]
+fn test_animations_round_trip() {
+    let (width, height) = (12, 10);
+    let frames: Vec<image::Frame> = (0..3)
+        .map(|i| {
+            let mut data = gradient(width, height);
+            data.rotate_left(i * 4 * width as usize);
+            let buffer = image::RgbaImage::from_raw(width, height, data).unwrap();
+            image::Frame::from_parts(
+                buffer,
+                0,
+                0,
+                image::Delay::from_numer_denom_ms(40 * (i as u32 + 1), 1),
+            )
+        })
+        .collect();
+
+    for format in [AnimationFormat::Png, AnimationFormat::WebP] {
+        let animation = Animation {
+            frames: frames.clone(),
+            width,
+            height,
+            format,
+            loop_count: 3,
+            png: PngOptions::default(),
+        };
+        let bytes = animation.write_to_memory().unwrap();
+        assert_eq!(animated_format(&bytes), Some(format));
+        if format == AnimationFormat::Png {
+            let unfiltered = Animation {
+                png: PngOptions {
+                    compression: PngCompression::Best,
+                    filter: PngFilter::None,
+                    optimize: false,
+                },
+                ..animation.clone()
+            };
+            assert_ne!(unfiltered.write_to_memory().unwrap(), bytes);
+        }
+
+        let Media::Animation(decoded) = Media::from_memory(&bytes).unwrap() else {
+            panic!("{format:?} was not read as an animation");
+        };
+        assert_eq!(decoded.loop_count, 3);
+        assert_eq!(decoded.frames.len(), frames.len());
+        for (a, b) in decoded.frames.iter().zip(&frames) {
+            assert_eq!(a.delay(), b.delay());
+            for (pa, pb) in a.buffer().pixels().zip(b.buffer().pixels()) {
+                if pb[3] == 0 {
+                    assert_eq!(pa[3], 0);
+                } else {
+                    assert_eq!(pa, pb);
+                }
+            }
+        }
+    }
+
+    // Still images stay images
+    let still = Image::from_rgba(frames[0].buffer().clone());
+    let bytes = still.write_to_memory().unwrap();
+    assert!(matches!(
+        Media::from_memory(&bytes).unwrap(),
+        Media::Image(_)
+    ));
+}
+
+#[test]
+fn test_image_formats_are_preserved() {
+    let (width, height) = (8, 6);
+    let buffer = image::RgbaImage::from_fn(width, height, |x, y| {
+        image::Rgba([(x * 30) as u8, (y * 40) as u8, 90, 255])
+    });
+    let palette = Palette::builder()
+        .colors(vec![Rgb([0, 0, 0]), Rgb([255, 0, 0]), Rgb([0, 0, 255])])
+        .build();
+    let config = Config::builder().palette(palette).build();
+
+    for (format, extension) in [
+        (image::ImageFormat::Bmp, "bmp"),
+        (image::ImageFormat::Pnm, "ppm"),
+        (image::ImageFormat::Jpeg, "png"),
+    ] {
+        let mut bytes = Vec::new();
+        image::DynamicImage::ImageRgba8(buffer.clone())
+            .into_rgb8()
+            .write_to(&mut std::io::Cursor::new(&mut bytes), format)
+            .unwrap();
+
+        let mut media = Media::from_memory(&bytes).unwrap();
+        assert_eq!(media.default_extension(), extension);
+        let Media::Image(image) = &mut media else {
+            panic!("{format:?} was not read as an image");
+        };
+        process_pixels_cpu(image.buffer.as_mut(), width, height, &config).unwrap();
+
+        let written = media.write_to_memory().unwrap();
+        let output_format = image::guess_format(&written).unwrap();
+        assert!(output_format.extensions_str().contains(&extension));
+
+        // Lossless formats keep the mapped colors exactly
+        let decoded = image::load_from_memory(&written).unwrap().into_rgba8();
+        let Media::Image(mapped) = &media else {
+            unreachable!()
+        };
+        assert_eq!(decoded, mapped.buffer);
+    }
+}
+
+#[cfg(feature = "tiff")]
+#[test]
+fn test_tiff_expands_packed_gray_and_palette_pages() {
+    use palettum::media::Tiff;
+
+    // Uncompressed little-endian TIFF with one strip of 2-bit samples
+    fn tiff(photometric: u16, rows: &[[u8; 5]], colormap: &[u16]) -> Vec<u8> {
+        let data: Vec<u8> = rows
+            .iter()
+            .flat_map(|row| {
+                (row.iter().fold(0u16, |acc, &s| acc << 2 | u16::from(s)) << 6).to_be_bytes()
+            })
+            .collect();
+        let height = rows.len() as u32;
+        let mut entries: Vec<(u16, u32)> = vec![
+            (256, 5),
+            (257, height),
+            (258, 2),
+            (259, 1),
+            (262, photometric.into()),
+            (273, 0),
+            (277, 1),
+            (278, height),
+            (279, data.len() as u32),
+        ];
+        if !colormap.is_empty() {
+            entries.push((320, 0));
+        }
+        let colormap_at = (8 + 2 + 12 * entries.len() + 4) as u32;
+        let data_at = colormap_at + 2 * colormap.len() as u32;
+
+        let mut bytes = b"II*\0\x08\0\0\0".to_vec();
+        bytes.extend((entries.len() as u16).to_le_bytes());
+        for (tag, value) in entries {
+            let (kind, count, value) = match tag {
+                273 => (4u16, 1u32, data_at),
+                279 => (4, 1, value),
+                320 => (3, colormap.len() as u32, colormap_at),
+                _ => (3, 1, value),
+            };
+            bytes.extend(tag.to_le_bytes());
+            bytes.extend(kind.to_le_bytes());
+            bytes.extend(count.to_le_bytes());
+            bytes.extend(value.to_le_bytes());
+        }
+        bytes.extend(0u32.to_le_bytes());
+        bytes.extend(colormap.iter().flat_map(|c| c.to_le_bytes()));
+        bytes.extend(data);
+        bytes
+    }
+
+    let rows = [[0, 1, 2, 3, 0], [3, 3, 1, 0, 2]];
+
+    // WhiteIsZero, so 0 is white
+    let gray = Tiff::from_memory(&tiff(0, &rows, &[])).unwrap();
+    let decoded: Vec<[u8; 4]> = gray.pages[0].pixels().map(|p| p.0).collect();
+    let expected: Vec<[u8; 4]> = rows
+        .iter()
+        .flatten()
+        .map(|&s| {
+            let level = 255 - 85 * s;
+            [level, level, level, 255]
+        })
+        .collect();
+    assert_eq!(decoded, expected);
+
+    // The ColorMap lists all reds, then all greens, then all blues
+    let colormap = [
+        0xffff, 0, 0, 0x8000, 0, 0xffff, 0, 0x8000, 0, 0, 0xffff, 0x8000,
+    ];
+    let colors = [
+        [255, 0, 0, 255],
+        [0, 255, 0, 255],
+        [0, 0, 255, 255],
+        [128, 128, 128, 255],
+    ];
+    let palette = Tiff::from_memory(&tiff(3, &rows, &colormap)).unwrap();
+    let decoded: Vec<[u8; 4]> = palette.pages[0].pixels().map(|p| p.0).collect();
+    let expected: Vec<[u8; 4]> = rows.iter().flatten().map(|&i| colors[i as usize]).collect();
+    assert_eq!(decoded, expected);
+}
+
+#[test]
+fn test_output_format_conversions() {
+    assert_eq!(
+        OutputFormat::from_path(std::path::Path::new("out/anim.WEBP")),
+        Some(OutputFormat::WebP)
+    );
+    assert_eq!(
+        OutputFormat::from_extension("jpeg"),
+        Some(OutputFormat::Jpeg)
+    );
+    assert_eq!(OutputFormat::from_extension("txt"), None);
+
+    let (width, height) = (6, 4);
+    let frames: Vec<image::Frame> = (0..3u8)
+        .map(|i| {
+            image::Frame::from_parts(
+                image::RgbaImage::from_pixel(width, height, image::Rgba([i * 80, 0, 0, 255])),
+                0,
+                0,
+                image::Delay::from_numer_denom_ms(50, 1),
+            )
+        })
+        .collect();
+    let gif = Media::Gif(Gif::from_frames(frames.clone(), width, height, 2));
+
+    // GIF loop counts leave out the first play, and a single play has no NETSCAPE2.0 block
+    let gif_loops = |bytes: &[u8]| {
+        bytes
+            .windows(15)
+            .find(|w| &w[..11] == b"NETSCAPE2.0")
+            .map(|w| u16::from_le_bytes([w[13], w[14]]))
+    };
+    let gif_bytes = gif.write_to_memory().unwrap();
+    assert_eq!(gif_loops(&gif_bytes), Some(1));
+    let once = Gif::from_frames(frames.clone(), width, height, 1);
+    let once_bytes = once.write_to_memory().unwrap();
+    assert_eq!(gif_loops(&once_bytes), None);
+    let reread = Gif::from_memory(&once_bytes).unwrap();
+    assert_eq!(gif_loops(&reread.write_to_memory().unwrap()), None);
+
+    // GIF → animated WebP keeps frames, delays and the number of plays
+    let decoded = Media::from_memory(&gif_bytes).unwrap();
+    let Media::Animation(webp) = decoded.convert(OutputFormat::WebP).unwrap() else {
+        panic!("GIF was not converted to an animation");
+    };
+    assert_eq!(webp.format, AnimationFormat::WebP);
+    assert_eq!(webp.loop_count, 2);
+    assert_eq!(webp.frames.len(), 3);
+    assert_eq!(webp.frames[1].delay(), frames[1].delay());
+    let Media::Animation(webp) = Media::from_memory(&webp.write_to_memory().unwrap()).unwrap()
+    else {
+        panic!("WebP was not read as an animation");
+    };
+    assert_eq!(webp.loop_count, 2);
+
+    // ...and back to a GIF that repeats once more after the first play
+    let Media::Gif(back) = Media::Animation(webp).convert(OutputFormat::Gif).unwrap() else {
+        panic!("WebP was not converted to a GIF");
+    };
+    assert_eq!(gif_loops(&back.write_to_memory().unwrap()), Some(1));
+
+    // A GIF that plays once stays that way through WebP and back
+    let mut once = Gif::from_frames(frames.clone(), width, height, 1);
+    for repeat in [None, Some(image::codecs::gif::Repeat::Finite(0))] {
+        once.repeat = repeat;
+        let Media::Animation(webp) = Media::Gif(once.clone())
+            .convert(OutputFormat::WebP)
+            .unwrap()
+        else {
+            panic!("GIF was not converted to an animation");
+        };
+        assert_eq!(webp.loop_count, 1, "{repeat:?}");
+        let Media::Gif(back) = Media::Animation(webp).convert(OutputFormat::Gif).unwrap() else {
+            panic!("WebP was not converted to a GIF");
+        };
+        assert_eq!(
+            gif_loops(&back.write_to_memory().unwrap()),
+            None,
+            "{repeat:?}"
+        );
+    }
+
+    // Animations written to a still format keep their first frame
+    let still = gif.convert(OutputFormat::Bmp).unwrap();
+    assert_eq!(still.default_extension(), "bmp");
+    let Media::Image(still) = still else {
+        panic!("GIF was not converted to an image");
+    };
+    assert_eq!(&still.buffer, frames[0].buffer());
+
+    // A still image becomes a one-frame GIF, and JPEG output drops alpha
+    let image = Media::Image(still);
+    let Media::Gif(single) = image.clone().convert(OutputFormat::Gif).unwrap() else {
+        panic!("image was not converted to a GIF");
+    };
+    assert_eq!(single.frames.len(), 1);
+    let jpeg = image.convert(OutputFormat::Jpeg).unwrap();
+    assert_eq!(jpeg.default_extension(), "jpg");
+    let bytes = jpeg.write_to_memory().unwrap();
+    assert_eq!(
+        image::guess_format(&bytes).unwrap(),
+        image::ImageFormat::Jpeg
+    );
+}
+
+#[test]
+fn test_indexed_png_bit_depth_and_optimization() {
+    let (width, height) = (7, 3);
+    let colors = vec![
+        Rgb([0, 0, 0]),
+        Rgb([255, 255, 255]),
+        Rgb([255, 0, 0]),
+        Rgb([0, 255, 0]),
+    ];
+    // Mostly white with some red, black unused, and one transparent pixel
+    let buffer = image::RgbaImage::from_fn(width, height, |x, y| match (x, y) {
+        (0, 0) => image::Rgba([0, 0, 0, 0]),
+        (_, 1) => image::Rgba([255, 0, 0, 255]),
+        (6, 2) => image::Rgba([0, 255, 0, 255]),
+        _ => image::Rgba([255, 255, 255, 255]),
+    });
+
+    let mut palette = colors.clone();
+    palette.push(Rgb([0, 0, 0]));
+    let mut image = Image {
+        palette: Some(palette),
+        ..Image::from_rgba(buffer)
+    };
+
+    // Bit depth lives in IHDR, right after the width and height
+    let plain = image.write_to_memory().unwrap();
+    assert_eq!(plain[24], 2, "four used entries should pack into 2 bits");
+    let decoded = image::load_from_memory(&plain).unwrap().into_rgba8();
+    assert_eq!(decoded, image.buffer);
+
+    // Opaque images leave out the transparent placeholder
+    let opaque = Image {
+        palette: Some(vec![Rgb([0, 0, 0]), Rgb([255, 255, 255]), Rgb([0, 0, 0])]),
+        ..Image::from_rgba(image::RgbaImage::from_fn(width, height, |x, _| {
+            image::Rgba([
+                (x % 2 * 255) as u8,
+                (x % 2 * 255) as u8,
+                (x % 2 * 255) as u8,
+                255,
+            ])
+        }))
+    };
+    let plain = opaque.write_to_memory().unwrap();
+    assert_eq!(plain[24], 1, "two opaque colors should pack into 1 bit");
+    let reader = png::Decoder::new(std::io::Cursor::new(&plain))
+        .read_info()
+        .unwrap();
+    assert!(reader.info().trns.is_none());
+    let decoded = image::load_from_memory(&plain).unwrap().into_rgba8();
+    assert_eq!(decoded, opaque.buffer);
+
+    image.png.optimize = true;
+    image.png.compression = palettum::media::PngCompression::Best;
+    image.png.filter = palettum::media::PngFilter::Adaptive;
+    let optimized = image.write_to_memory().unwrap();
+    assert_eq!(
+        optimized[24], 2,
+        "four used entries should pack into 2 bits"
+    );
+    let decoded = image::load_from_memory(&optimized).unwrap().into_rgba8();
+    assert_eq!(decoded, image.buffer);
+
+    // The transparent entry comes first, so tRNS holds a single alpha
+    let mut decoder = png::Decoder::new(std::io::Cursor::new(&optimized));
+    decoder.set_transformations(png::Transformations::IDENTITY);
+    let reader = decoder.read_info().unwrap();
+    let info = reader.info();
+    assert_eq!(info.trns.as_deref(), Some(&[0u8][..]));
+    assert_eq!(info.palette.as_deref().unwrap()[3..6], [255, 255, 255]);
+}
+
+#[test]
+fn test_metadata_passthrough_and_orientation() {
+    // Little-endian EXIF: orientation 6 (rotated 90° clockwise) and a GPS latitude
+    let mut exif = b"II*\0".to_vec();
+    exif.extend_from_slice(&8u32.to_le_bytes());
+    exif.extend_from_slice(&2u16.to_le_bytes());
+    exif.extend_from_slice(&[0x12, 0x01, 3, 0, 1, 0, 0, 0, 6, 0, 0, 0]);
+    exif.extend_from_slice(&[0x25, 0x88, 4, 0, 1, 0, 0, 0, 38, 0, 0, 0]);
+    exif.extend_from_slice(&0u32.to_le_bytes());
+    exif.extend_from_slice(&1u16.to_le_bytes());
+    exif.extend_from_slice(&[0x02, 0x00, 5, 0, 3, 0, 0, 0, 56, 0, 0, 0]);
+    exif.extend_from_slice(&0u32.to_le_bytes());
+    for value in [52u32, 1, 31, 1, 7, 1] {
+        exif.extend_from_slice(&value.to_le_bytes());
+    }
+
+    // A 4x2 JPEG with the EXIF and a comment spliced in after SOI
+    let (width, height) = (4, 2);
+    let mut jpeg = Vec::new();
+    image::DynamicImage::ImageRgb8(image::RgbImage::from_pixel(
+        width,
+        height,
+        image::Rgb([200, 30, 30]),
+    ))
+    .write_to(
+        &mut std::io::Cursor::new(&mut jpeg),
+        image::ImageFormat::Jpeg,
+    )
+    .unwrap();
+    let mut segments = vec![0xFF, 0xE1];
+    segments.extend_from_slice(&(2 + 6 + exif.len() as u16).to_be_bytes());
+    segments.extend_from_slice(b"Exif\0\0");
+    segments.extend_from_slice(&exif);
+    segments.extend_from_slice(&[0xFF, 0xFE, 0, 7]);
+    segments.extend_from_slice(b"hello");
+    jpeg.splice(2..2, segments);
+
+    let Media::Image(image) = Media::from_memory(&jpeg).unwrap() else {
+        panic!("JPEG was not read as an image");
+    };
+    assert_eq!((image.width, image.height), (height, width));
+    assert_eq!(image.metadata.text, [("Comment".into(), "hello".into())]);
+    let kept = image.metadata.exif.clone().unwrap();
+    assert_eq!(
+        &kept[18..20],
+        &[1, 0],
+        "orientation should be reset once applied"
+    );
+    assert_eq!(&kept[30..34], &[38, 0, 0, 0]);
+
+    // PNG and WebP outputs carry it all over
+    for format in [OutputFormat::Png, OutputFormat::WebP] {
+        let converted = Media::Image(image.clone()).convert(format).unwrap();
+        let bytes = converted.write_to_memory().unwrap();
+        let Media::Image(reread) = Media::from_memory(&bytes).unwrap() else {
+            panic!("{format:?} was not read as an image");
+        };
+        assert_eq!(reread.metadata.exif, image.metadata.exif, "{format:?}");
+        if format == OutputFormat::Png {
+            assert_eq!(reread.metadata.text, image.metadata.text);
+        }
+    }
+
+    // Safe drops the location and every trace of it
+    let mut safe = image.metadata.clone();
+    safe.retain(MetadataMode::Safe);
+    let safe_exif = safe.exif.unwrap();
+    assert_eq!(&safe_exif[8..10], &[1, 0]);
+    assert!(safe_exif[38..].iter().all(|&b| b == 0));
+
+    let mut stripped = image.metadata.clone();
+    stripped.retain(MetadataMode::Strip);
+    assert!(stripped.is_empty());
+}
+
+#[test]
+fn test_icon_sets_indexed_entries_and_cursors() {
+    // Red left half, blue right half, with a transparent corner
+    let mut source = image::RgbaImage::from_fn(64, 64, |x, _| {
+        if x < 32 {
+            image::Rgba([255, 0, 0, 255])
+        } else {
+            image::Rgba([0, 0, 255, 255])
+        }
+    });
+    source.put_pixel(0, 0, image::Rgba([0, 0, 0, 0]));
+
+    let mut ico = Ico::icon_set(&source, &STANDARD_ICON_SIZES, Filter::Nearest).unwrap();
+    assert_eq!(ico.widths, STANDARD_ICON_SIZES);
+    ico.palette = Some(vec![
+        image::Rgb([255, 0, 0]),
+        image::Rgb([0, 0, 255]),
+        image::Rgb([0, 0, 0]),
+    ]);
+
+    // Entries below 256 are 4-bit BMPs with a mask, the largest stays PNG
+    let bytes = ico.write_to_memory().unwrap();
+    assert_eq!(&bytes[..6], &[0, 0, 1, 0, 7, 0]);
+    for (i, &size) in STANDARD_ICON_SIZES.iter().enumerate() {
+        let entry = &bytes[6 + 16 * i..22 + 16 * i];
+        let offset = u32::from_le_bytes(entry[12..16].try_into().unwrap()) as usize;
+        if size < 256 {
+            assert_eq!(entry[2], 16, "{size}");
+            assert_eq!(u16::from_le_bytes([entry[6], entry[7]]), 4, "{size}");
+            assert_eq!(&bytes[offset..offset + 4], &40u32.to_le_bytes());
+        } else {
+            assert_eq!(&bytes[offset + 1..offset + 4], b"PNG");
+        }
+    }
+
+    let Media::Ico(reread) = Media::from_memory(&bytes).unwrap() else {
+        panic!("ICO was not read as an icon");
+    };
+    for (original, decoded) in ico.buffers.iter().zip(&reread.buffers) {
+        assert_eq!(original, decoded);
+    }
+
+    // Every entry can be forced to BMP
+    ico.encoding = IcoEncoding::Bmp;
+    let bytes = ico.write_to_memory().unwrap();
+    assert_eq!(&bytes[6 + 16 * 6..6 + 16 * 6 + 4], &[0, 0, 16, 0]);
+
+    // Resizing keeps the range of sizes instead of collapsing it
+    let mut resized = ico.clone();
+    resized
+        .resize(Some(128), None, None, Filter::Nearest)
+        .unwrap();
+    assert_eq!(resized.widths, [8, 12, 16, 24, 32, 64, 128]);
+
+    // Cursors carry a hotspot per entry, scaled from the largest one
+    ico.set_hotspot(128, 64);
+    let bytes = Media::Ico(ico).write_to_memory().unwrap();
+    assert_eq!(&bytes[..4], &[0, 0, 2, 0]);
+    let Media::Ico(cursor) = Media::from_memory(&bytes).unwrap() else {
+        panic!("CUR was not read as an icon");
+    };
+    let hotspots = cursor.hotspots.clone().unwrap();
+    assert_eq!(hotspots[0], (8, 4));
+    assert_eq!(hotspots[6], (128, 64));
+
+    // Rebuilding the set keeps the hotspot on the same spot of the source
+    let mut rebuilt = cursor.clone();
+    rebuilt.regenerate(&[32], Filter::Nearest).unwrap();
+    assert_eq!(rebuilt.hotspots, Some(vec![(16, 8)]));
+}
+
+#[cfg(feature = "video")]
+#[test]
+fn test_video_passthrough_selects_streams() {
+    use ffmpeg::media::Type;
+    use palettum::{
+        ffmpeg_next as ffmpeg,
+        media::{Video, VideoPassthrough},
+    };
+
+    let without_audio = VideoPassthrough {
+        audio: false,
+        ..Default::default()
+    };
+    assert!(!without_audio.keeps(Type::Audio));
+    assert!(without_audio.keeps(Type::Subtitle));
+    assert!(without_audio.keeps(Type::Data));
+    assert!(!without_audio.keeps(Type::Video));
+
+    // `Video` writes with libx264rgb, and the source clip needs AAC for its audio track
+    ffmpeg::init().unwrap();
+    if ffmpeg::encoder::find_by_name("libx264rgb").is_none()
+        || ffmpeg::encoder::find(ffmpeg::codec::Id::AAC).is_none()
+    {
+        eprintln!("Skipping stream selection: FFmpeg lacks the libx264rgb or AAC encoder");
+        return;
+    }
+
+    let dir = std::env::temp_dir().join(format!("palettum-passthrough-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (picture, source, output) = (
+        dir.join("picture.mp4"),
+        dir.join("source.mp4"),
+        dir.join("output.mp4"),
+    );
+
+    let frames: Vec<_> = (0..3u8)
+        .map(|i| {
+            let buffer = image::RgbaImage::from_pixel(16, 16, image::Rgba([i * 80, 0, 0, 255]));
+            image::Frame::from_parts(buffer, 0, 0, image::Delay::from_numer_denom_ms(100, 1))
+        })
+        .collect();
+    Video::from_frames(&frames)
+        .unwrap()
+        .write_to_file(&picture)
+        .unwrap();
+
+    // Remux the picture next to a third of a second of silence
+    const RATE: i32 = 48_000;
+    let mut ictx = ffmpeg::format::input(&picture).unwrap();
+    let mut octx = ffmpeg::format::output(&source).unwrap();
+    let video_in = ictx.streams().best(Type::Video).unwrap();
+    let (video_index, video_time_base) = (video_in.index(), video_in.time_base());
+    let mut video_out = octx
+        .add_stream(ffmpeg::encoder::find(ffmpeg::codec::Id::None))
+        .unwrap();
+    video_out.set_parameters(video_in.parameters());
+    unsafe {
+        (*video_out.parameters().as_mut_ptr()).codec_tag = 0;
+    }
+
+    let codec = ffmpeg::encoder::find(ffmpeg::codec::Id::AAC).unwrap();
+    let mut audio = ffmpeg::codec::Context::new_with_codec(codec)
+        .encoder()
+        .audio()
+        .unwrap();
+    audio.set_rate(RATE);
+    audio.set_format(ffmpeg::format::Sample::F32(
+        ffmpeg::format::sample::Type::Planar,
+    ));
+    audio.set_channel_layout(ffmpeg::ChannelLayout::MONO);
+    audio.set_time_base(ffmpeg::Rational::new(1, RATE));
+    if octx
+        .format()
+        .flags()
+        .contains(ffmpeg::format::Flags::GLOBAL_HEADER)
+    {
+        audio.set_flags(ffmpeg::codec::Flags::GLOBAL_HEADER);
+    }
+    let mut audio = audio.open_as(codec).unwrap();
+    let mut audio_out = octx.add_stream(codec).unwrap();
+    audio_out.set_parameters(&audio);
+    audio_out.set_time_base(ffmpeg::Rational::new(1, RATE));
+    octx.write_header().unwrap();
+
+    for (stream, mut packet) in ictx.packets() {
+        if stream.index() == video_index {
+            packet.rescale_ts(video_time_base, octx.stream(0).unwrap().time_base());
+            packet.set_position(-1);
+            packet.set_stream(0);
+            packet.write_interleaved(&mut octx).unwrap();
+        }
+    }
+
+    let audio_time_base = octx.stream(1).unwrap().time_base();
+    let write_audio = |encoder: &mut ffmpeg::encoder::Audio,
+                       octx: &mut ffmpeg::format::context::Output| {
+        let mut packet = ffmpeg::codec::packet::Packet::empty();
+        while encoder.receive_packet(&mut packet).is_ok() {
+            packet.rescale_ts(ffmpeg::Rational::new(1, RATE), audio_time_base);
+            packet.set_stream(1);
+            packet.write_interleaved(octx).unwrap();
+        }
+    };
+    let samples = audio.frame_size() as usize;
+    for pts in (0..RATE as usize * 3 / 10).step_by(samples) {
+        let mut frame = ffmpeg::frame::Audio::new(audio.format(), samples, audio.channel_layout());
+        frame.set_rate(RATE as u32);
+        frame.plane_mut::<f32>(0).fill(0.0);
+        frame.set_pts(Some(pts as i64));
+        audio.send_frame(&frame).unwrap();
+        write_audio(&mut audio, &mut octx);
+    }
+    audio.send_eof().unwrap();
+    write_audio(&mut audio, &mut octx);
+    octx.write_trailer().unwrap();
+
+    let media_types = |path: &std::path::Path| {
+        let ictx = ffmpeg::format::input(path).unwrap();
+        ictx.streams()
+            .map(|stream| stream.parameters().medium())
+            .collect::<Vec<_>>()
+    };
+
+    let mut video = Video::from_file(&source).unwrap();
+    video.write_to_file(&output).unwrap();
+    assert_eq!(media_types(&output), [Type::Video, Type::Audio]);
+
+    video.passthrough.audio = false;
+    video.write_to_file(&output).unwrap();
+    assert_eq!(media_types(&output), [Type::Video]);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -100,16 +100,9 @@ impl ResizedFrame {
 pub async fn resize_frame(bytes: Vec<u8>, width: u32, height: u32) -> Result<ResizedFrame> {
     let instance = get_gpu_instance().await?;
     let config = instance.config.read().clone();
-    let mut image = Image {
-        buffer: image::RgbaImage::from_raw(width, height, bytes).ok_or(
-            palettum::error::Error::Internal(
-                "Failed to create RgbaImage from raw bytes".to_string(),
-            ),
-        )?,
-        width,
-        height,
-        palette: None,
-    };
+    let mut image = Image::from_rgba(image::RgbaImage::from_raw(width, height, bytes).ok_or(
+        palettum::error::Error::Internal("Failed to create RgbaImage from raw bytes".to_string()),
+    )?);
 
     image.resize(
         config.resize_width,